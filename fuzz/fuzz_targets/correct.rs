@@ -0,0 +1,40 @@
+#![no_main]
+
+//! Input: a query line, a quality line, then whitelist lines (each line's occurrences are its count).
+//! Empty whitelist entries, and those of a different length than the first, are dropped.
+
+use std::collections::HashMap;
+use libfuzzer_sys::fuzz_target;
+use barcodes::trie::Trie;
+use barcodes::correct::{best_correction,likelihood_of_errors,CorrectionConfig};
+
+fuzz_target!(|data: &[u8]| {
+    let mut lines = data.split(|&b| b == b'\n');
+    let query = lines.next().unwrap_or_default();
+    let qual = lines.next().unwrap_or_default();
+    let words: Vec<&[u8]> = lines.collect();
+    let word_length = words.iter().find(|w| !w.is_empty()).map_or(0, |w| w.len());
+    let words: Vec<&[u8]> = words.into_iter().filter(|w| !w.is_empty() && w.len() == word_length).collect();
+
+    let trie = Trie::from_words(words.iter());
+    let mut counts: HashMap<&[u8], f64> = HashMap::new();
+    for &word in words.iter() {
+        *counts.entry(word).or_insert(0.0) += 1.0;
+    }
+
+    for &word in words.iter().filter(|w| w.len() == query.len()) {
+        let likelihood = likelihood_of_errors(query, word, qual, None, None);
+        assert!((0.0..=1.0).contains(&likelihood));
+        let likelihood = likelihood_of_errors(query, word, qual, Some(20), None);
+        assert!((0.0..=1.0).contains(&likelihood));
+    }
+
+    for max_edit_distance in 0..=2 {
+        let config = CorrectionConfig {max_edit_distance, ..Default::default()};
+        if let Some(correction) = best_correction(&trie, &counts, query, qual, &config) {
+            assert!(trie.contains_word(&correction.barcode));
+            assert!(correction.distance <= max_edit_distance);
+            assert!((0.0..=1.0 + 1e-9).contains(&correction.posterior));
+        }
+    }
+});
@@ -0,0 +1,49 @@
+#![no_main]
+
+//! Input: a query line, followed by whitelist lines. Empty whitelist entries, and those of a different length
+//! than the first, are dropped, since a Trie only holds words of one length.
+
+use libfuzzer_sys::fuzz_target;
+use barcodes::trie::{Trie,SubstitutionCosts};
+
+fuzz_target!(|data: &[u8]| {
+    let mut lines = data.split(|&b| b == b'\n');
+    let query = lines.next().unwrap_or_default();
+    let words: Vec<&[u8]> = lines.collect();
+    let word_length = words.iter().find(|w| !w.is_empty()).map_or(0, |w| w.len());
+    let words: Vec<&[u8]> = words.into_iter().filter(|w| !w.is_empty() && w.len() == word_length).collect();
+
+    let mut trie = Trie::new();
+    for word in words.iter() {
+        trie.add_word(word);
+    }
+
+    let mut sorted = words.clone();
+    sorted.sort();
+    let sorted_trie = Trie::from_sorted_words(&sorted).unwrap();
+    assert_eq!(trie.len(), sorted_trie.len());
+    assert!(trie.iter().eq(sorted_trie.iter()));
+
+    assert_eq!(trie.contains_word(query), words.contains(&query));
+    for max_distance in 0..=2 {
+        for (word, distance) in trie.get_words_within_hamming_distance(query, max_distance) {
+            assert!(distance <= max_distance);
+            assert!(trie.contains_word(word.as_bytes()));
+        }
+    }
+    let neighbors = trie.get_words_within_hamming_distance(query, 2);
+    let nearest_distance = neighbors.iter().map(|(_, d)| *d).min();
+    match trie.get_nearest_word(query, 2) {
+        Some((word, distance, unique)) => {
+            assert_eq!(Some(distance), nearest_distance);
+            assert!(neighbors.contains(&(word, distance)));
+            assert_eq!(unique, neighbors.iter().filter(|(_, d)| *d == distance).count() == 1);
+        },
+        None => assert_eq!(nearest_distance, None),
+    }
+    let costs = SubstitutionCosts::transition_transversion(0.5, 1.0).unwrap();
+    for (word, cost) in trie.get_words_within_substitution_cost(query, 1.0, &costs) {
+        assert!(cost <= 1.0 + 1e-9);
+        assert!(trie.contains_word(word.as_bytes()));
+    }
+});
@@ -0,0 +1,171 @@
+use std::io::{self,Read,Write};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt,AsyncWriteExt,BufReader,BufWriter};
+use tokio::sync::mpsc::{self,Receiver,Sender};
+use tokio::task;
+use crate::correct::{correct_barcodes_in_stream,CorrectionConfig,CorrectionStats};
+
+/// Number of bytes read from the input, or written to the output, at a time.
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Number of chunks the reader may get ahead of the corrector (and the corrector of the writer) by.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// The (compressed) input, as received by the corrector from the reader task.
+struct ChannelReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                },
+                None => return Ok(0)
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// The (compressed) output, as sent by the corrector to the writer task.
+struct ChannelWriter {
+    chunks: Sender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if !self.chunk.is_empty() {
+            let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+            self.chunks.blocking_send(chunk).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The output writer stopped"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+/// Read the input in chunks, until it's read in full or the corrector stops receiving them. A read error is
+/// passed on to the corrector.
+async fn read_chunks(file: File, chunks: Sender<io::Result<Vec<u8>>>) {
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, file);
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        match (&mut reader).take(CHUNK_SIZE as u64).read_to_end(&mut chunk).await {
+            Ok(0) => return,
+            Ok(_) => {
+                if chunks.send(Ok(chunk)).await.is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                let _ = chunks.send(Err(e)).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Write the chunks of the output as the corrector sends them.
+async fn write_chunks(file: File, mut chunks: Receiver<Vec<u8>>) -> io::Result<()> {
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, file);
+    while let Some(chunk) = chunks.recv().await {
+        writer.write_all(&chunk).await?;
+    }
+    writer.flush().await
+}
+
+/// Correct the barcodes in a fastq file like [`correct_barcodes_in_fastq`](crate::correct::correct_barcodes_in_fastq),
+/// without blocking the tokio runtime it's awaited on.
+///
+/// The input is read, and the output written, by tokio tasks, which pass (compressed) chunks to and from the
+/// (CPU-bound) correction over channels; the correction itself runs on tokio's blocking thread pool (see
+/// [`spawn_blocking`](tokio::task::spawn_blocking)). The input is read as a stream, so multiple correction
+/// rounds and checksum verification aren't supported (see
+/// [`correct_barcodes_in_stream`](crate::correct::correct_barcodes_in_stream)).
+///
+/// Since a [`CorrectionConfig`] may borrow (e.g., a progress callback), it's built on the blocking thread by
+/// `config`, rather than passed in.
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use barcodes::async_correct::correct_barcodes_in_fastq_async;
+/// use barcodes::correct::CorrectionConfig;
+/// use barcodes::io::open_fastq;
+/// # let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// # fs::write(path("async_whitelist.txt"), "ACGTACGT\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("async_counts.tsv"), "ACGTACGT\t10\nTTTTTTTT\t10\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("async_in.fastq.gz")).unwrap(), Compression::fast());
+/// # for i in 0..10000 {
+/// #     fastq.write_all(format!("@r{}\nACGTACGA\n+\nIIIIIIII\n", i).as_bytes()).unwrap();
+/// # }
+/// # fastq.finish().unwrap();
+/// let (whitelist, counts, output) = (path("async_whitelist.txt"), path("async_counts.tsv"), path("async_out.fastq.gz"));
+/// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// let stats = runtime.block_on(correct_barcodes_in_fastq_async(&path("async_in.fastq.gz"), &whitelist, &counts, &output, CorrectionConfig::default)).unwrap();
+/// assert_eq!((stats.total, stats.matched_whitelist_after_correction), (10000, 10000));
+/// let records: Vec<_> = open_fastq(&output).unwrap().records().map(|r| r.unwrap()).collect();
+/// assert_eq!(records.len(), 10000);
+/// assert_eq!(records[9999].desc(), Some("CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII"));
+///
+/// // errors are returned by the future
+/// let missing = path("async_missing.fastq.gz");
+/// assert!(runtime.block_on(correct_barcodes_in_fastq_async(&missing, &whitelist, &counts, &output, CorrectionConfig::default)).is_err());
+/// // (the whitelist isn't a gzipped fastq)
+/// assert!(runtime.block_on(correct_barcodes_in_fastq_async(&whitelist, &whitelist, &counts, &output, CorrectionConfig::default)).is_err());
+/// ```
+pub async fn correct_barcodes_in_fastq_async<F>(input_fastq_filename: &str, whitelist_filename: &str, counts_filename: &str, output_fastq_filename: &str, config: F) -> io::Result<CorrectionStats>
+where
+    F: FnOnce() -> CorrectionConfig<'static> + Send + 'static
+{
+    let input = File::open(input_fastq_filename).await?;
+    let output = File::create(output_fastq_filename).await?;
+
+    let (input_sender, input_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let (output_sender, output_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let reader = task::spawn(read_chunks(input, input_sender));
+    let writer = task::spawn(write_chunks(output, output_receiver));
+
+    let [input_name, whitelist_filename, counts_filename] = [input_fastq_filename, whitelist_filename, counts_filename].map(str::to_string);
+    let corrector = task::spawn_blocking(move || {
+        let input = ChannelReader {chunks: input_receiver, chunk: Vec::new(), position: 0};
+        let mut output = ChannelWriter {chunks: output_sender, chunk: Vec::with_capacity(CHUNK_SIZE)};
+        let stats = correct_barcodes_in_stream(input, &input_name, &whitelist_filename, &counts_filename, &mut output, &config())?;
+        output.flush()?;
+        Ok::<_, io::Error>(stats)
+    });
+
+    // the corrector finishing (or failing) closes the output channel, ending the writer, and the input
+    // channel, ending the reader
+    let stats = corrector.await.map_err(io::Error::other)?;
+    let written = writer.await.map_err(io::Error::other)?;
+    reader.await.map_err(io::Error::other)?;
+    let stats = stats?;
+    written?;
+    Ok(stats)
+}
@@ -0,0 +1,316 @@
+use std::fs::File;
+use std::io::{self,Read,Write,BufReader};
+use flate2::read::MultiGzDecoder;
+use log::info;
+use crate::io::create_output;
+
+const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+/// Reads must have all of these flags (paired, properly paired) to be used for fragments
+const FRAGMENT_REQUIRED_FLAGS: u16 = 0x1 | 0x2;
+
+/// Reads with any of these flags (unmapped, mate unmapped, secondary, QC fail, duplicate, supplementary)
+/// are not used for fragments
+const FRAGMENT_EXCLUDED_FLAGS: u16 = 0x4 | 0x8 | 0x100 | 0x200 | 0x400 | 0x800;
+
+/// Offsets of the Tn5 insertion sites relative to the ends of the aligned fragment
+const TN5_PLUS_STRAND_SHIFT: i64 = 4;
+const TN5_MINUS_STRAND_SHIFT: i64 = -5;
+
+/// The fields of a BAM record needed to call fragments.
+struct BamRecord {
+    ref_id: i32,
+    pos: i32,
+    flag: u16,
+    tlen: i32,
+    aux: Vec<u8>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0_u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+/// A length or count field of a BAM file, which is an error if negative.
+fn to_length(value: i32, field: &str) -> io::Result<usize> {
+    usize::try_from(value).map_err(|_| invalid_data(&format!("Negative BAM {} ({})", field, value)))
+}
+
+/// Read exactly `length` bytes, without allocating them up front (so a corrupt length in a truncated file
+/// is an error rather than a huge allocation).
+fn read_bytes<R: Read>(reader: &mut R, length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated BAM file"));
+    }
+    Ok(bytes)
+}
+
+fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+/// Read the BAM header, returning the reference sequence names.
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Vec<String>> {
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != BAM_MAGIC {
+        return Err(invalid_data("Not a BAM file"));
+    }
+
+    // skip the SAM header text
+    let l_text = to_length(read_i32(reader)?, "header text length")?;
+    if io::copy(&mut reader.take(l_text as u64), &mut io::sink())? < l_text as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated BAM header"));
+    }
+
+    let n_ref = to_length(read_i32(reader)?, "reference count")?;
+    let mut references: Vec<String> = Vec::new();
+    for _ in 0..n_ref {
+        let l_name = to_length(read_i32(reader)?, "reference name length")?;
+        let mut name = read_bytes(reader, l_name)?;
+        name.pop(); // NUL terminator
+        references.push(String::from_utf8(name).map_err(|_| invalid_data("Invalid reference name"))?);
+        read_i32(reader)?; // reference length
+    }
+
+    Ok(references)
+}
+
+/// Read the next BAM record, or None at the end of the file.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<BamRecord>> {
+    let mut block_size = [0_u8; 4];
+    match reader.read_exact(&mut block_size) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    }
+
+    let block = read_bytes(reader, to_length(i32::from_le_bytes(block_size), "record size")?)?;
+    if block.len() < 32 {
+        return Err(invalid_data("Truncated BAM record"));
+    }
+
+    let l_read_name = block[8] as usize;
+    let n_cigar_op = u16_at(&block, 12) as usize;
+    let l_seq = to_length(i32_at(&block, 16), "sequence length")?;
+    let aux_start = l_seq.checked_add(l_seq.div_ceil(2))
+        .and_then(|n| n.checked_add(32 + l_read_name + 4 * n_cigar_op))
+        .filter(|&aux_start| aux_start <= block.len())
+        .ok_or_else(|| invalid_data("Truncated BAM record"))?;
+
+    Ok(Some(BamRecord {
+        ref_id: i32_at(&block, 0),
+        pos: i32_at(&block, 4),
+        flag: u16_at(&block, 14),
+        tlen: i32_at(&block, 28),
+        aux: block[aux_start..].to_vec(),
+    }))
+}
+
+/// Size in bytes of a single value of a BAM aux field type.
+fn aux_value_size(value_type: u8) -> io::Result<usize> {
+    match value_type {
+        b'A' | b'c' | b'C' => Ok(1),
+        b's' | b'S' => Ok(2),
+        b'i' | b'I' | b'f' => Ok(4),
+        _ => Err(invalid_data("Invalid BAM aux field type"))
+    }
+}
+
+/// Find the value of a string (`Z`) aux field in a BAM record's aux data.
+fn find_string_tag<'a>(aux: &'a [u8], tag: &[u8; 2]) -> io::Result<Option<&'a [u8]>> {
+    let mut i = 0;
+    while i + 3 <= aux.len() {
+        let value_type = aux[i + 2];
+        let value_start = i + 3;
+
+        let value_end = match value_type {
+            b'Z' | b'H' => {
+                let length = aux[value_start..].iter().position(|&b| b == 0).ok_or_else(|| invalid_data("Unterminated BAM aux string"))?;
+                if &aux[i..i + 2] == tag && value_type == b'Z' {
+                    return Ok(Some(&aux[value_start..value_start + length]));
+                }
+                value_start + length + 1
+            },
+            b'B' => {
+                if value_start + 5 > aux.len() {
+                    return Err(invalid_data("Truncated BAM aux array"));
+                }
+                let count = to_length(i32_at(aux, value_start + 1), "aux array length")?;
+                count.checked_mul(aux_value_size(aux[value_start])?)
+                    .and_then(|size| size.checked_add(value_start + 5))
+                    .ok_or_else(|| invalid_data("Truncated BAM aux array"))?
+            },
+            _ => value_start + aux_value_size(value_type)?
+        };
+        if value_end > aux.len() {
+            return Err(invalid_data("Truncated BAM aux field"));
+        }
+
+        i = value_end;
+    }
+
+    Ok(None)
+}
+
+/// Write a fragment file from a BAM file of aligned, barcode-corrected read pairs.
+///
+/// Each properly paired, primary, non-duplicate read pair whose barcode has been corrected (i.e., has a
+/// `CB:Z` tag) gives one fragment, spanning the Tn5 insertion sites (shifted +4/-5 bp from the aligned
+/// ends). The fragments are deduplicated, sorted, and written as BED4 (chrom, start, end, barcode); the
+/// output is gzipped if the filename ends with `.gz`. Returns the number of fragments written.
+pub fn bam_to_fragments(bam_filename: &str, fragments_filename: &str) -> io::Result<usize> {
+    let mut bam = BufReader::new(MultiGzDecoder::new(File::open(bam_filename)?));
+    let references = read_header(&mut bam)?;
+
+    let mut fragments: Vec<(i32, i64, i64, Vec<u8>)> = Vec::new();
+    let mut total: usize = 0;
+
+    while let Some(record) = read_record(&mut bam)? {
+        total += 1;
+
+        // each pair is counted once, from its leftmost read
+        if record.flag & FRAGMENT_REQUIRED_FLAGS != FRAGMENT_REQUIRED_FLAGS || record.flag & FRAGMENT_EXCLUDED_FLAGS != 0 || record.tlen <= 0 {
+            continue;
+        }
+
+        if let Some(barcode) = find_string_tag(&record.aux, b"CB")? {
+            let start = record.pos as i64 + TN5_PLUS_STRAND_SHIFT;
+            let end = record.pos as i64 + record.tlen as i64 + TN5_MINUS_STRAND_SHIFT;
+            fragments.push((record.ref_id, start, end, barcode.to_vec()));
+        }
+    }
+
+    fragments.sort();
+    fragments.dedup();
+
+    let mut writer = create_output(fragments_filename)?;
+    for (ref_id, start, end, barcode) in fragments.iter() {
+        let chrom = usize::try_from(*ref_id).ok().and_then(|i| references.get(i)).ok_or_else(|| invalid_data("Invalid BAM reference id"))?;
+        writeln!(writer, "{}\t{}\t{}\t{}", chrom, start, end, String::from_utf8_lossy(barcode))?;
+    }
+    writer.flush()?;
+
+    info!("Wrote {} unique fragments from {} BAM records", fragments.len(), total);
+
+    Ok(fragments.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    /// A BAM record of an 8bp read with no CIGAR operations.
+    fn bam_record(ref_id: i32, pos: i32, flag: u16, tlen: i32, aux: &[u8]) -> Vec<u8> {
+        let mut block: Vec<u8> = Vec::new();
+        block.extend(ref_id.to_le_bytes());
+        block.extend(pos.to_le_bytes());
+        block.extend([3, 60]); // l_read_name, mapq
+        block.extend(0_u16.to_le_bytes()); // bin
+        block.extend(0_u16.to_le_bytes()); // n_cigar_op
+        block.extend(flag.to_le_bytes());
+        block.extend(8_i32.to_le_bytes()); // l_seq
+        block.extend(ref_id.to_le_bytes()); // next_refID
+        block.extend((pos + tlen.max(0)).to_le_bytes()); // next_pos
+        block.extend(tlen.to_le_bytes());
+        block.extend(b"r1\0");
+        block.extend([0x12, 0x48, 0x12, 0x48]); // ACGTACGT
+        block.extend([40; 8]);
+        block.extend(aux);
+
+        let mut record = (block.len() as i32).to_le_bytes().to_vec();
+        record.extend(block);
+        record
+    }
+
+    /// Write a (gzipped) BAM file with references chr1 and chr2.
+    fn write_bam(name: &str, records: &[Vec<u8>]) -> String {
+        let mut bam: Vec<u8> = BAM_MAGIC.to_vec();
+        let text = b"@HD\tVN:1.6\n";
+        bam.extend((text.len() as i32).to_le_bytes());
+        bam.extend(text);
+        bam.extend(2_i32.to_le_bytes());
+        for reference in [&b"chr1\0"[..], b"chr2\0"] {
+            bam.extend((reference.len() as i32).to_le_bytes());
+            bam.extend(reference);
+            bam.extend(1000_i32.to_le_bytes());
+        }
+        for record in records {
+            bam.extend(record);
+        }
+
+        let mut encoder = GzEncoder::new(File::create(path(name)).unwrap(), Compression::fast());
+        encoder.write_all(&bam).unwrap();
+        encoder.finish().unwrap();
+        path(name)
+    }
+
+    #[test]
+    fn fragments_are_filtered_shifted_and_deduplicated() {
+        const PAIRED: u16 = 0x1 | 0x2;
+        let records = [
+            bam_record(0, 100, PAIRED | 0x20 | 0x40, 200, b"CBZAAAA\0"),
+            // its mate, and a PCR duplicate of the pair that wasn't flagged
+            bam_record(0, 292, PAIRED | 0x10 | 0x80, -200, b"CBZAAAA\0"),
+            bam_record(0, 100, PAIRED | 0x20 | 0x40, 200, b"CBZAAAA\0"),
+            // flagged duplicate, secondary, not properly paired, and uncorrected barcode
+            bam_record(0, 500, PAIRED | 0x400, 100, b"CBZAAAA\0"),
+            bam_record(0, 600, PAIRED | 0x100, 100, b"CBZAAAA\0"),
+            bam_record(0, 700, 0x1, 100, b"CBZAAAA\0"),
+            bam_record(0, 800, PAIRED, 100, b"CRZGGGG\0"),
+            // CB after an array and an integer field
+            bam_record(1, 50, PAIRED, 100, b"XBBs\x02\0\0\0\x01\0\x02\0NMC\x01CBZCCCC\0"),
+        ];
+        let bam = write_bam("bam_fragments.bam", &records);
+
+        assert_eq!(bam_to_fragments(&bam, &path("bam_fragments.bed")).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(path("bam_fragments.bed")).unwrap(), "chr1\t104\t295\tAAAA\nchr2\t54\t145\tCCCC\n");
+    }
+
+    #[test]
+    fn corrupt_lengths_are_errors() {
+        // a negative record size
+        let mut record = bam_record(0, 100, 0x3, 200, b"CBZAAAA\0");
+        record[..4].copy_from_slice(&(-1_i32).to_le_bytes());
+        let bam = write_bam("bam_negative_size.bam", &[record]);
+        assert_eq!(bam_to_fragments(&bam, &path("bam_negative_size.bed")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a record size far beyond the end of the file
+        let mut record = bam_record(0, 100, 0x3, 200, b"CBZAAAA\0");
+        record[..4].copy_from_slice(&i32::MAX.to_le_bytes());
+        let bam = write_bam("bam_huge_size.bam", &[record]);
+        assert_eq!(bam_to_fragments(&bam, &path("bam_huge_size.bed")).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // a negative sequence length
+        let mut record = bam_record(0, 100, 0x3, 200, b"CBZAAAA\0");
+        record[20..24].copy_from_slice(&(-8_i32).to_le_bytes());
+        let bam = write_bam("bam_negative_seq.bam", &[record]);
+        assert_eq!(bam_to_fragments(&bam, &path("bam_negative_seq.bed")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // aux arrays whose length would overflow, or is negative
+        for count in [i32::MAX, -1] {
+            let mut aux = b"XBBI".to_vec();
+            aux.extend(count.to_le_bytes());
+            aux.extend(b"CBZAAAA\0");
+            let bam = write_bam("bam_aux_array.bam", &[bam_record(0, 100, 0x3, 200, &aux)]);
+            assert_eq!(bam_to_fragments(&bam, &path("bam_aux_array.bed")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs::{self,File};
+use std::io::{self,Write};
+use std::time::{Duration,Instant};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::correct::{correct_barcodes,read_configured_whitelist,CorrectionConfig,CorrectionStats};
+
+/// Fraction of synthetic barcodes that are exact whitelist matches.
+pub const BENCHMARK_EXACT_FRACTION: f64 = 0.8;
+
+/// Fraction of synthetic barcodes that are one substitution away from a whitelisted barcode (the rest are
+/// random sequences).
+pub const BENCHMARK_MUTANT_FRACTION: f64 = 0.15;
+
+/// Seed of the synthetic read generator, so that benchmarks are reproducible.
+const BENCHMARK_SEED: u64 = 0x5eed;
+
+/// Quality string character of synthetic barcodes.
+const BENCHMARK_QUALITY: u8 = b'F';
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// A small deterministic PRNG (SplitMix64); statistical quality is not a concern for synthetic reads (or
+/// for subsampling).
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, n)
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Uniform in [0, 1)
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Write `n` synthetic gzipped fastq records whose sequences are barcodes drawn from the whitelist:
+/// [`BENCHMARK_EXACT_FRACTION`] exact matches, [`BENCHMARK_MUTANT_FRACTION`] with one substitution, and the
+/// rest random. Returns the number of (uncompressed) bytes written.
+fn write_synthetic_fastq(filename: &str, whitelist: &[Vec<u8>], n: usize) -> io::Result<u64> {
+    let mut rng = SplitMix64(BENCHMARK_SEED);
+    let barcode_length = whitelist[0].len();
+    let quality = vec![BENCHMARK_QUALITY; barcode_length];
+    let mut writer = GzEncoder::new(File::create(filename)?, Compression::fast());
+    let mut bytes: u64 = 0;
+
+    for i in 0..n {
+        let draw = rng.unit();
+        let barcode = if draw < BENCHMARK_EXACT_FRACTION + BENCHMARK_MUTANT_FRACTION {
+            let mut barcode = whitelist[rng.below(whitelist.len())].clone();
+            if draw >= BENCHMARK_EXACT_FRACTION {
+                let position = rng.below(barcode_length);
+                let substitutes: Vec<u8> = BASES.iter().copied().filter(|&b| b != barcode[position]).collect();
+                barcode[position] = substitutes[rng.below(substitutes.len())];
+            }
+            barcode
+        } else {
+            (0..barcode_length).map(|_| BASES[rng.below(BASES.len())]).collect()
+        };
+
+        let mut record = format!("@benchmark{}\n", i).into_bytes();
+        record.extend_from_slice(&barcode);
+        record.extend_from_slice(b"\n+\n");
+        record.extend_from_slice(&quality);
+        record.push(b'\n');
+        writer.write_all(&record)?;
+        bytes += record.len() as u64;
+    }
+
+    writer.finish()?;
+    Ok(bytes)
+}
+
+/// Throughput of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchmarkResult {
+    pub records: usize,
+    /// Size of the synthetic fastq, uncompressed
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub stats: CorrectionStats,
+}
+
+impl BenchmarkResult {
+    pub fn records_per_second(&self) -> f64 {
+        self.records as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn megabytes_per_second(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Measure correction throughput on `n` synthetic reads.
+///
+/// The reads (see [`BENCHMARK_EXACT_FRACTION`] and [`BENCHMARK_MUTANT_FRACTION`]) are generated with a fixed
+/// seed into a temporary file, which is then run through [`correct_barcodes`] with the corrected output
+/// discarded. Only the correction is timed.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::benchmark::benchmark;
+/// use barcodes::correct::CorrectionConfig;
+/// let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// fs::write(path("benchmark_whitelist.txt"), "ACGTACGT\nTTTTGGGG\nGGCCAATT\n").unwrap();
+/// fs::write(path("benchmark_counts.tsv"), "ACGTACGT\t10\nTTTTGGGG\t20\n").unwrap();
+/// let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+/// let result = benchmark(1000, &path("benchmark_whitelist.txt"), &path("benchmark_counts.tsv"), &config).unwrap();
+/// assert_eq!(result.stats.total, 1000);
+/// assert!(result.stats.matched_whitelist_before_correction >= 700);
+/// assert!(result.stats.matched_whitelist_after_correction > result.stats.matched_whitelist_before_correction);
+/// // "@benchmark{i}\n", then the barcode, "+" and quality lines
+/// assert_eq!(result.bytes, 1000 * 31 + (0..1000usize).map(|i| i.to_string().len() as u64).sum::<u64>());
+/// assert!(result.records_per_second() > 0.0);
+/// ```
+pub fn benchmark(n: usize, whitelist_filename: &str, counts_filename: &str, config: &CorrectionConfig) -> io::Result<BenchmarkResult> {
+    let whitelist = read_configured_whitelist(whitelist_filename, config)?;
+    if whitelist.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Whitelist is empty"));
+    }
+
+    let fastq_filename = std::env::temp_dir().join(format!("barcodes_benchmark_{}.fastq.gz", std::process::id())).to_str().unwrap().to_string();
+    let bytes = write_synthetic_fastq(&fastq_filename, &whitelist, n)?;
+
+    let start = Instant::now();
+    let stats = correct_barcodes(&fastq_filename, whitelist_filename, counts_filename, io::sink(), config);
+    let elapsed = start.elapsed();
+    fs::remove_file(&fastq_filename)?;
+
+    Ok(BenchmarkResult {records: n, bytes, elapsed, stats: stats?})
+}
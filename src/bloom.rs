@@ -0,0 +1,81 @@
+use std::hash::{DefaultHasher,Hash,Hasher};
+use std::io;
+
+/// A Bloom filter of whitelisted barcodes: a compact set that answers membership with no false negatives,
+/// but with false positives at about a chosen rate.
+///
+/// A positive answer only means the barcode is probably whitelisted, so it must be confirmed (e.g., against
+/// the whitelist [`Trie`](crate::trie::Trie)) before being trusted, while a negative answer is final.
+///
+/// # Example
+/// ```
+/// use barcodes::bloom::BloomWhitelist;
+/// let whitelist: Vec<&[u8]> = vec![b"ACGTACGT", b"TTTTTTTT"];
+/// let bloom = BloomWhitelist::from_words(&whitelist, 0.01).unwrap();
+/// assert!(bloom.contains(b"ACGTACGT"));
+/// assert!(bloom.contains(b"TTTTTTTT"));
+/// // there are false positives, but few at this rate
+/// let false_positives = (0..1000).filter(|i| bloom.contains(format!("{:08}", i).as_bytes())).count();
+/// assert!(false_positives < 50);
+/// assert!(BloomWhitelist::from_words(&whitelist, 1.5).is_err());
+/// ```
+pub struct BloomWhitelist {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomWhitelist {
+    /// Create an empty filter sized for `expected_words` words at a false positive rate of
+    /// `false_positive_rate` (which must be strictly between 0 and 1).
+    pub fn new(expected_words: usize, false_positive_rate: f64) -> io::Result<BloomWhitelist> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bloom filter false positive rate must be between 0 and 1 (got {})", false_positive_rate)));
+        }
+
+        // the optimal number of bits and hash functions for the expected number of words
+        let n = expected_words.max(1) as f64;
+        let num_bits = (-n * false_positive_rate.ln() / 2_f64.ln().powi(2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * 2_f64.ln()).round().max(1.0) as u32;
+        Ok(BloomWhitelist {bits: vec![0; num_bits.div_ceil(64) as usize], num_bits, num_hashes})
+    }
+
+    /// Create a filter holding the given words, at a false positive rate of `false_positive_rate`.
+    pub fn from_words<W: AsRef<[u8]>>(words: &[W], false_positive_rate: f64) -> io::Result<BloomWhitelist> {
+        let mut bloom = BloomWhitelist::new(words.len(), false_positive_rate)?;
+        for word in words {
+            bloom.insert(word.as_ref());
+        }
+        Ok(bloom)
+    }
+
+    /// The bit positions of a barcode, by double hashing.
+    fn positions(&self, barcode: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            barcode.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1) | 1);
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits) as usize)
+    }
+
+    /// Add a barcode to the filter.
+    pub fn insert(&mut self, barcode: &[u8]) {
+        let positions: Vec<usize> = self.positions(barcode).collect();
+        for position in positions {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    /// Check whether a barcode may be in the filter (false means it definitely isn't).
+    pub fn contains(&self, barcode: &[u8]) -> bool {
+        self.positions(barcode).all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    /// Memory used by the filter's bits, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::{BTreeMap,HashMap};
+use std::hash::Hash;
+
+/// A least-recently-used cache holding up to a fixed number of entries.
+///
+/// Entries are stamped with the time they were last used, so that the least recently used one can be
+/// found (and evicted) in logarithmic time.
+///
+/// # Example
+/// ```
+/// use barcodes::cache::LruCache;
+/// let mut cache = LruCache::new(2);
+/// cache.put("a", 1);
+/// cache.put("b", 2);
+/// assert_eq!(cache.get(&"a"), Some(&1));
+/// // "b" is now the least recently used entry, so it's evicted to make room
+/// cache.put("c", 3);
+/// assert_eq!(cache.get(&"b"), None);
+/// assert_eq!(cache.get(&"a"), Some(&1));
+/// assert_eq!(cache.get(&"c"), Some(&3));
+/// assert_eq!(cache.len(), 2);
+/// ```
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    last_used: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Create a cache holding up to `capacity` entries (a capacity of 0 caches nothing).
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {capacity, entries: HashMap::new(), last_used: BTreeMap::new(), clock: 0}
+    }
+
+    /// Get the value of a key, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let (_, used) = self.entries.get_mut(key)?;
+        self.last_used.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.last_used.insert(self.clock, key.clone());
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Add (or replace) the value of a key, evicting the least recently used entry if the cache is full.
+    pub fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.last_used.remove(&used);
+        } else if self.entries.len() == self.capacity {
+            if let Some((_, evicted)) = self.last_used.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+
+        self.clock += 1;
+        self.last_used.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+    }
+
+    /// Number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
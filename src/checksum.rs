@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::{self,Write};
+use md5::Md5;
+use sha2::{Digest,Sha256};
+
+/// Hash function of input file checksums.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+/// The running state of one of the hash functions.
+enum State {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+/// A checksum computed as bytes are written to it, so that a file can be checksummed while it's read for
+/// something else (see [`crate::io::open_fastq_checksummed`]).
+///
+/// # Example
+/// ```
+/// use std::io::Write;
+/// use barcodes::checksum::{Checksum,ChecksumAlgorithm};
+/// let mut md5 = Checksum::new(ChecksumAlgorithm::Md5);
+/// md5.write_all(b"The quick brown fox ").unwrap();
+/// md5.write_all(b"jumps over the lazy dog").unwrap();
+/// assert_eq!(md5.finish(), "9e107d9d372bb6826bd81d3542a419d6");
+/// assert_eq!(Checksum::new(ChecksumAlgorithm::Md5).finish(), "d41d8cd98f00b204e9800998ecf8427e");
+///
+/// let mut sha256 = Checksum::new(ChecksumAlgorithm::Sha256);
+/// sha256.write_all(b"abc").unwrap();
+/// assert_eq!(sha256.finish(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+/// // a message spanning several blocks, and whose padding spills into an extra block
+/// let mut sha256 = Checksum::new(ChecksumAlgorithm::Sha256);
+/// sha256.write_all(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").unwrap();
+/// assert_eq!(sha256.finish(), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+/// ```
+pub struct Checksum {
+    state: State,
+    /// Number of bytes written
+    length: u64,
+}
+
+impl Checksum {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Checksum {
+        let state = match algorithm {
+            ChecksumAlgorithm::Md5 => State::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha256 => State::Sha256(Sha256::new()),
+        };
+        Checksum {state, length: 0}
+    }
+
+    /// Number of bytes checksummed so far.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The checksum of the bytes written so far, as lowercase hex.
+    pub fn finish(self) -> String {
+        let digest: Vec<u8> = match self.state {
+            State::Md5(md5) => md5.finalize().to_vec(),
+            State::Sha256(sha256) => sha256.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            State::Md5(md5) => md5.update(buf),
+            State::Sha256(sha256) => sha256.update(buf),
+        }
+        self.length += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compute the checksum of a file, as lowercase hex.
+pub fn file_checksum(filename: &str, algorithm: ChecksumAlgorithm) -> io::Result<String> {
+    let mut checksum = Checksum::new(algorithm);
+    io::copy(&mut File::open(filename)?, &mut checksum)?;
+    Ok(checksum.finish())
+}
+
+/// Check a checksum against the one expected (as hex, in either case), returning an error naming the file if
+/// they differ.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::checksum::{file_checksum,verify_checksum,ChecksumAlgorithm};
+/// let path = std::env::temp_dir().join("checksum_example.txt");
+/// fs::write(&path, "The quick brown fox jumps over the lazy dog").unwrap();
+/// let path = path.to_str().unwrap();
+/// let checksum = file_checksum(path, ChecksumAlgorithm::Sha256).unwrap();
+/// assert_eq!(checksum, "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592");
+/// assert!(verify_checksum(&checksum, "D7A8FBB307D7809469CA9ABCB0082E4F8D5651E46D3CDB762D02D0BF37C9E592", path).is_ok());
+/// assert!(verify_checksum(&checksum, "9e107d9d372bb6826bd81d3542a419d6", path).is_err());
+/// ```
+pub fn verify_checksum(checksum: &str, expected: &str, filename: &str) -> io::Result<()> {
+    if checksum.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("Checksum of {} is {}, but {} was expected (the file may be truncated or corrupted)", filename, checksum, expected)))
+    }
+}
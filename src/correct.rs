@@ -1,14 +1,31 @@
 use std::fs::File;
 use std::collections::{HashSet,HashMap};
+use std::rc::Rc;
+use std::cell::{Cell,RefCell,OnceCell};
+use std::thread::{self,ScopedJoinHandle};
+use std::borrow::Cow;
 use std::cmp;
-use flate2::read::GzDecoder;
+use std::time::{Duration,Instant};
+use std::sync::atomic::Ordering;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use std::io::{Read,BufReader,BufWriter};
+use std::io::{self,Read,Write,BufWriter};
 use bio::io::fastq;
-use itertools::izip;
-use log::info;
-use crate::trie::Trie;
+use bio::alphabets::dna::revcomp;
+use itertools::{izip,Itertools};
+use clap::ValueEnum;
+use log::{info,warn};
+use crate::trie::{Trie,PartitionedTrie,SubstitutionCosts,SearchStats};
+use crate::io::{open_fastq,is_gzipped,open_fastq_checksummed,open_fastq_stream,finish_checksum,open_output,create_output,to_phred33,shift_quality,CountingWriter,MappedFile,BgzfWriter,PHRED33_OFFSET};
+use crate::checksum::{verify_checksum,ChecksumAlgorithm};
+use crate::progress::ProgressBar;
+use crate::error_profile::{ErrorProfile,ErrorModel};
+use crate::disk_counts::DiskCounts;
+use crate::bloom::BloomWhitelist;
+use crate::cache::LruCache;
+use crate::benchmark::SplitMix64;
+use crate::multiqc::{write_multiqc,sample_name};
+use crate::whitelist::{read_whitelist,expand_whitelist,barcode_length,barcode_lengths,precompute_neighborhood,Neighborhoods,WhitelistColumn,WhitelistFile,DEFAULT_MAX_IUPAC_EXPANSIONS};
 
 
 
@@ -22,144 +39,3692 @@ use crate::trie::Trie;
 
 // To match CellRanger corrections, max_allowed_quality should be 66
 fn probability_of_incorrect_base_call(quality_score: &u8, max_quality_score: &u8) -> f64 {
-    let q = (cmp::min(*quality_score, *max_quality_score) as f64) - 33.0;
+    // bytes below '!' aren't valid phred scores, and are treated as phred 0 (a certain error)
+    let q = cmp::min(*quality_score, *max_quality_score).saturating_sub(33) as f64;
     let power_base: f64 = 10.0;
-    power_base.powf(-1.0 * q / 10.0)
+    power_base.powf(-q / 10.0)
 }
 
-fn probability_of_incorrect_base_calls(uncorrected: &[u8], corrected: &[u8], phred: &[u8]) -> f64 {
+/// Phred score given to every position of barcodes read from record names, which have no qualities.
+const READ_NAME_BARCODE_QUALITY: u8 = b'I';
+
+/// The barcode of a record and its phred scores (before trimming, as phred+33): the record's sequence, or, if
+/// `config.read_name_delimiter` is set, the last field of its name (with a uniform placeholder quality).
+fn record_barcode<'a>(record: &'a fastq::Record, config: &CorrectionConfig) -> (&'a [u8], Cow<'a, [u8]>) {
+    match config.read_name_delimiter {
+        Some(delimiter) => {
+            let barcode = record.id().rsplit_once(delimiter).map_or("", |(_name, barcode)| barcode).as_bytes();
+            (barcode, Cow::Owned(vec![READ_NAME_BARCODE_QUALITY; barcode.len()]))
+        },
+        None => (record.seq(), to_phred33(record.qual(), config.phred_offset))
+    }
+}
+
+/// The name and description of an output record. By default, this is the record's name, with the barcode
+/// `tags` as the description. If the barcode is read from the record's name, it's instead the name with the
+/// corrected barcode (if any) in place of the uncorrected one, and the record's own description. With
+/// [`BarcodeOutput::Cellranger`], it's the name with `#` and the corrected barcode (if any) appended, and
+/// the record's own description.
+fn output_name_and_description(record: &fastq::Record, corrected_barcode: Option<&[u8]>, tags: String, config: &CorrectionConfig) -> (String, Option<String>) {
+    if config.read_name_delimiter.is_none() && config.barcode_output == BarcodeOutput::Cellranger {
+        let name = match corrected_barcode {
+            Some(corrected) => format!("{}{}{}", record.id(), CELLRANGER_BARCODE_DELIMITER, String::from_utf8_lossy(corrected)),
+            None => record.id().to_string()
+        };
+        return (name, record.desc().map(|d| d.to_string()));
+    }
+
+    match config.read_name_delimiter {
+        Some(delimiter) => {
+            let name = match (record.id().rsplit_once(delimiter), corrected_barcode) {
+                (Some((name, _barcode)), Some(corrected)) => format!("{}{}{}", name, delimiter, String::from_utf8_lossy(corrected)),
+                _ => record.id().to_string()
+            };
+            (name, record.desc().map(|d| d.to_string()))
+        },
+        None => (record.id().to_string(), Some(tags))
+    }
+}
+
+/// Trim a fixed number of bases from the 5' and 3' ends of a barcode and its phred scores.
+fn trim_barcode<'s, 'p>(seq: &'s [u8], phred: &'p [u8], trim_5p: usize, trim_3p: usize) -> (&'s [u8], &'p [u8]) {
+    let start = cmp::min(trim_5p, seq.len());
+    let end = cmp::max(start, seq.len().saturating_sub(trim_3p));
+    (&seq[start..end], &phred[start..end])
+}
+
+/// Length of the adapter at the 5' end of a barcode (0 if `config.adapter` isn't set, or the barcode doesn't
+/// start with it, within `config.adapter_mismatches` mismatches).
+fn adapter_prefix_length(seq: &[u8], config: &CorrectionConfig) -> usize {
+    match &config.adapter {
+        Some(adapter) if seq.len() >= adapter.len() && izip!(adapter, seq).filter(|(a, b)| a != b).count() <= config.adapter_mismatches => adapter.len(),
+        _ => 0
+    }
+}
+
+/// Splice a linker of `length` bases starting at `offset` out of a barcode and its phred scores, joining
+/// the flanking segments (a linker running past the end of the barcode is removed up to the end).
+fn remove_linker(seq: &[u8], phred: &[u8], offset: usize, length: usize) -> (Vec<u8>, Vec<u8>) {
+    let start = cmp::min(offset, seq.len());
+    let end = cmp::min(start.saturating_add(length), seq.len());
+    ([&seq[..start], &seq[end..]].concat(), [&phred[..start], &phred[end..]].concat())
+}
+
+/// The barcode of a record as it is matched against the whitelist, and its phred scores: the
+/// [`record_barcode`], with any adapter trimmed from its 5' end, trimmed, with any linker removed, and then reverse complemented (with the phred
+/// scores reversed) if `config.revcomp` is set.
+fn matching_barcode<'a>(record: &'a fastq::Record, config: &CorrectionConfig) -> (Cow<'a, [u8]>, Cow<'a, [u8]>) {
+    let (barcode, barcode_qual) = record_barcode(record, config);
+    let trim_5p = adapter_prefix_length(barcode, config) + config.trim_5p;
+    let (seq, qual) = match barcode_qual {
+        Cow::Borrowed(qual) => {
+            let (seq, qual) = trim_barcode(barcode, qual, trim_5p, config.trim_3p);
+            (Cow::Borrowed(seq), Cow::Borrowed(qual))
+        },
+        Cow::Owned(qual) => {
+            let (seq, qual) = trim_barcode(barcode, &qual, trim_5p, config.trim_3p);
+            (Cow::Borrowed(seq), Cow::Owned(qual.to_vec()))
+        }
+    };
+    let (seq, qual) = if config.linker_length > 0 {
+        let (seq, qual) = remove_linker(&seq, &qual, config.linker_offset, config.linker_length);
+        (Cow::Owned(seq), Cow::Owned(qual))
+    } else {
+        (seq, qual)
+    };
+
+    if config.revcomp {
+        (Cow::Owned(revcomp(seq.iter())), Cow::Owned(qual.iter().rev().copied().collect()))
+    } else {
+        (seq, qual)
+    }
+}
+
+/// A [`matching_barcode`] (and its phred scores) that's too long for the whitelist, truncated to the longest
+/// whitelist barcode length (of `barcode_lengths`, shortest first) it exceeds, if `config.length_mismatch` is
+/// [`LengthMismatch::Truncate`]. Returns whether it was truncated.
+fn truncate_barcode<'a>(seq: Cow<'a, [u8]>, qual: Cow<'a, [u8]>, barcode_lengths: &[usize], config: &CorrectionConfig) -> (Cow<'a, [u8]>, Cow<'a, [u8]>, bool) {
+    let truncated_length = match config.length_mismatch {
+        LengthMismatch::Truncate if !barcode_lengths.contains(&seq.len()) => barcode_lengths.iter().rev().copied().find(|&length| length < seq.len()),
+        _ => None,
+    };
+    let Some(length) = truncated_length else {
+        return (seq, qual, false);
+    };
+    let truncate = |s: Cow<'a, [u8]>| match s {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[..length]),
+        Cow::Owned(mut s) => {
+            s.truncate(length);
+            Cow::Owned(s)
+        }
+    };
+    (truncate(seq), truncate(qual), true)
+}
+
+/// Barcodes with a Shannon entropy (in bits per base) below this are considered low complexity.
+const LOW_COMPLEXITY_ENTROPY: f64 = 0.5;
+
+/// Shannon entropy of a sequence's base composition, in bits per base.
+///
+/// # Example
+/// ```
+/// use barcodes::correct::shannon_entropy;
+/// assert_eq!(shannon_entropy(b"AAAAAAAA"), 0.0);
+/// assert_eq!(shannon_entropy(b"ACGTACGT"), 2.0);
+/// assert!(shannon_entropy(b"AAAAAAAAAAAAAAAT") < 0.5);
+/// ```
+pub fn shannon_entropy(seq: &[u8]) -> f64 {
+    let mut base_counts: HashMap<u8, usize> = HashMap::new();
+    for &base in seq {
+        *base_counts.entry(base).or_insert(0) += 1;
+    }
+
+    let length = seq.len() as f64;
+    base_counts.values().map(|&c| {
+        let p = (c as f64) / length;
+        -p * p.log2()
+    }).sum()
+}
+
+/// Length of the longest run of identical consecutive bases in a sequence.
+///
+/// # Example
+/// ```
+/// use barcodes::correct::longest_homopolymer_run;
+/// assert_eq!(longest_homopolymer_run(b"ACGGGGTA"), 4);
+/// assert_eq!(longest_homopolymer_run(b"ACGT"), 1);
+/// assert_eq!(longest_homopolymer_run(b""), 0);
+/// ```
+pub fn longest_homopolymer_run(seq: &[u8]) -> usize {
+    seq.chunk_by(|a, b| a == b).map(|run| run.len()).max().unwrap_or(0)
+}
+
+/// Check whether any position of a barcode has a phred score below `min_quality`.
+fn has_low_quality_position(phred: &[u8], min_quality: u8) -> bool {
+    phred.iter().any(|&p| p.saturating_sub(33) < min_quality)
+}
+
+/// Probability used in place of the phred-based error probability for mismatches at low-quality positions
+/// (that of the true base being any particular one of the four).
+const RANDOM_BASE_PROBABILITY: f64 = 0.25;
+
+/// Likelihood of the sequencing errors needed to turn `corrected` into `uncorrected`.
+///
+/// This is the product of the error probabilities (derived from the phred scores) at the mismatched
+/// positions. If `min_base_qual` is set, mismatches at positions with a phred score below it are given
+/// the probability of a random base instead, so that they don't dominate the likelihood. If an
+/// `error_profile` is given, the probabilities of mismatches at the positions it covers are taken from it
+/// rather than from the phred scores. If an `error_model` is given, the probabilities of mismatches at the
+/// positions and read bases it covers are multiplied by its rates.
+///
+/// # Example
+/// ```
+/// use barcodes::correct::likelihood_of_errors;
+/// // one mismatch, at a position with phred score 2 ('#')
+/// let without_floor = likelihood_of_errors(b"ACGT", b"ACGA", b"III#", None, None, None);
+/// let with_floor = likelihood_of_errors(b"ACGT", b"ACGA", b"III#", Some(10), None, None);
+/// assert!((without_floor - 10_f64.powf(-0.2)).abs() < 1e-12);
+/// assert_eq!(with_floor, 0.25);
+/// ```
+pub fn likelihood_of_errors(uncorrected: &[u8], corrected: &[u8], phred: &[u8], min_base_qual: Option<u8>, error_profile: Option<&ErrorProfile>, error_model: Option<&ErrorModel>) -> f64 {
 
     assert_eq!(uncorrected.len(), corrected.len());
     
     let mut l: f64 = 1.0;
     
-    for (u, c, p) in izip!(uncorrected, corrected, phred) {
+    for (i, (u, c, p)) in izip!(uncorrected, corrected, phred).enumerate() {
         if u != c {
-            l *= probability_of_incorrect_base_call(p, &66);
+            l *= mismatch_probability(i, *u, *p, min_base_qual, error_profile, error_model);
         }
     }
 
     l
 }
 
-/// Correct a non-whitelisted barcode.
+/// Probability of the sequencing error behind a mismatch at position `i` of a barcode, where the read base
+/// is `u` with phred score `p` (see [`likelihood_of_errors`]). This doesn't depend on the whitelisted base.
+fn mismatch_probability(i: usize, u: u8, p: u8, min_base_qual: Option<u8>, error_profile: Option<&ErrorProfile>, error_model: Option<&ErrorModel>) -> f64 {
+    match (min_base_qual, error_profile.and_then(|e| e.probability(i))) {
+        (Some(q), _) if p.saturating_sub(33) < q => RANDOM_BASE_PROBABILITY,
+        (_, profile) => {
+            let probability = profile.unwrap_or_else(|| probability_of_incorrect_base_call(&p, &66));
+            probability * error_model.and_then(|m| m.rate(i, u)).unwrap_or(1.0)
+        },
+    }
+}
+
+/// How to handle reads whose barcode length differs from the whitelist barcode length.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LengthMismatch {
+    /// Stop with an error
+    Error,
+    /// Log a warning and leave the barcode uncorrected
+    Warn,
+    /// Silently leave the barcode uncorrected
+    Skip,
+    /// Truncate a barcode that's too long (e.g., from adapter read-through) to the whitelist barcode length,
+    /// keeping its first bases, and correct it; silently leave one that's too short uncorrected
+    Truncate,
+}
+
+/// Model of how likely a whitelisted barcode is to be read as the uncorrected barcode, which weighs the
+/// correction candidates along with their counts.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CorrectionModel {
+    /// The product of the error probabilities of the mismatched bases (see [`likelihood_of_errors`]), as
+    /// CellRanger does
+    Product,
+    /// The uncorrected barcode is a single multinomial draw over the candidates, each in proportion to
+    /// `exp(-sum of the phred scores of its mismatches)` times its count
+    Multinomial,
+}
+
+/// Compression format of the corrected fastq output(s).
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Standard gzip
+    Gzip,
+    /// Blocked gzip (see [`BgzfWriter`]), as preferred by samtools and htslib
+    Bgzf,
+}
+
+/// Where the corrected barcode of each record is written in the output fastq.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum BarcodeOutput {
+    /// As SAM tags (`CR:Z`, `CB:Z`, ...) in the record's description
+    Tags,
+    /// Appended to the record's name after a `#` (e.g., `read1#ACGTACGT`), leaving the description as it is;
+    /// records without a corrected barcode keep their name
+    Cellranger,
+    /// As tags, with the record's sequence replaced by the corrected barcode (or the uncorrected one, if it
+    /// wasn't corrected) followed by the UMI, as read by STARsolo's `--soloType CB_UMI_Simple` (requires a
+    /// UMI length)
+    Starsolo,
+}
+
+/// Separator between the read name and the corrected barcode with [`BarcodeOutput::Cellranger`].
+const CELLRANGER_BARCODE_DELIMITER: char = '#';
+
+/// How barcode counts are used as the prior for correction.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CountsMode {
+    /// Use the raw counts
+    Raw,
+    /// Divide the counts by the total count (before adding pseudocounts), converting them to frequencies
+    Frequency,
+}
+
+/// How often each whitelisted barcode was observed, the prior for correction.
+pub trait BarcodeCounts {
+    /// The count of a barcode (0 if it was never observed).
+    fn count(&self, barcode: &[u8]) -> f64;
+}
+
+impl BarcodeCounts for HashMap<&[u8], f64> {
+    fn count(&self, barcode: &[u8]) -> f64 {
+        *self.get(barcode).unwrap_or(&0.0)
+    }
+}
+
+impl BarcodeCounts for HashMap<&[u8], usize> {
+    fn count(&self, barcode: &[u8]) -> f64 {
+        *self.get(barcode).unwrap_or(&0) as f64
+    }
+}
+
+/// Counts kept on disk, scaled and given a pseudocount like those kept in memory (see `prior_counts`).
+struct DiskPriorCounts {
+    counts: DiskCounts,
+    count_unit: f64,
+    pseudocount: f64,
+    /// The first error reading the table since the last [`check`](DiskPriorCounts::check), as a lookup
+    /// can't fail
+    error: RefCell<Option<io::Error>>,
+}
+
+impl DiskPriorCounts {
+    fn new(counts: DiskCounts, count_unit: f64, pseudocount: f64) -> DiskPriorCounts {
+        DiskPriorCounts {counts, count_unit, pseudocount, error: RefCell::new(None)}
+    }
+
+    /// Return the first error reading the table (if any) since the last check, e.g., after a search, whose
+    /// result can't be trusted if there was one.
+    fn check(&self) -> io::Result<()> {
+        match self.error.borrow_mut().take() {
+            Some(e) => Err(io::Error::new(e.kind(), format!("Failed to read the disk-backed counts: {}", e))),
+            None => Ok(())
+        }
+    }
+}
+
+impl BarcodeCounts for DiskPriorCounts {
+    fn count(&self, barcode: &[u8]) -> f64 {
+        match self.counts.get(barcode) {
+            // only whitelisted barcodes are ever looked up, so each gets the pseudocount
+            Ok(count) => count.unwrap_or(0.0) * self.count_unit + self.pseudocount,
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                0.0
+            }
+        }
+    }
+}
+
+type CountsReader<'scope, 'a> = ScopedJoinHandle<'scope, io::Result<HashMap<&'a [u8], f64>>>;
+
+/// A (mapped) counts file parsed on another thread, so that parsing it overlaps with reading the whitelist;
+/// the counts, which borrow their barcodes from the file, are waited for when first needed.
+struct PendingCounts<'scope, 'a> {
+    reader: RefCell<Option<CountsReader<'scope, 'a>>>,
+    counts: OnceCell<HashMap<&'a [u8], f64>>,
+}
+
+impl<'scope, 'a: 'scope> PendingCounts<'scope, 'a> {
+    fn spawn(scope: &'scope thread::Scope<'scope, '_>, contents: &'a [u8], counts_filename: &'a str) -> PendingCounts<'scope, 'a> {
+        let reader = scope.spawn(move || parse_counts(contents, counts_filename));
+        PendingCounts {reader: RefCell::new(Some(reader)), counts: OnceCell::new()}
+    }
+
+    /// Counts that are never read (e.g., if kept on disk instead).
+    fn empty() -> PendingCounts<'scope, 'a> {
+        PendingCounts::ready(HashMap::new())
+    }
+
+    /// Counts that are already in memory.
+    fn ready(counts: HashMap<&'a [u8], f64>) -> PendingCounts<'scope, 'a> {
+        PendingCounts {reader: RefCell::new(None), counts: OnceCell::from(counts)}
+    }
+
+    fn get(&self) -> io::Result<&HashMap<&'a [u8], f64>> {
+        if let Some(reader) = self.reader.borrow_mut().take() {
+            let counts = reader.join().map_err(|_| io::Error::other("Reading the counts failed"))??;
+            info!("Read the counts of {} barcodes", counts.len());
+            let _ = self.counts.set(counts);
+        }
+        self.counts.get().ok_or_else(|| io::Error::other("Reading the counts failed"))
+    }
+}
+
+/// Where the fastq records come from: a gzipped fastq file, or a stream of one's (compressed) bytes, with a
+/// name for it (e.g., for the MultiQC sample name).
+enum FastqInput<'a> {
+    File(&'a str),
+    Stream(&'a str, Box<dyn Read + Send>),
+}
+
+/// Where the barcode counts come from: a counts file, or (summed) counts already in memory.
+enum CountsSource<'a> {
+    File(&'a str),
+    Memory(HashMap<Vec<u8>, f64>),
+}
+
+/// Parameters controlling barcode correction.
+pub struct CorrectionConfig<'a> {
+    /// Max Hamming distance between an uncorrected barcode and its correction
+    pub max_edit_distance: usize,
+    /// Min posterior probability required to accept a correction
+    pub threshold: f64,
+    /// If set, barcodes with any position below this phred score are not corrected
+    pub min_position_quality: Option<u8>,
+    /// Max number of concrete sequences a degenerate (IUPAC) whitelist barcode may expand to
+    pub max_iupac_expansions: usize,
+    /// How to handle reads whose barcode length differs from the whitelist barcode length
+    pub length_mismatch: LengthMismatch,
+    /// Number of correction rounds. After each round but the last, the counts are updated with that
+    /// round's corrections, and reads that couldn't be corrected are retried with the updated counts
+    pub correction_rounds: usize,
+    /// If set, a TSV of the read id, uncorrected barcode, corrected barcode and posterior of each
+    /// corrected read (not including exact matches) is written to this path (gzipped if it ends with `.gz`)
+    pub corrections_log_path: Option<String>,
+    /// How barcode counts are used as the prior for correction
+    pub counts_mode: CountsMode,
+    /// If set, this adapter (or primer) is trimmed from the 5' end of barcode reads that start with it before
+    /// correction, ahead of `trim_5p` (the output record itself is not trimmed)
+    pub adapter: Option<Vec<u8>>,
+    /// Max number of mismatches between the start of a barcode read and the adapter for it to be trimmed
+    pub adapter_mismatches: usize,
+    /// Number of bases to trim from the 5' end of the barcode read before correction (the output
+    /// record itself is not trimmed)
+    pub trim_5p: usize,
+    /// Number of bases to trim from the 3' end of the barcode read before correction (the output
+    /// record itself is not trimmed)
+    pub trim_3p: usize,
+    /// Offset (within the trimmed barcode) of a constant linker to remove before correction, joining the
+    /// segments on either side of it into the barcode that is matched
+    pub linker_offset: usize,
+    /// Length of the linker to remove (0 for no linker)
+    pub linker_length: usize,
+    /// If set, low complexity barcodes (Shannon entropy below 0.5 bits per base) are not corrected
+    pub filter_low_complexity: bool,
+    /// If set, barcodes containing a run of at least this many identical bases are not corrected
+    /// (and are tagged with `XF:Z:homopolymer`)
+    pub max_homopolymer_run: Option<usize>,
+    /// If set, reads whose barcode can't be corrected get their uncorrected barcode as `CB:Z`, rather
+    /// than having no `CB:Z` tag
+    pub assign_raw_if_uncorrectable: bool,
+    /// If set, mismatches at positions with a phred score below this are given the probability of a
+    /// random base in the likelihood, rather than the probability derived from the phred score
+    pub min_base_qual: Option<u8>,
+    /// If set, per-position substitution probabilities used in the likelihood in place of those derived
+    /// from the phred scores (positions are those of the barcode after trimming)
+    pub error_profile: Option<ErrorProfile>,
+    /// If set, per-position, per-base error rates by which the mismatch probabilities in the likelihood
+    /// (from the phred scores or `error_profile`) are multiplied, where the model covers the read's base
+    /// (positions are those of the barcode after trimming)
+    pub error_model: Option<ErrorModel>,
+    /// Barcodes in which more than this fraction of bases are N are not corrected
+    pub max_n_fraction: f64,
+    /// If set, a progress bar is drawn on stderr (if it's a terminal). The total number of records is
+    /// estimated from the size of a gzipped input, or counted in a pre-pass over an uncompressed one
+    pub progress: bool,
+    /// If set, a gzipped TSV describing the correction decision for every read is written to this path
+    /// (see [`AUDIT_LOG_HEADER`] for its columns)
+    pub audit_log_path: Option<String>,
+    /// If set, reads with a corrected (or whitelisted) barcode are demultiplexed by the first this many
+    /// bases of the barcode, into one output per prefix found in the whitelist; the remaining reads go to
+    /// the main output
+    pub demux_prefix_length: Option<usize>,
+    /// Path pattern of the demultiplexed outputs, in which `{prefix}` is replaced by the barcode prefix
+    pub demux_output_pattern: String,
+    /// Compression format of the corrected fastq output(s)
+    pub output_format: OutputFormat,
+    /// Where corrected barcodes are written in the output records (ignored if the barcode is read from the
+    /// read name, in which case it's always written back there)
+    pub barcode_output: BarcodeOutput,
+    /// If set, the sample index read of each record is corrected alongside the cell barcode
+    pub index: Option<IndexConfig>,
+    /// If set, the whitelist (after IUPAC expansion) is assumed to be sorted, so the trie can be built
+    /// faster; an out-of-order whitelist is an error
+    pub assume_sorted_whitelist: bool,
+    /// Offset of the UMI in the barcode read (before trimming)
+    pub umi_offset: usize,
+    /// If set, the UMI of this length at `umi_offset` in the barcode read is written to the output as
+    /// `UR:Z` (with its quality as `UY:Z`); it isn't corrected
+    pub umi_length: Option<usize>,
+    /// Number of records between progress reports (log lines, progress bar updates and calls to
+    /// `progress_callback`)
+    pub progress_interval: usize,
+    /// If set, called with the statistics so far at every progress report, and once more at the end
+    pub progress_callback: Option<&'a dyn Fn(&CorrectionStats)>,
+    /// If set, the barcode is read from the last field of the record's name (e.g., `read1_ACGTACGT` with
+    /// delimiter `_`) rather than from its sequence, and the corrected barcode is written back into the name,
+    /// leaving the record's description as it is
+    pub read_name_delimiter: Option<char>,
+    /// If set, the final statistics are written to this path as JSON
+    pub stats_json_path: Option<String>,
+    /// Concentration of the symmetric Dirichlet prior over whitelisted barcodes, i.e., the pseudocount added
+    /// to every whitelisted barcode's count. Values above 1 smooth the prior toward uniform (so that rarely
+    /// seen barcodes are less often corrected away from), and values below 1 concentrate it on the barcodes
+    /// with high counts
+    pub dirichlet_concentration: f64,
+    /// If true, the counts file isn't read, and every whitelisted barcode gets the same prior, so that
+    /// corrections are chosen by the likelihood of the errors alone (not compatible with multiple
+    /// correction rounds)
+    pub uniform_prior: bool,
+    /// Prior weight (in the same units as the counts, in which every whitelisted barcode gets a pseudocount
+    /// of `dirichlet_concentration`) of the hypothesis that a barcode with a single correction candidate is correct as read. The
+    /// candidate's posterior is its likelihood times its count, relative to the sum of that and this weight
+    pub uncorrected_prior: f64,
+    /// If set, the prior probability that a read's true barcode isn't in the whitelist. This hypothesis (which
+    /// needs no errors) is included in the posterior of every correction, whatever the number of candidates,
+    /// in place of `uncorrected_prior`
+    pub unmatched_prior: Option<f64>,
+    /// If set, a correction is only accepted if its likelihood times count is at least this many times that of
+    /// the runner-up candidate (as well as passing `threshold`)
+    pub min_likelihood_ratio: Option<f64>,
+    /// If set, the final statistics are written as MultiQC custom content to this path (see
+    /// [`write_multiqc`](crate::multiqc::write_multiqc))
+    pub multiqc_path: Option<String>,
+    /// If set, a TSV of each read's barcode tags (see [`TAGS_TSV_HEADER`]) is written to this path (gzipped
+    /// if it ends with `.gz`)
+    pub tags_tsv_path: Option<String>,
+    /// Whether to write the corrected fastq (turning this off is useful when only the tags TSV is needed)
+    pub write_fastq: bool,
+    /// If set, correction candidates are the whitelisted barcodes within a substitution cost (rather than a
+    /// Hamming distance) of `max_edit_distance` (the likelihood of a candidate is still based on quality)
+    pub substitution_costs: Option<SubstitutionCosts>,
+    /// If true, malformed fastq records (see [`CorrectionStats::malformed_records`]) are skipped and counted,
+    /// rather than stopping with an error
+    pub skip_errors: bool,
+    /// If set, each read's true barcode is taken from this tag (e.g., `GT`, for `GT:Z:<barcode>`) in its
+    /// input description, and the barcodes assigned are compared to it (see [`CorrectionStats::precision`])
+    pub ground_truth_tag: Option<String>,
+    /// If true, barcodes are reverse complemented (and their phred scores reversed) before matching against
+    /// the whitelist (after trimming), e.g., if the barcode is read from the opposite strand; the `CR:Z` and
+    /// `CY:Z` tags are given in this orientation
+    pub revcomp: bool,
+    /// If true, each output description is checked to be a strictly tab-separated block of SAM tags (see
+    /// [`parse_sam_tags`]), so that it can be carried into a BAM (e.g., with `samtools import -T` or an
+    /// aligner's comment passthrough); a description that isn't stops correction with an error
+    pub strict_sam_tags: bool,
+    /// If set, and the counts file is larger than this many bytes, counts are kept in a sorted table on disk
+    /// (see [`DiskCounts`]) rather than in memory, using at most about this much memory to build it (not
+    /// compatible with multiple correction rounds)
+    pub max_counts_memory: Option<usize>,
+    /// Max number of uncorrected barcodes (with their phred scores, which the correction depends on) whose
+    /// best correction is cached, so that repeated barcodes skip the trie search (0 disables the cache)
+    pub correction_cache_size: usize,
+    /// If set, the whitelist is a tab-separated table with the barcodes in this column (see
+    /// [`read_whitelist_column`]), rather than one barcode per line
+    pub whitelist_column: Option<WhitelistColumn>,
+    /// If true, the first (non-comment) line of a tabular whitelist is a header (always the case if
+    /// `whitelist_column` is a name)
+    pub whitelist_header: bool,
+    /// If set, the exact-match check of each barcode first tests a [`BloomWhitelist`] with this false
+    /// positive rate instead of the whitelist hash set, confirming positives against the whitelist trie
+    pub bloom_false_positive_rate: Option<f64>,
+    /// If set, only this fraction of the records (chosen at random, but reproducibly, with
+    /// `subsample_seed`) are corrected and written, e.g., to quickly estimate the correction rate
+    pub subsample: Option<f64>,
+    /// Seed of the random number generator used for subsampling
+    pub subsample_seed: u64,
+    /// Offset of the phred scores in the input quality strings (33, or 64 for legacy Illumina data; see
+    /// [`detect_phred_offset`](crate::io::detect_phred_offset)). Qualities are converted to phred+33 for
+    /// correction and in the output tags
+    pub phred_offset: u8,
+    /// If set, barcodes (e.g., of spike-in controls) that are assigned as-is (with `CB:Z` the same as `CR:Z`),
+    /// even though they aren't whitelisted
+    pub force_accept: Option<HashSet<Vec<u8>>>,
+    /// If set, the barcodes being corrected are feature barcodes (e.g., the antibody or hashtag oligos of
+    /// CITE-seq or cell hashing) rather than cell barcodes, and are tagged `FR:Z` and `FB:Z` (with quality
+    /// `FY:Z`) rather than `CR:Z` and `CB:Z` (and `CY:Z`)
+    pub feature_barcodes: bool,
+    /// If set, the spatial (x, y) coordinates of whitelisted barcodes (e.g., of Visium spots), written as a
+    /// `SPATIAL:Z:<x>_<y>` tag (or `SPATIAL:Z:NA` for barcodes without coordinates) on reads assigned a
+    /// barcode. As `SPATIAL` isn't a two character tag name, this can't be combined with
+    /// [`strict_sam_tags`](CorrectionConfig::strict_sam_tags)
+    pub spatial_coords: Option<HashMap<Vec<u8>, (f64, f64)>>,
+    /// If set, the fastq output(s) are flushed every this many records written, so that a job killed part
+    /// way through leaves output that can be decompressed up to the last flush. Each flush ends a
+    /// compressed block (a gzip sync flush, or a BGZF block), losing the compression context built up so
+    /// far, so flushing every few records makes the output noticeably larger; every few thousand records
+    /// costs little
+    pub flush_interval: Option<usize>,
+    /// If set, the expected checksum (as hex) of the input fastq file, which is checksummed as it's read. If
+    /// the checksums differ (e.g., as the file was truncated or corrupted), an error is returned once the
+    /// input has been read, before the output is finished (so a gzipped output is left incomplete)
+    pub expected_checksum: Option<String>,
+    /// Hash function of [`expected_checksum`](CorrectionConfig::expected_checksum)
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Shift added to every phred score of the barcode quality tag (`CY:Z`) and of the output quality
+    /// strings (saturating at 0 and at the highest score the string can encode), e.g., to recalibrate them
+    pub qual_shift: i8,
+    /// If set, the shifted qualities (see [`qual_shift`](CorrectionConfig::qual_shift)) are also used to
+    /// weigh corrections, rather than the original ones
+    pub qual_shift_likelihood: bool,
+    /// If set, only the first this many records are corrected (after subsampling, if any), and the rest of
+    /// the input is ignored, e.g., for quick parameter sweeps
+    pub max_records: Option<usize>,
+    /// Number of threads decompressing the input fastq while its records are corrected (see
+    /// [`open_fastq_checksummed`](crate::io::open_fastq_checksummed)): BGZF input is decompressed on this
+    /// many threads, and other gzip input on one. If 0, the input is decompressed as it's read
+    pub decompression_threads: usize,
+    /// If set, records are copied to the output untouched (with their original description, and no
+    /// correction or tags), e.g., to time the I/O alone, or to diff against a tagged run
+    pub passthrough: bool,
+    /// If set, the number of candidate corrections found in the whitelist trie is tagged (`<tag>:i:<n>`,
+    /// e.g., `XN:i:3`) on each record whose barcode was searched for one, whether or not it was corrected
+    pub neighbor_count_tag: Option<String>,
+    /// If set, the whitelisted neighbors of every sequence within the max edit distance of the whitelist are
+    /// precomputed (see [`precompute_neighborhood`]), so that correcting a barcode is a lookup rather than a
+    /// trie search. This takes a lot of memory, and can't be used with substitution costs
+    pub precompute_neighborhoods: bool,
+    /// If set, a gzipped TSV of the best correction of every read that was searched for one (i.e., that
+    /// wasn't whitelisted or filtered), and its posterior, is written to this path, whether or not the
+    /// correction was accepted, e.g., to apply another threshold (see [`POSTERIOR_OUTPUT_HEADER`] for its columns)
+    pub posterior_output_path: Option<String>,
+    /// How the correction candidates are weighed (see [`CorrectionModel`]); the EM correction
+    /// ([`correct_barcodes_em`]) always uses the product model
+    pub correction_model: CorrectionModel,
+}
+
+/// Parameters of sample index correction, which is done alongside cell barcode correction.
+///
+/// The index read of each record (from a fastq whose records are in the same order as the barcode fastq)
+/// is corrected against its own whitelist and counts, and the corrected index is written to the output
+/// record's description as a `<tag>:Z` tag. Records whose index can't be corrected are either excluded
+/// from the output or tagged with `XU:Z:index`.
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use barcodes::correct::{correct_barcodes_in_fastq,CorrectionConfig,IndexConfig};
+/// use barcodes::io::open_fastq;
+/// # let dir = std::env::temp_dir();
+/// # let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+/// # let write_fastq = |name: &str, records: &[u8]| {
+/// #     let mut fastq = GzEncoder::new(File::create(path(name)).unwrap(), Compression::fast());
+/// #     fastq.write_all(records).unwrap();
+/// #     fastq.finish().unwrap();
+/// # };
+/// # fs::write(path("index_example_whitelist.txt"), "ACGTACGT\n").unwrap();
+/// # fs::write(path("index_example_counts.tsv"), "ACGTACGT\t10\n").unwrap();
+/// # fs::write(path("index_example_index_whitelist.txt"), "AAAAAAAA\nCCCCCCCC\n").unwrap();
+/// # fs::write(path("index_example_index_counts.tsv"), "AAAAAAAA\t10\nCCCCCCCC\t10\n").unwrap();
+/// # write_fastq("index_example_in.fastq.gz", b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACGTACGT\n+\nIIIIIIII\n");
+/// // r1's index is one mismatch from AAAAAAAA, while r2's is far from both whitelisted indices
+/// # write_fastq("index_example_index.fastq.gz", b"@r1\nAAAAAAAT\n+\nIIIIIIII\n@r2\nGGGGTTTT\n+\nIIIIIIII\n");
+/// let index = IndexConfig {
+///     fastq_path: path("index_example_index.fastq.gz"),
+///     whitelist_path: path("index_example_index_whitelist.txt"),
+///     counts_path: path("index_example_index_counts.tsv"),
+///     tag: "SI".to_string(),
+///     max_edit_distance: 1,
+///     exclude_uncorrectable: false,
+/// };
+/// let config = CorrectionConfig {index: Some(index.clone()), ..Default::default()};
+/// let stats = correct_barcodes_in_fastq(&path("index_example_in.fastq.gz"), &path("index_example_whitelist.txt"), &path("index_example_counts.tsv"), &path("index_example_out.fastq.gz"), &config).unwrap();
+/// assert_eq!(stats.index_uncorrectable, 1);
+/// let records: Vec<_> = open_fastq(&path("index_example_out.fastq.gz")).unwrap().records().map(|r| r.unwrap()).collect();
+/// assert_eq!(records[0].desc(), Some("CR:Z:ACGTACGT\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\tSI:Z:AAAAAAAA"));
+/// assert_eq!(records[1].desc(), Some("CR:Z:ACGTACGT\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\tXU:Z:index"));
+///
+/// // records with an uncorrectable index can instead be left out
+/// let config = CorrectionConfig {index: Some(IndexConfig {exclude_uncorrectable: true, ..index}), ..Default::default()};
+/// correct_barcodes_in_fastq(&path("index_example_in.fastq.gz"), &path("index_example_whitelist.txt"), &path("index_example_counts.tsv"), &path("index_example_out_excluded.fastq.gz"), &config).unwrap();
+/// assert_eq!(open_fastq(&path("index_example_out_excluded.fastq.gz")).unwrap().records().count(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// Gzipped fastq of index reads
+    pub fastq_path: String,
+    /// Whitelist of sample indices
+    pub whitelist_path: String,
+    /// TSV of sample index counts
+    pub counts_path: String,
+    /// Name of the tag holding the corrected index
+    pub tag: String,
+    /// Max Hamming distance between an uncorrected index and its correction
+    pub max_edit_distance: usize,
+    /// If set, records whose index can't be corrected are left out of the output
+    pub exclude_uncorrectable: bool,
+}
+
+impl Default for CorrectionConfig<'_> {
+    fn default() -> Self {
+        CorrectionConfig {
+            max_edit_distance: 2,
+            threshold: 0.975,
+            min_position_quality: None,
+            max_iupac_expansions: DEFAULT_MAX_IUPAC_EXPANSIONS,
+            length_mismatch: LengthMismatch::Warn,
+            correction_rounds: 1,
+            corrections_log_path: None,
+            counts_mode: CountsMode::Raw,
+            adapter: None,
+            adapter_mismatches: DEFAULT_ADAPTER_MISMATCHES,
+            trim_5p: 0,
+            trim_3p: 0,
+            linker_offset: 0,
+            linker_length: 0,
+            filter_low_complexity: false,
+            max_homopolymer_run: None,
+            assign_raw_if_uncorrectable: false,
+            min_base_qual: None,
+            error_profile: None,
+            error_model: None,
+            max_n_fraction: 1.0,
+            progress: false,
+            audit_log_path: None,
+            demux_prefix_length: None,
+            demux_output_pattern: DEFAULT_DEMUX_OUTPUT_PATTERN.to_string(),
+            output_format: OutputFormat::Gzip,
+            barcode_output: BarcodeOutput::Tags,
+            index: None,
+            assume_sorted_whitelist: false,
+            umi_offset: 0,
+            umi_length: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            progress_callback: None,
+            read_name_delimiter: None,
+            stats_json_path: None,
+            dirichlet_concentration: DEFAULT_DIRICHLET_CONCENTRATION,
+            uniform_prior: false,
+            uncorrected_prior: DEFAULT_UNCORRECTED_PRIOR,
+            unmatched_prior: None,
+            min_likelihood_ratio: None,
+            multiqc_path: None,
+            tags_tsv_path: None,
+            write_fastq: true,
+            substitution_costs: None,
+            skip_errors: false,
+            ground_truth_tag: None,
+            revcomp: false,
+            strict_sam_tags: false,
+            max_counts_memory: None,
+            correction_cache_size: DEFAULT_CORRECTION_CACHE_SIZE,
+            whitelist_column: None,
+            whitelist_header: false,
+            bloom_false_positive_rate: None,
+            subsample: None,
+            subsample_seed: DEFAULT_SUBSAMPLE_SEED,
+            phred_offset: PHRED33_OFFSET,
+            force_accept: None,
+            feature_barcodes: false,
+            spatial_coords: None,
+            flush_interval: None,
+            expected_checksum: None,
+            checksum_algorithm: ChecksumAlgorithm::Md5,
+            qual_shift: 0,
+            qual_shift_likelihood: false,
+            max_records: None,
+            decompression_threads: 0,
+            passthrough: false,
+            neighbor_count_tag: None,
+            precompute_neighborhoods: false,
+            posterior_output_path: None,
+            correction_model: CorrectionModel::Product,
+        }
+    }
+}
+
+/// Read the whitelist, in the format given by `config` (see [`CorrectionConfig::whitelist_column`]).
+pub fn read_configured_whitelist(whitelist_filename: &str, config: &CorrectionConfig) -> io::Result<Vec<Vec<u8>>> {
+    let whitelist = WhitelistFile::open(whitelist_filename)?;
+    Ok(configured_barcodes(&whitelist, config)?.into_iter().map(<[u8]>::to_vec).collect())
+}
+
+/// The barcodes of a whitelist file, in the format given by `config`, borrowed from the file.
+fn configured_barcodes<'a>(whitelist: &'a WhitelistFile, config: &CorrectionConfig) -> io::Result<Vec<&'a [u8]>> {
+    match &config.whitelist_column {
+        Some(column) => whitelist.column(column, config.whitelist_header),
+        None => whitelist.barcodes(),
+    }
+}
+
+/// Default concentration of the Dirichlet prior over whitelisted barcodes (a pseudocount of 1).
+pub const DEFAULT_DIRICHLET_CONCENTRATION: f64 = 1.0;
+
+/// Default prior weight of leaving a barcode with a single correction candidate uncorrected.
+pub const DEFAULT_UNCORRECTED_PRIOR: f64 = 1e-5;
+
+/// Default number of cached corrections.
+pub const DEFAULT_CORRECTION_CACHE_SIZE: usize = 100000;
+
+/// Default max number of mismatches in a trimmed adapter.
+pub const DEFAULT_ADAPTER_MISMATCHES: usize = 1;
+
+/// Default seed of the random number generator used for subsampling.
+pub const DEFAULT_SUBSAMPLE_SEED: u64 = 42;
+
+/// Default number of records between progress reports.
+pub const DEFAULT_PROGRESS_INTERVAL: usize = 1000000;
+
+/// Default path pattern of demultiplexed outputs.
+pub const DEFAULT_DEMUX_OUTPUT_PATTERN: &str = "{prefix}.corrected.fastq.gz";
+
+/// Placeholder for the barcode prefix in demultiplexed output path patterns.
+const DEMUX_PREFIX_PLACEHOLDER: &str = "{prefix}";
+
+/// Summary statistics of a barcode correction run.
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use std::collections::HashMap;
+/// use barcodes::correct::{correct_barcodes,CorrectionConfig};
+/// # let dir = std::env::temp_dir();
+/// # let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+/// # fs::write(path("stats_n_whitelist.txt"), "ACGTACGT\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("stats_n_counts.tsv"), "ACGTACGT\t10\nTTTTTTTT\t10\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("stats_n_in.fastq.gz")).unwrap(), Compression::fast());
+/// // two clean barcodes, one N-containing barcode that can be corrected, and one that can't
+/// # fastq.write_all(b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nTTTTTTTA\n+\nIIIIIIII\n").unwrap();
+/// # fastq.write_all(b"@r3\nACGTACGN\n+\nIIIIIIII\n@r4\nNNGTACGN\n+\nIIIIIIII\n").unwrap();
+/// # fastq.finish().unwrap();
+/// let stats = correct_barcodes(&path("stats_n_in.fastq.gz"), &path("stats_n_whitelist.txt"), &path("stats_n_counts.tsv"), Vec::new(), &CorrectionConfig::default()).unwrap();
+/// assert_eq!(stats.total, 4);
+/// assert_eq!(stats.matched_whitelist_after_correction, 3);
+/// assert_eq!(stats.reads_with_n, 2);
+/// assert_eq!(stats.reads_with_n_corrected, 1);
+/// assert_eq!(stats.unique_barcodes, 2);
+/// assert_eq!(stats.unique_whitelisted_barcodes, 2);
+/// // r3 shares r1's barcode
+/// assert_eq!(stats.saturation, 0.25);
+/// // r2 and r3 were both one mismatch from their correction, at the last position
+/// assert_eq!(stats.corrections_at_distance, HashMap::from([(1, 2)]));
+/// assert_eq!(stats.mismatches_at_position, vec![0, 0, 0, 0, 0, 0, 0, 2]);
+/// // only the barcodes that weren't whitelisted were searched for in the whitelist trie, and r4 had no
+/// // candidates
+/// assert_eq!((stats.trie_search.queries, stats.trie_search.candidates), (3, 2));
+/// // the run was timed
+/// assert!(stats.elapsed > std::time::Duration::ZERO);
+/// assert!(stats.records_per_second() > 0.0);
+///
+/// // the statistics so far can be followed with a progress callback
+/// use std::cell::RefCell;
+/// let totals = RefCell::new(Vec::new());
+/// let callback = |stats: &barcodes::correct::CorrectionStats| totals.borrow_mut().push(stats.total);
+/// let config = CorrectionConfig {progress_interval: 1, progress_callback: Some(&callback), ..Default::default()};
+/// let stats = correct_barcodes(&path("stats_n_in.fastq.gz"), &path("stats_n_whitelist.txt"), &path("stats_n_counts.tsv"), Vec::new(), &config).unwrap();
+/// let totals = totals.into_inner();
+/// assert_eq!(totals.len(), 5);
+/// assert!(totals.windows(2).all(|w| w[0] <= w[1]));
+/// assert_eq!(totals.last(), Some(&stats.total));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CorrectionStats {
+    /// Number of reads processed
+    pub total: usize,
+    /// Number of reads whose barcode was whitelisted before correction
+    pub matched_whitelist_before_correction: usize,
+    /// Number of reads whose barcode was whitelisted after correction
+    pub matched_whitelist_after_correction: usize,
+    /// Number of reads whose barcode length didn't match the whitelist barcode length (including any that
+    /// were truncated to it; see [`LengthMismatch::Truncate`])
+    pub length_mismatches: usize,
+    /// Number of reads whose barcode was not corrected because a position was below the minimum quality
+    pub filtered_low_quality: usize,
+    /// Number of reads whose barcode was not corrected because it was low complexity
+    pub filtered_low_complexity: usize,
+    /// Number of reads whose barcode was not corrected because it contained a long homopolymer run
+    pub filtered_homopolymer: usize,
+    /// Number of reads whose barcode was not corrected because too many of its bases were N
+    pub filtered_high_n: usize,
+    /// Number of reads left uncorrected whose best correction had a posterior just below the threshold
+    /// (within `NEAR_MISS_BAND` of it)
+    pub near_misses: usize,
+    /// Number of reads whose barcode contained at least one N
+    pub reads_with_n: usize,
+    /// Number of reads whose barcode contained at least one N that were nonetheless assigned a whitelisted
+    /// barcode
+    pub reads_with_n_corrected: usize,
+    /// Number of reads whose sample index couldn't be corrected (if correcting sample indices)
+    pub index_uncorrectable: usize,
+    /// Number of unique barcodes assigned as `CB:Z` (only available once the run is finished)
+    pub unique_barcodes: usize,
+    /// Number of unique whitelisted barcodes assigned as `CB:Z` to at least one read (only available once
+    /// the run is finished)
+    pub unique_whitelisted_barcodes: usize,
+    /// Rough estimate of the number of cells, from [`estimate_cells`] (only available once the run is
+    /// finished)
+    pub estimated_cells: usize,
+    /// Sequencing saturation: the fraction of reads assigned a barcode that had already been assigned to an
+    /// earlier read (only available once the run is finished)
+    pub saturation: f64,
+    /// Number of reads corrected at each Hamming distance (not including exact matches)
+    pub corrections_at_distance: HashMap<usize, usize>,
+    /// Number of accepted corrections changing each (0-based) position of the barcode as matched (after
+    /// trimming); a position with many more than the others points to a sequencing problem in that cycle
+    pub mismatches_at_position: Vec<usize>,
+    /// Number of fastq records that were skipped because they couldn't be parsed, or their sequence and
+    /// quality differed in length (if `skip_errors` is set; these aren't included in `total`)
+    pub malformed_records: usize,
+    /// Number of reads with a ground truth barcode (if `ground_truth_tag` is set)
+    pub ground_truth_reads: usize,
+    /// Number of reads with a ground truth barcode that were assigned a barcode
+    pub ground_truth_assigned: usize,
+    /// Number of reads with a ground truth barcode that were assigned that barcode
+    pub ground_truth_correct: usize,
+    /// Number of reads whose best correction was found in the correction cache (see
+    /// [`CorrectionConfig::correction_cache_size`])
+    pub correction_cache_hits: usize,
+    /// Number of fastq records written (to all outputs, if demultiplexing; only available once the run is
+    /// finished)
+    pub written_records: usize,
+    /// Size in bytes of the main (gzipped) fastq output (only available once the run is finished)
+    pub output_bytes: u64,
+    /// If the records were subsampled, the fraction kept (the other statistics cover only the kept records)
+    pub subsample_fraction: Option<f64>,
+    /// Number of reads whose barcode wasn't whitelisted, but was assigned as-is since it's to be force-accepted
+    /// (see [`CorrectionConfig::force_accept`])
+    pub force_accepted: usize,
+    /// Wall-clock duration of the run, from reading the whitelist to finishing the output (only available once
+    /// the run is finished)
+    pub elapsed: Duration,
+    /// Work done by the Hamming distance searches of the whitelist trie for correction candidates (exact
+    /// matches aren't searched for; only available once the run is finished)
+    pub trie_search: SearchStats,
+    /// The parameters of the run (None if the statistics weren't made by a run)
+    pub parameters: Option<CorrectionParameters>,
+}
+
+impl CorrectionStats {
+    /// The statistics as a JSON object.
+    ///
+    /// # Example
+    /// ```
+    /// use barcodes::correct::CorrectionStats;
+    /// use std::collections::HashMap;
+    /// let stats = CorrectionStats {total: 10, saturation: 0.5, corrections_at_distance: HashMap::from([(2, 1), (1, 3)]), ..Default::default()};
+    /// let json = stats.to_json();
+    /// assert!(json.starts_with("{\n  \"total\": 10,\n"));
+    /// assert!(json.contains("\"saturation\": 0.5,\n"));
+    /// assert!(json.contains("\"corrections_at_distance\": {\"1\": 3, \"2\": 1}\n"));
+    /// ```
+    pub fn to_json(&self) -> String {
+        let fields: Vec<(&str, String)> = vec![
+            ("total", self.total.to_string()),
+            ("matched_whitelist_before_correction", self.matched_whitelist_before_correction.to_string()),
+            ("matched_whitelist_after_correction", self.matched_whitelist_after_correction.to_string()),
+            ("length_mismatches", self.length_mismatches.to_string()),
+            ("filtered_low_quality", self.filtered_low_quality.to_string()),
+            ("filtered_low_complexity", self.filtered_low_complexity.to_string()),
+            ("filtered_homopolymer", self.filtered_homopolymer.to_string()),
+            ("filtered_high_n", self.filtered_high_n.to_string()),
+            ("near_misses", self.near_misses.to_string()),
+            ("reads_with_n", self.reads_with_n.to_string()),
+            ("reads_with_n_corrected", self.reads_with_n_corrected.to_string()),
+            ("index_uncorrectable", self.index_uncorrectable.to_string()),
+            ("unique_barcodes", self.unique_barcodes.to_string()),
+            ("unique_whitelisted_barcodes", self.unique_whitelisted_barcodes.to_string()),
+            ("estimated_cells", self.estimated_cells.to_string()),
+            ("saturation", self.saturation.to_string()),
+            ("malformed_records", self.malformed_records.to_string()),
+            ("ground_truth_reads", self.ground_truth_reads.to_string()),
+            ("ground_truth_assigned", self.ground_truth_assigned.to_string()),
+            ("ground_truth_correct", self.ground_truth_correct.to_string()),
+            ("correction_cache_hits", self.correction_cache_hits.to_string()),
+            ("written_records", self.written_records.to_string()),
+            ("output_bytes", self.output_bytes.to_string()),
+            ("subsample_fraction", self.subsample_fraction.map_or("null".to_string(), |f| f.to_string())),
+            ("force_accepted", self.force_accepted.to_string()),
+            ("elapsed_seconds", self.elapsed.as_secs_f64().to_string()),
+            ("records_per_second", self.records_per_second().to_string()),
+            ("trie_searches", self.trie_search.queries.to_string()),
+            ("trie_nodes_visited", self.trie_search.nodes_visited.to_string()),
+            ("trie_candidates", self.trie_search.candidates.to_string()),
+            ("version", format!("\"{}\"", env!("CARGO_PKG_VERSION"))),
+            ("parameters", self.parameters.as_ref().map_or("null".to_string(), |p| p.to_json())),
+            ("mismatches_at_position", format!("[{}]", self.mismatches_at_position.iter().join(", "))),
+            ("corrections_at_distance", self.corrections_at_distance_json()),
+        ];
+
+        let fields: Vec<String> = fields.iter().map(|(name, value)| format!("  \"{}\": {}", name, value)).collect();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+
+    /// Of the reads with a ground truth barcode that were assigned a barcode, the fraction assigned the true
+    /// one (None if there were none).
+    ///
+    /// # Example
+    /// ```
+    /// use barcodes::correct::CorrectionStats;
+    /// let stats = CorrectionStats {ground_truth_reads: 10, ground_truth_assigned: 8, ground_truth_correct: 6, ..Default::default()};
+    /// assert_eq!(stats.precision(), Some(0.75));
+    /// assert_eq!(stats.recall(), Some(0.6));
+    /// assert!((stats.f1().unwrap() - 2.0 / 3.0).abs() < 1e-12);
+    /// assert_eq!(CorrectionStats::default().precision(), None);
+    /// ```
+    pub fn precision(&self) -> Option<f64> {
+        (self.ground_truth_assigned > 0).then(|| self.ground_truth_correct as f64 / self.ground_truth_assigned as f64)
+    }
+
+    /// Of the reads with a ground truth barcode, the fraction assigned it (None if there were none).
+    pub fn recall(&self) -> Option<f64> {
+        (self.ground_truth_reads > 0).then(|| self.ground_truth_correct as f64 / self.ground_truth_reads as f64)
+    }
+
+    /// The harmonic mean of [`precision`](Self::precision) and [`recall`](Self::recall).
+    pub fn f1(&self) -> Option<f64> {
+        match (self.precision(), self.recall()) {
+            (Some(p), Some(r)) if p + r > 0.0 => Some(2.0 * p * r / (p + r)),
+            (Some(_), Some(_)) => Some(0.0),
+            _ => None
+        }
+    }
+
+    /// Tally a read against its ground truth barcode, if it has one.
+    fn add_ground_truth(&mut self, truth: Option<&[u8]>, assigned: Option<&[u8]>) {
+        if let Some(truth) = truth {
+            self.ground_truth_reads += 1;
+            if let Some(assigned) = assigned {
+                self.ground_truth_assigned += 1;
+                if assigned == truth {
+                    self.ground_truth_correct += 1;
+                }
+            }
+        }
+    }
+
+    /// Number of records processed per second of the run (0 if its duration isn't known).
+    pub fn records_per_second(&self) -> f64 {
+        if self.elapsed.is_zero() { 0.0 } else { self.total as f64 / self.elapsed.as_secs_f64() }
+    }
+
+    /// Tally the positions at which an accepted correction differs from the uncorrected barcode.
+    fn add_mismatches(&mut self, uncorrected: &[u8], corrected: &[u8]) {
+        for (i, (u, c)) in izip!(uncorrected, corrected).enumerate() {
+            if u != c {
+                if self.mismatches_at_position.len() <= i {
+                    self.mismatches_at_position.resize(i + 1, 0);
+                }
+                self.mismatches_at_position[i] += 1;
+            }
+        }
+    }
+
+    /// `corrections_at_distance` as a JSON object, ordered by distance.
+    fn corrections_at_distance_json(&self) -> String {
+        let distances: Vec<String> = self.corrections_at_distance.iter().sorted().map(|(distance, n)| format!("\"{}\": {}", distance, n)).collect();
+        format!("{{{}}}", distances.join(", "))
+    }
+}
+
+/// The parameters of a run that most affect its corrections, recorded in its statistics (see
+/// [`CorrectionStats::parameters`]) so that the run can be reproduced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorrectionParameters {
+    /// Max Hamming distance between an uncorrected barcode and its correction
+    pub max_edit_distance: usize,
+    /// Min posterior probability required to accept a correction
+    pub threshold: f64,
+    /// Pseudocount added to the count of every whitelisted barcode (the Dirichlet concentration)
+    pub pseudocount: f64,
+    /// Prior weight of the hypothesis that a barcode with a single correction candidate is correct as read
+    pub uncorrected_prior: f64,
+    /// Prior probability that a read's true barcode isn't whitelisted, if weighed against every correction
+    pub unmatched_prior: Option<f64>,
+    /// Model weighing the correction candidates
+    pub correction_model: CorrectionModel,
+    /// How the counts are used as the prior
+    pub counts_mode: CountsMode,
+    /// Min ratio of the likelihood times count of a correction to that of the runner-up candidate
+    pub min_likelihood_ratio: Option<f64>,
+    /// How reads whose barcode length doesn't match the whitelist are handled
+    pub length_mismatch: LengthMismatch,
+}
+
+impl CorrectionParameters {
+    /// The parameters of a run with `config`.
+    pub fn from_config(config: &CorrectionConfig) -> CorrectionParameters {
+        CorrectionParameters {
+            max_edit_distance: config.max_edit_distance,
+            threshold: config.threshold,
+            pseudocount: config.dirichlet_concentration,
+            uncorrected_prior: config.uncorrected_prior,
+            unmatched_prior: config.unmatched_prior,
+            correction_model: config.correction_model,
+            counts_mode: config.counts_mode,
+            min_likelihood_ratio: config.min_likelihood_ratio,
+            length_mismatch: config.length_mismatch,
+        }
+    }
+
+    /// The parameters as a (single-line) JSON object, with options named as on the command line.
+    ///
+    /// # Example
+    /// ```
+    /// use barcodes::correct::{CorrectionConfig,CorrectionParameters};
+    /// let parameters = CorrectionParameters::from_config(&CorrectionConfig {max_edit_distance: 1, unmatched_prior: Some(0.1), ..Default::default()});
+    /// assert_eq!(parameters.to_json(), concat!(
+    ///     "{\"max_edit_distance\": 1, \"threshold\": 0.975, \"pseudocount\": 1, \"uncorrected_prior\": 0.00001, \"unmatched_prior\": 0.1, ",
+    ///     "\"correction_model\": \"product\", \"counts_mode\": \"raw\", \"min_likelihood_ratio\": null, \"length_mismatch\": \"warn\"}",
+    /// ));
+    /// ```
+    pub fn to_json(&self) -> String {
+        let optional = |value: Option<f64>| value.map_or("null".to_string(), |v| v.to_string());
+        let name = |value: Option<clap::builder::PossibleValue>| format!("\"{}\"", value.expect("parameter values aren't skipped").get_name());
+        let fields: Vec<(&str, String)> = vec![
+            ("max_edit_distance", self.max_edit_distance.to_string()),
+            ("threshold", self.threshold.to_string()),
+            ("pseudocount", self.pseudocount.to_string()),
+            ("uncorrected_prior", self.uncorrected_prior.to_string()),
+            ("unmatched_prior", optional(self.unmatched_prior)),
+            ("correction_model", name(self.correction_model.to_possible_value())),
+            ("counts_mode", name(self.counts_mode.to_possible_value())),
+            ("min_likelihood_ratio", optional(self.min_likelihood_ratio)),
+            ("length_mismatch", name(self.length_mismatch.to_possible_value())),
+        ];
+        let fields: Vec<String> = fields.iter().map(|(name, value)| format!("\"{}\": {}", name, value)).collect();
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+/// Width of the band of posteriors below the threshold in which a rejected correction is a near miss.
+pub const NEAR_MISS_BAND: f64 = 0.05;
+
+/// Significance level at which a barcode's read count is considered above the background in [`estimate_cells`].
+const CELL_ESTIMATE_SIGNIFICANCE: f64 = 0.001;
+
+/// Smallest read count that is significant at `alpha` under a Poisson background with mean `lambda`.
+fn poisson_significant_count(lambda: f64, alpha: f64) -> usize {
+    // accumulate the CDF, with the terms computed in log space so that they don't underflow for large means
+    let mut log_term = -lambda;
+    let mut cdf = log_term.exp();
+    let mut k: usize = 0;
+    while 1.0 - cdf >= alpha {
+        k += 1;
+        log_term += lambda.ln() - (k as f64).ln();
+        cdf += log_term.exp();
+    }
+    k + 1
+}
+
+/// Estimate the number of cells from the number of reads assigned to each barcode.
+///
+/// Most observed barcodes are background (e.g., ambient DNA, or barcode errors that survived correction),
+/// so reads are modelled as landing on barcodes following a Poisson distribution whose mean is the median
+/// number of reads per barcode. Barcodes with significantly more reads than that (p < 0.001) are counted as
+/// cells. This is only a sanity check of library complexity, not a substitute for proper cell calling.
+///
+/// # Example
+/// ```
+/// use barcodes::correct::estimate_cells;
+/// assert_eq!(estimate_cells(&[1, 1, 2, 1, 1, 500, 600]), 2);
+/// assert_eq!(estimate_cells(&[]), 0);
+/// ```
+pub fn estimate_cells(reads_per_barcode: &[usize]) -> usize {
+    if reads_per_barcode.is_empty() {
+        return 0;
+    }
+
+    let mut sorted = reads_per_barcode.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2] as f64;
+    let threshold = poisson_significant_count(median, CELL_ESTIMATE_SIGNIFICANCE);
+    sorted.iter().filter(|&&n| n >= threshold).count()
+}
+
+/// Reasons for a non-whitelisted barcode not to be put through correction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filter {
+    LengthMismatch,
+    LowQuality,
+    LowComplexity,
+    Homopolymer,
+    HighN,
+}
+
+impl Filter {
+    /// Name of the filter, as written to the audit log and `XF:Z` tags.
+    fn name(&self) -> &'static str {
+        match self {
+            Filter::LengthMismatch => "length_mismatch",
+            Filter::LowQuality => "low_quality",
+            Filter::LowComplexity => "low_complexity",
+            Filter::Homopolymer => "homopolymer",
+            Filter::HighN => "high_n",
+        }
+    }
+}
+
+/// Check whether a non-whitelisted barcode should be put through correction, returning the reason if not.
+/// `barcode_lengths` are the lengths of the whitelisted barcodes.
+fn filter_barcode(seq: &[u8], qual: &[u8], barcode_lengths: &[usize], config: &CorrectionConfig) -> Option<Filter> {
+    if !barcode_lengths.contains(&seq.len()) {
+        Some(Filter::LengthMismatch)
+    } else if (seq.iter().filter(|&&b| b == b'N').count() as f64) > config.max_n_fraction * (seq.len() as f64) {
+        Some(Filter::HighN)
+    } else if config.min_position_quality.is_some_and(|q| has_low_quality_position(qual, q)) {
+        Some(Filter::LowQuality)
+    } else if config.filter_low_complexity && shannon_entropy(seq) < LOW_COMPLEXITY_ENTROPY {
+        Some(Filter::LowComplexity)
+    } else if config.max_homopolymer_run.is_some_and(|n| longest_homopolymer_run(seq) >= n) {
+        Some(Filter::Homopolymer)
+    } else {
+        None
+    }
+}
+
+/// A correction of an uncorrected barcode to a whitelisted barcode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correction {
+    /// The whitelisted barcode the uncorrected barcode was corrected to
+    pub barcode: Vec<u8>,
+    /// Hamming distance between the uncorrected and corrected barcodes
+    pub distance: usize,
+    /// Posterior probability of the correction
+    pub posterior: f64,
+    /// Number of whitelisted barcodes (within the max edit distance) that were candidates for the correction
+    pub candidates: usize,
+}
+
+/// Choose the most likely correction of a non-whitelisted barcode.
 /// 
 /// Given the uncorrected barcode, it's phred score, a vector of similar whitelisted barcodes (e.g., 
 /// whitelisted barcodes w/in Hamming distance two of the uncorrected barcode), and a vector of counts 
 /// for the similar whitelisted barcodes (representing how often each of those similar barcodes are 
-/// observed in the library; these act as a sort of "prior"), attempts to correct the uncorrected barcode
-/// to one of the similar whitelisted barcodes.
-fn correct_barcode<'a> (uncorrected: &[u8], uncorrected_phred: &[u8], similar: &Vec<&'a [u8]>, similar_counts: &Vec<&usize>) -> Option<&'a [u8]> {
+/// observed in the library; these act as a sort of "prior"), finds the similar whitelisted barcode that the
+/// uncorrected barcode most likely should be corrected to. Returns the index of that barcode in `similar`,
+/// along with its posterior probability. A single candidate is weighed against the hypothesis that the
+/// barcode is correct as read (with prior weight `config.uncorrected_prior`), rather than accepted outright.
+/// If `config.unmatched_prior` is set, that hypothesis is weighed against any number of candidates. If
+/// `config.min_likelihood_ratio` is set, None is returned unless the best candidate's likelihood times count
+/// is at least that many times the runner-up's.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{accepted_correction,best_correction,CorrectionConfig};
+/// let trie = Trie::from_words([b"ACGTACGT", b"ACGTACGA"]);
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 1000.0), (&b"ACGTACGA"[..], 1.0)]);
+/// let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+/// assert!(best_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config).unwrap().posterior > 0.975);
+///
+/// // a high quality mismatch is better explained by a barcode that isn't on the whitelist
+/// let config = CorrectionConfig {max_edit_distance: 1, unmatched_prior: Some(0.01), ..Default::default()};
+/// assert!(best_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config).unwrap().posterior < 0.975);
+///
+/// // at 1000 to 1, the best candidate passes the posterior threshold, but not a strict likelihood ratio
+/// let config = CorrectionConfig {max_edit_distance: 1, min_likelihood_ratio: Some(10.0), ..Default::default()};
+/// assert_eq!(accepted_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config).unwrap().barcode, b"ACGTACGT");
+/// let config = CorrectionConfig {max_edit_distance: 1, min_likelihood_ratio: Some(2000.0), ..Default::default()};
+/// assert!(best_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config).is_none());
+/// ```
+///
+/// If several candidates share the top posterior, the one with the higher count is preferred, and then the
+/// lexicographically smaller barcode, so the result doesn't depend on the order of `similar`. Posteriors are
+/// normalized by a floating point sum, though, which depends on the order of `similar`, so candidates that
+/// would tie exactly might not in another order: callers pass candidates in a canonical order (see
+/// [`correction_candidates`]).
+///
+/// If `stop_below` is set, `similar` must be ordered by ascending Hamming distance, and None is returned as
+/// soon as no candidate can reach a posterior of `stop_below`, without weighing the remaining candidates.
+fn correct_barcode(uncorrected: &[u8], uncorrected_phred: &[u8], similar: &[&[u8]], similar_counts: &[f64], config: &CorrectionConfig, stop_below: Option<f64>) -> Option<(usize, f64)> {
 
     if similar.is_empty() {
         return None;
-    } else if similar.len() == 1 {
-        return Some(similar[0]);
-    } else {
-        let probability_of_errors: Vec<f64> = similar.iter().map(|&s| probability_of_incorrect_base_calls(uncorrected, s, uncorrected_phred)).collect();
-        //let likelihood: Vec<f64> = probability_of_errors.iter().zip(similar_counts.iter()).map(|(&i, &&j)| i*(j as f64)).collect();
-        let probability_of_errors_times_count: Vec<f64> = izip!(probability_of_errors, similar_counts).map(|(i, &&j)| i*(j as f64)).collect();
-        let norm_factor: f64 = probability_of_errors_times_count.iter().sum();
-        let posteriors: Vec<f64> = probability_of_errors_times_count.iter().map(|i| i / norm_factor).collect();
+    }
+
+    let likelihood = |s: &[u8]| likelihood_of_errors(uncorrected, s, uncorrected_phred, config.min_base_qual, config.error_profile.as_ref(), config.error_model.as_ref());
+    let probability_of_errors: Vec<f64> = match stop_below {
+        None => similar.iter().map(|&s| likelihood(s)).collect(),
+        Some(threshold) => {
+            // each mismatch multiplies the likelihood by a probability that doesn't depend on the whitelisted
+            // base, so a candidate at distance d is at most as likely as the d likeliest mismatches together
+            let mut mismatches: Vec<f64> = izip!(uncorrected, uncorrected_phred).enumerate()
+                .map(|(i, (u, p))| mismatch_probability(i, *u, *p, config.min_base_qual, config.error_profile.as_ref(), config.error_model.as_ref()))
+                .collect();
+            mismatches.sort_unstable_by(|a, b| b.total_cmp(a));
+            let max_likelihood: Vec<f64> = std::iter::once(1.0).chain(mismatches.iter().scan(1.0, |l, m| { *l *= m; Some(*l) })).collect();
+            let mut max_remaining_count = similar_counts.to_vec();
+            for i in (0..max_remaining_count.len().saturating_sub(1)).rev() {
+                max_remaining_count[i] = max_remaining_count[i].max(max_remaining_count[i + 1]);
+            }
 
-        for (&correction, p) in izip!(similar, posteriors) {
-            if p >= 0.975 {
-                return Some(correction);
+            let (mut likelihoods, mut sum, mut max) = (Vec::with_capacity(similar.len()), 0.0, 0.0_f64);
+            for (i, &s) in similar.iter().enumerate() {
+                // a seen candidate's posterior is at most max / sum, and an unseen one's at most x / (sum + x),
+                // where x bounds its likelihood times count
+                let distance = izip!(uncorrected, s).filter(|(a, b)| a != b).count();
+                let x = max_likelihood[distance.min(max_likelihood.len() - 1)] * max_remaining_count[i];
+                if max < threshold * sum && x < threshold * (sum + x) {
+                    return None;
+                }
+
+                let l = likelihood(s);
+                sum += l * similar_counts[i];
+                max = max.max(l * similar_counts[i]);
+                likelihoods.push(l);
             }
+            likelihoods
         }
+    };
+    let posteriors = candidate_posteriors(&probability_of_errors, similar_counts, config);
+
+    choose_candidate(similar, similar_counts, &posteriors, config)
+}
 
-        // for (i, &correction) in similar.iter().enumerate() {
-        //     if posteriors[i] >= 0.975 {
-        //         return Some(correction);
-        //     }
-        // }
+/// Choose the most likely correction of a non-whitelisted barcode, as [`correct_barcode`] does, but with the
+/// multinomial model (see [`CorrectionModel::Multinomial`]): the uncorrected barcode is drawn from the
+/// candidates, each with probability proportional to `exp(-q)` times its count, where `q` is the sum of the
+/// phred scores of the bases in which it differs from the uncorrected barcode. Unlike in the product model,
+/// the candidates don't compete with the barcode being correct as read (`config.uncorrected_prior` and
+/// `config.unmatched_prior` don't apply), and the base quality options (`config.min_base_qual`, error profile
+/// and model) aren't used; `config.min_likelihood_ratio` still is.
+///
+/// With phred scores on a natural log scale, rather than a log10 one, a few points of quality make much more
+/// of a difference than they do in the product model.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{best_correction,CorrectionConfig,CorrectionModel};
+/// let trie = Trie::from_words([b"ACGTACGT", b"ACGTACCC"]);
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 10.0), (&b"ACGTACCC"[..], 10.0)]);
+/// // ACGTACGC is a phred 20 error from ACGTACCC, or a phred 30 one from ACGTACGT
+/// let product = best_correction(&trie, &counts, b"ACGTACGC", b"IIIIII5?", &CorrectionConfig::default()).unwrap();
+/// let config = CorrectionConfig {correction_model: CorrectionModel::Multinomial, ..Default::default()};
+/// let multinomial = best_correction(&trie, &counts, b"ACGTACGC", b"IIIIII5?", &config).unwrap();
+/// assert_eq!((product.barcode.as_slice(), multinomial.barcode.as_slice()), (&b"ACGTACCC"[..], &b"ACGTACCC"[..]));
+/// assert!(product.posterior < 0.975);
+/// assert!((multinomial.posterior - 1.0 / (1.0 + (-10.0_f64).exp())).abs() < 1e-9);
+/// ```
+fn correct_barcode_multinomial(uncorrected: &[u8], uncorrected_phred: &[u8], similar: &[&[u8]], similar_counts: &[f64], config: &CorrectionConfig) -> Option<(usize, f64)> {
+    if similar.is_empty() {
+        return None;
     }
 
-    None
+    // normalized in log space, as exp(-q) underflows for a few high quality mismatches
+    let log_weights: Vec<f64> = izip!(similar, similar_counts).map(|(s, count)| {
+        let q: f64 = izip!(uncorrected, *s, uncorrected_phred).filter(|(u, c, _p)| u != c).map(|(_u, _c, p)| p.saturating_sub(PHRED33_OFFSET) as f64).sum();
+        count.ln() - q
+    }).collect();
+    let max = log_weights.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return None;
+    }
+    let norm_factor: f64 = log_weights.iter().map(|w| (w - max).exp()).sum();
+    let posteriors: Vec<f64> = log_weights.iter().map(|w| (w - max).exp() / norm_factor).collect();
 
+    choose_candidate(similar, similar_counts, &posteriors, config)
 }
 
+/// The candidate with the top posterior (breaking ties as [`correct_barcode`] describes) and its posterior,
+/// unless it fails `config.min_likelihood_ratio`.
+fn choose_candidate(similar: &[&[u8]], similar_counts: &[f64], posteriors: &[f64], config: &CorrectionConfig) -> Option<(usize, f64)> {
+    let best = (0..similar.len()).max_by(|&a, &b| {
+        posteriors[a].total_cmp(&posteriors[b])
+            .then(similar_counts[a].total_cmp(&similar_counts[b]))
+            .then(similar[b].cmp(similar[a]))
+    }).unwrap();
+
+    // the posteriors share a normalizer, so their ratio is that of the likelihoods times the counts
+    if let Some(min_ratio) = config.min_likelihood_ratio {
+        let runner_up = (0..similar.len()).filter(|&i| i != best).map(|i| posteriors[i]).max_by(f64::total_cmp);
+        if runner_up.is_some_and(|runner_up| posteriors[best] < min_ratio * runner_up) {
+            return None;
+        }
+    }
 
-pub fn correct_barcodes_in_fastq (input_fastq_filename: &str, whitelist_filename: &str, counts_filename: &str, output_fastq_filename: &str, max_edit_distance: usize) {
+    Some((best, posteriors[best]))
+}
 
-    // read the whitelist
-    let mut whitelist_file = File::open(whitelist_filename).unwrap();
-    let mut whitelist: String = String::new();
-    whitelist_file.read_to_string(&mut whitelist).unwrap();
-    let whitelist: HashSet<&[u8]> = whitelist.split("\n").map(|s| s.trim_end().as_bytes()).collect();
+/// The posterior probability of each correction candidate, given the likelihood of the errors each needs
+/// and its count.
+fn candidate_posteriors(likelihoods: &[f64], counts: &[f64], config: &CorrectionConfig) -> Vec<f64> {
+    let probability_of_errors_times_count: Vec<f64> = izip!(likelihoods, counts).map(|(i, j)| i*j).collect();
+    let mut norm_factor: f64 = probability_of_errors_times_count.iter().sum();
 
-    let mut whitelist_trie = Trie::new();
-    for &whitelisted_barcode in whitelist.iter() {
-        whitelist_trie.add_word(whitelisted_barcode);
-    }
-
-    // read the counts
-    let mut counts: HashMap<&[u8], usize> = HashMap::new();
-    let mut counts_file = File::open(counts_filename).unwrap();
-    let mut counts_string = String::new();
-    counts_file.read_to_string(&mut counts_string).unwrap();
-    counts_string = counts_string.trim().to_string();
-    for i in counts_string.split("\n") {
-        let barcode_and_count: Vec<&str> = i.split("\t").collect();
-        let barcode = barcode_and_count[0].as_bytes();
-        let count = barcode_and_count[1].parse::<usize>().unwrap();
-        let e = counts.entry(barcode).or_insert(0);
-        *e += count;
+    // the hypothesis that the barcode is correct as read (which needs no errors) competes with the candidates
+    match config.unmatched_prior {
+        // with the candidates' counts as their prior given that the true barcode is whitelisted, scaled to
+        // the unmatched prior
+        Some(p) => norm_factor += p * counts.iter().sum::<f64>() / (1.0 - p),
+        None if likelihoods.len() == 1 => norm_factor += config.uncorrected_prior,
+        None => {}
     }
-    // add pseudocount
-    for &whitelisted_barcode in whitelist.iter() {
-        if counts.contains_key(whitelisted_barcode) {
-            *(counts.get_mut(&whitelisted_barcode).unwrap()) += 1;
-        } else {
-            counts.insert(whitelisted_barcode, 1);
-        }
+
+    probability_of_errors_times_count.iter().map(|i| i / norm_factor).collect()
+}
+
+/// Correct a single barcode against a whitelist.
+///
+/// Looks up the whitelisted barcodes within `max_edit_distance` of `seq` in the whitelist `trie`, and
+/// attempts to correct `seq` to one of them, using `qual` (the phred scores of `seq`) and `counts` (how
+/// often each whitelisted barcode is observed in the library). The correction is accepted only if its
+/// posterior probability is at least `threshold` (CellRanger uses 0.975). Barcodes that are already
+/// whitelisted are returned as-is, at the cost of a single walk down the trie (their neighbors are never
+/// enumerated). Ties between equally likely candidates are broken by preferring the higher count, and then
+/// the lexicographically smaller barcode.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::correct_one;
+/// let mut trie = Trie::new();
+/// trie.add_word(b"ACGTACGT");
+/// trie.add_word(b"ACGTACGA");
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 1000.0), (&b"ACGTACGA"[..], 1.0)]);
+/// let correction = correct_one(&trie, &counts, b"ACGTACGC", b"IIIIIIII", 1, 0.975).unwrap();
+/// assert_eq!(correction.barcode, b"ACGTACGT".to_vec());
+/// assert_eq!(correction.distance, 1);
+/// assert!(correction.posterior > 0.99);
+///
+/// // an exact match is kept, even though its neighbor would win on counts
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 1.0), (&b"ACGTACGA"[..], 1e9)]);
+/// let correction = correct_one(&trie, &counts, b"ACGTACGT", b"IIIIIIII", 1, 0.975).unwrap();
+/// assert_eq!(correction.barcode, b"ACGTACGT".to_vec());
+/// assert_eq!(correction.distance, 0);
+///
+/// // with equal counts, both candidates are equally likely, so the lexicographically smaller one wins
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 10.0), (&b"ACGTACGA"[..], 10.0)]);
+/// let correction = correct_one(&trie, &counts, b"ACGTACGC", b"IIIIIIII", 1, 0.5).unwrap();
+/// assert_eq!(correction.barcode, b"ACGTACGA".to_vec());
+/// assert_eq!(correction.posterior, 0.5);
+///
+/// // a single candidate that has never been observed in the library, two high quality mismatches away, isn't
+/// // enough to correct the barcode
+/// let single_counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGT"[..], 1.0)]);
+/// let single_trie = Trie::from_words([b"ACGTACGT"]);
+/// assert_eq!(correct_one(&single_trie, &single_counts, b"ACGTACCC", b"IIIIIIII", 2, 0.975), None);
+/// // ...unless there's little reason to believe the barcode is correct as read
+/// assert!(correct_one(&single_trie, &single_counts, b"ACGTACCC", b"IIIIII##", 2, 0.975).is_some());
+///
+/// // a max edit distance of 0 only accepts exact matches
+/// assert_eq!(correct_one(&trie, &counts, b"ACGTACGC", b"IIIIIIII", 0, 0.975), None);
+/// ```
+pub fn correct_one(trie: &Trie, counts: &HashMap<&[u8], f64>, seq: &[u8], qual: &[u8], max_edit_distance: usize, threshold: f64) -> Option<Correction> {
+    let config = CorrectionConfig {max_edit_distance, threshold, ..Default::default()};
+    accepted_correction(trie, counts, seq, qual, &config)
+}
+
+/// The whitelisted barcodes a barcode could be corrected to, with their Hamming distance from it, ordered
+/// by distance and then barcode (rather than in the order the trie happens to be walked in, which varies from
+/// run to run). They're looked up in `neighborhoods`, if given, rather than searched for in the trie.
+fn correction_candidates(trie: &Trie, seq: &[u8], config: &CorrectionConfig, neighborhoods: Option<&Neighborhoods>) -> Vec<(String, usize)> {
+    match (&config.substitution_costs, neighborhoods) {
+        (Some(costs), _) => trie.get_words_within_substitution_cost(seq, config.max_edit_distance as f64, costs).into_iter()
+            .map(|(word, _cost)| {
+                let distance = izip!(word.bytes(), seq).filter(|(a, b)| a != *b).count();
+                (word, distance)
+            })
+            .sorted_by(|(a_word, a_distance), (b_word, b_distance)| a_distance.cmp(b_distance).then(a_word.cmp(b_word)))
+            .collect(),
+        // the table only holds sequences of bases, so others (e.g., with an N) are still searched for
+        (None, Some(neighborhoods)) if seq.iter().all(|b| b"ACGT".contains(b)) => match neighborhoods.get(seq) {
+            // neighbors are in lexicographic order, and the sort is stable
+            Some(neighbors) => neighbors.iter()
+                .map(|word| (String::from_utf8_lossy(word).into_owned(), izip!(word, seq).filter(|(a, b)| a != b).count()))
+                .sorted_by_key(|(_word, distance)| *distance)
+                .collect(),
+            None => Vec::new(),
+        },
+        (None, _) => trie.get_words_by_hamming_distance(seq, config.max_edit_distance)
     }
+}
 
-    let fastq_in = BufReader::new(GzDecoder::new(File::open(input_fastq_filename).unwrap()));
-    let fastq_reader = fastq::Reader::from_bufread(fastq_in);
+/// Find the most likely correction of a single barcode against a whitelist, regardless of its posterior.
+///
+/// This is [`correct_one`] without the posterior threshold, which is useful to see how close a rejected
+/// correction came to being accepted. The max edit distance and likelihood options are taken from `config`.
+///
+/// Exactly tied candidates go to the lexicographically smaller barcode, however the whitelist trie was built.
+/// (Until ties were broken this way, the result could depend on the trie's walk order, so corrections of
+/// exactly tied barcodes may differ from those of earlier versions.)
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::{Trie,SubstitutionCosts};
+/// use barcodes::correct::{best_correction,CorrectionConfig};
+/// let whitelist: [&[u8]; 4] = [b"GCGTACGT", b"ACGTACGA", b"TCGTACGT", b"CCGTACGT"];
+/// let counts: HashMap<&[u8], f64> = whitelist.iter().map(|&barcode| (barcode, 7.0)).collect();
+/// let costs = SubstitutionCosts::transition_transversion(1.0, 1.0).unwrap();
+/// let config = CorrectionConfig {max_edit_distance: 1, substitution_costs: Some(costs), ..Default::default()};
+/// let corrections: Vec<_> = (0..20).map(|_| best_correction(&Trie::from_words(whitelist), &counts, b"ACGTACGT", b"IIIIIIII", &config).unwrap()).collect();
+/// assert_eq!(corrections[0].barcode, b"ACGTACGA");
+/// assert!(corrections.iter().all(|c| c == &corrections[0]));
+/// ```
+pub fn best_correction<C: BarcodeCounts + ?Sized>(trie: &Trie, counts: &C, seq: &[u8], qual: &[u8], config: &CorrectionConfig) -> Option<Correction> {
+    search_correction(trie, counts, seq, qual, config, None, None).0
+}
 
-    let fastq_out = BufWriter::new(GzEncoder::new(File::create(output_fastq_filename).unwrap(), Compression::fast()));
-    let mut fastq_writer = fastq::Writer::from_bufwriter(fastq_out);
+/// Find the correction of a single barcode against a whitelist, if its posterior is at least
+/// `config.threshold`.
+///
+/// This gives the same result as filtering [`best_correction`] on the threshold, but the candidates are
+/// weighed nearest (and then most counted) first, and the search stops as soon as none of them can reach the
+/// threshold.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{accepted_correction,best_correction,CorrectionConfig};
+/// let whitelist: [&[u8]; 5] = [b"ACGTACGT", b"ACGTACGA", b"ACGTACCC", b"TCGTACGG", b"GGGGGGGG"];
+/// let trie = Trie::from_words(whitelist);
+/// let counts: HashMap<&[u8], f64> = HashMap::from([
+///     (whitelist[0], 1000.0), (whitelist[1], 10.0), (whitelist[2], 500.0), (whitelist[3], 5.0), (whitelist[4], 1.0),
+/// ]);
+/// let config = CorrectionConfig {max_edit_distance: 2, ..Default::default()};
+/// for (seq, qual) in [(b"ACGTACGC", b"IIIIIII#"), (b"ACGTACGC", b"IIIIIIII"), (b"ACGTACCG", b"IIIIII#I"), (b"TCGTACGC", b"IIIIIIII"), (b"GGGGGGCC", b"IIIIII##")] {
+///     assert_eq!(
+///         accepted_correction(&trie, &counts, seq, qual, &config),
+///         best_correction(&trie, &counts, seq, qual, &config).filter(|c| c.posterior >= config.threshold),
+///     );
+/// }
+/// assert_eq!(accepted_correction(&trie, &counts, b"ACGTACGC", b"IIIIIII#", &config).unwrap().barcode, b"ACGTACGT");
+/// ```
+pub fn accepted_correction<C: BarcodeCounts + ?Sized>(trie: &Trie, counts: &C, seq: &[u8], qual: &[u8], config: &CorrectionConfig) -> Option<Correction> {
+    search_correction(trie, counts, seq, qual, config, Some(config.threshold), None).0.filter(|c| c.posterior >= config.threshold)
+}
 
-    let mut matched_whitelist_before_correction: usize = 0;
-    let mut matched_whitelist_after_correction: usize = 0;
-    let mut total: usize = 0;
+/// Find the most likely correction of a single barcode, stopping early if `stop_below` is set and no
+/// candidate can reach that posterior (see [`correct_barcode`]), along with the number of candidates.
+/// Candidates are looked up in `neighborhoods`, if given (see [`correction_candidates`]).
+fn search_correction<C: BarcodeCounts + ?Sized>(trie: &Trie, counts: &C, seq: &[u8], qual: &[u8], config: &CorrectionConfig, stop_below: Option<f64>, neighborhoods: Option<&Neighborhoods>) -> (Option<Correction>, usize) {
+    if trie.contains_word(seq) {
+        return (Some(Correction {barcode: seq.to_vec(), distance: 0, posterior: 1.0, candidates: 1}), 1);
+    }
 
-    for result in fastq_reader.records() {
-        total += 1;
+    if config.max_edit_distance == 0 {
+        return (None, 0);
+    }
+
+    // nearest first, and then most counted first, so that the likeliest candidates are weighed first
+    let candidates: Vec<((String, usize), f64)> = correction_candidates(trie, seq, config, neighborhoods).into_iter()
+        .map(|(s, d)| { let count = counts.count(s.as_bytes()); ((s, d), count) })
+        .sorted_by(|((_, a_distance), a_count), ((_, b_distance), b_count)| a_distance.cmp(b_distance).then(b_count.total_cmp(a_count)))
+        .collect();
+    let similar: Vec<&[u8]> = candidates.iter().map(|((s, _d), _count)| s.as_bytes()).collect();
+    let similar_counts: Vec<f64> = candidates.iter().map(|(_, count)| *count).collect();
+
+    let correction = match config.correction_model {
+        CorrectionModel::Product => correct_barcode(seq, qual, &similar, &similar_counts, config, stop_below),
+        CorrectionModel::Multinomial => correct_barcode_multinomial(seq, qual, &similar, &similar_counts, config),
+    };
+    let correction = correction.map(|(i, posterior)| Correction {
+        barcode: similar[i].to_vec(),
+        distance: candidates[i].0.1,
+        posterior,
+        candidates: candidates.len(),
+    });
+    (correction, candidates.len())
+}
 
-        let record = result.unwrap();
+/// The result of correcting the barcode of a single fastq record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorrectedRecord<'a> {
+    /// The original record
+    pub record: &'a fastq::Record,
+    /// The corrected barcode, if the correction was accepted (or the barcode was already whitelisted)
+    pub barcode: Option<Vec<u8>>,
+    /// The posterior probability of the best correction, whether accepted or not (None if there were no
+    /// candidate corrections)
+    pub posterior: Option<f64>,
+}
 
-        if whitelist.contains(&record.seq()) {
-            matched_whitelist_before_correction += 1;
-            matched_whitelist_after_correction += 1;
-            let new_description = format!("CR:Z:{}\tCB:Z:{}\tCY:Z:{}", String::from_utf8(record.seq().to_vec()).unwrap(), String::from_utf8(record.seq().to_vec()).unwrap(), String::from_utf8(record.qual().to_vec()).unwrap());
+/// Correct the barcodes of a stream of fastq records.
+///
+/// Records are corrected lazily as the returned iterator is consumed, so large inputs never need to be
+/// held in memory. Each record's barcode is trimmed and filtered according to `config` before correction.
+/// `counts` are the number of reads of each whitelisted barcode.
+///
+/// The corrected records only borrow from `records`, so they can outlive the trie, counts and config.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use bio::io::fastq::Record;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{correct_batch,CorrectionConfig};
+/// let records = vec![
+///     Record::with_attrs("r1", None, b"ACGTACGC", b"IIIIIIII"),
+///     Record::with_attrs("r2", None, b"GGGGGGGG", b"IIIIIIII"),
+/// ];
+/// let corrected: Vec<_> = {
+///     let trie = Trie::from_words([b"ACGTACGT", b"TTTTTTTT"]);
+///     let counts: HashMap<&[u8], usize> = HashMap::from([(&b"ACGTACGT"[..], 10), (&b"TTTTTTTT"[..], 10)]);
+///     let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+///     correct_batch(records.iter(), &trie, &counts, &config).collect()
+/// };
+/// assert_eq!(corrected[0].record.id(), "r1");
+/// assert_eq!(corrected[0].barcode, Some(b"ACGTACGT".to_vec()));
+/// assert_eq!(corrected[1].barcode, None);
+/// assert_eq!(corrected[1].posterior, None);
+/// ```
+pub fn correct_batch<'r: 'c, 'c>(records: impl Iterator<Item = &'r fastq::Record> + 'c, trie: &'c Trie, counts: &'c HashMap<&'c [u8], usize>, config: &'c CorrectionConfig<'c>) -> impl Iterator<Item = CorrectedRecord<'r>> + 'c {
+    records.map(move |record| {
+        let (seq, qual) = matching_barcode(record, config);
+        let barcode_length = (!trie.is_empty()).then(|| trie.word_length());
+        let (seq, qual, _truncated) = truncate_barcode(seq, qual, barcode_length.as_slice(), config);
+        let (seq, qual): (&[u8], &[u8]) = (&seq, &qual);
 
-            fastq_writer.write(record.id(), Some(&new_description), record.seq(), record.qual()).unwrap();
+        let best = if !trie.is_empty() && (trie.contains_word(seq) || filter_barcode(seq, qual, &[trie.word_length()], config).is_none()) {
+            best_correction(trie, counts, seq, qual, config)
         } else {
-            let corrections = whitelist_trie.get_words_within_hamming_distance(record.seq(), max_edit_distance);
-            let corrections: Vec<&[u8]> = corrections.iter().map(|(s, _c)| s.as_bytes()).collect();
-            let corrections_counts: Vec<&usize> = corrections.iter().map(|&s| counts.get(s).unwrap_or(&0)).collect();
-            let corrected = correct_barcode(record.seq(), record.qual(), &corrections, &corrections_counts);
+            None
+        };
 
-            let new_description = match corrected {
-                Some(x) => {
-                    matched_whitelist_after_correction += 1;
-                    format!("CR:Z:{}\tCB:Z:{}\tCY:Z:{}", String::from_utf8(record.seq().to_vec()).unwrap(), String::from_utf8(x.to_vec()).unwrap(), String::from_utf8(record.qual().to_vec()).unwrap())
-                },
-                None => {
-                    format!("CR:Z:{}\tCY:Z:{}", String::from_utf8(record.seq().to_vec()).unwrap(), String::from_utf8(record.qual().to_vec()).unwrap())
-                },
-            };
-            
-            fastq_writer.write(record.id(), Some(&new_description), record.seq(), record.qual()).unwrap();
+        CorrectedRecord {
+            record,
+            posterior: best.as_ref().map(|c| c.posterior),
+            barcode: best.filter(|c| c.posterior >= config.threshold).map(|c| c.barcode),
         }
-        
-        if total % 1000000 == 0 {
-            info!("Processed {total} records so far; {matched_whitelist_before_correction} matched whitelist before correction, {matched_whitelist_after_correction} matched whitelist after correction");
+    })
+}
+
+/// A read's barcode, as seen by [`correct_barcodes_em`].
+enum EmBarcode {
+    Whitelisted(Vec<u8>),
+    /// The whitelisted barcodes it could be corrected to, with the likelihood of the errors each needs
+    Correctable(Vec<(Vec<u8>, f64)>),
+    Uncorrectable,
+}
+
+/// Correct the barcodes of a set of fastq records by expectation-maximization, refining the counts used as
+/// the prior along with the corrections, rather than taking the counts as given.
+///
+/// The counts start as the number of records whose barcode is whitelisted. Each iteration gives the
+/// correction candidates of every other record their posterior probability under the current counts (plus
+/// the pseudocount `config.dirichlet_concentration`), then sets each whitelisted barcode's count to the
+/// expected number of records from it, until no count changes by more than `tol`, or `max_iters` iterations
+/// have been run. This helps most when sequencing is shallow, so that the counts of a separate pass are
+/// unreliable.
+///
+/// Returns each record's barcode (the whitelisted barcode, or the best correction if its posterior under
+/// the final counts is at least `config.threshold`, else None), and the final counts. Records are trimmed
+/// and filtered according to `config`, like in [`correct_batch`].
+///
+/// # Example
+/// ```
+/// use std::collections::HashSet;
+/// use bio::io::fastq::Record;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{correct_barcodes_em,CorrectionConfig};
+/// let whitelist: HashSet<&[u8]> = HashSet::from([&b"AAAAAAAA"[..], &b"AAAAAAAC"[..]]);
+/// let trie: Trie = whitelist.iter().collect();
+/// // five reads of AAAAAAAA, and two one mismatch from both whitelisted barcodes
+/// let mut records: Vec<Record> = (0..5).map(|i| Record::with_attrs(&format!("r{}", i), None, b"AAAAAAAA", b"IIIIIIII")).collect();
+/// records.push(Record::with_attrs("r5", None, b"AAAAAAAG", b"IIIIIIII"));
+/// records.push(Record::with_attrs("r6", None, b"AAAAAAAG", b"IIIIIIII"));
+/// records.push(Record::with_attrs("r7", None, b"GGGGGGGG", b"IIIIIIII"));
+/// let config = CorrectionConfig {max_edit_distance: 1, threshold: 0.8, ..Default::default()};
+/// let (barcodes, counts) = correct_barcodes_em(&records, &trie, &whitelist, 100, 1e-9, &config);
+/// assert_eq!(barcodes[0], Some(b"AAAAAAAA".to_vec()));
+/// assert_eq!(barcodes[5], Some(b"AAAAAAAA".to_vec()));
+/// assert_eq!(barcodes[7], None);
+/// // the ambiguous reads are split between the barcodes in proportion to their counts (with pseudocounts):
+/// // r = (5 + 2r + 1) / (7 + 2), so r = 6/7
+/// assert!((counts[&b"AAAAAAAA".to_vec()] - (5.0 + 2.0 * 6.0 / 7.0)).abs() < 1e-6);
+/// assert!((counts[&b"AAAAAAAC".to_vec()] - 2.0 / 7.0).abs() < 1e-6);
+/// ```
+#[allow(clippy::type_complexity)] // a pair of the assignments and the counts is plainer than a new type
+pub fn correct_barcodes_em(records: &[fastq::Record], trie: &Trie, whitelist: &HashSet<&[u8]>, max_iters: usize, tol: f64, config: &CorrectionConfig) -> (Vec<Option<Vec<u8>>>, HashMap<Vec<u8>, f64>) {
+    let barcodes: Vec<EmBarcode> = records.iter().map(|record| {
+        let (seq, qual) = matching_barcode(record, config);
+        let barcode_length = (!trie.is_empty()).then(|| trie.word_length());
+        let (seq, qual, _truncated) = truncate_barcode(seq, qual, barcode_length.as_slice(), config);
+        let (seq, qual): (&[u8], &[u8]) = (&seq, &qual);
+        if whitelist.contains(&seq) {
+            return EmBarcode::Whitelisted(seq.to_vec());
+        }
+        if trie.is_empty() || config.max_edit_distance == 0 || filter_barcode(seq, qual, &[trie.word_length()], config).is_some() {
+            return EmBarcode::Uncorrectable;
+        }
+        let candidates: Vec<(Vec<u8>, f64)> = correction_candidates(trie, seq, config, None).into_iter()
+            .map(|(word, _distance)| {
+                let likelihood = likelihood_of_errors(seq, word.as_bytes(), qual, config.min_base_qual, config.error_profile.as_ref(), config.error_model.as_ref());
+                (word.into_bytes(), likelihood)
+            })
+            .collect();
+        if candidates.is_empty() { EmBarcode::Uncorrectable } else { EmBarcode::Correctable(candidates) }
+    }).collect();
+
+    let posteriors = |candidates: &[(Vec<u8>, f64)], counts: &HashMap<Vec<u8>, f64>| -> (Vec<f64>, Vec<f64>) {
+        let likelihoods: Vec<f64> = candidates.iter().map(|(_barcode, likelihood)| *likelihood).collect();
+        let priors: Vec<f64> = candidates.iter().map(|(barcode, _likelihood)| counts.get(barcode).copied().unwrap_or(0.0) + config.dirichlet_concentration).collect();
+        (candidate_posteriors(&likelihoods, &priors, config), priors)
+    };
+
+    let mut whitelisted_counts: HashMap<Vec<u8>, f64> = whitelist.iter().map(|&barcode| (barcode.to_vec(), 0.0)).collect();
+    for barcode in barcodes.iter() {
+        if let EmBarcode::Whitelisted(barcode) = barcode {
+            *whitelisted_counts.entry(barcode.clone()).or_insert(0.0) += 1.0;
+        }
+    }
+
+    let mut counts = whitelisted_counts.clone();
+    for iteration in 1..=max_iters {
+        // E-step: the probability that each correctable read is from each of its candidates; M-step: the
+        // expected number of reads from each whitelisted barcode
+        let mut updated_counts = whitelisted_counts.clone();
+        for barcode in barcodes.iter() {
+            if let EmBarcode::Correctable(candidates) = barcode {
+                let (posteriors, _priors) = posteriors(candidates, &counts);
+                for ((candidate, _likelihood), posterior) in izip!(candidates, posteriors) {
+                    *updated_counts.entry(candidate.clone()).or_insert(0.0) += posterior;
+                }
+            }
+        }
+
+        let change = updated_counts.iter().map(|(barcode, &count)| (count - counts.get(barcode).copied().unwrap_or(0.0)).abs()).fold(0.0, f64::max);
+        counts = updated_counts;
+        if change <= tol {
+            info!("EM converged after {} iterations", iteration);
+            break;
+        }
+    }
+
+    let assignments = barcodes.iter().map(|barcode| match barcode {
+        EmBarcode::Whitelisted(barcode) => Some(barcode.clone()),
+        EmBarcode::Correctable(candidates) => {
+            let (posteriors, priors) = posteriors(candidates, &counts);
+            // ties are broken like in correct_barcode
+            let best = (0..candidates.len()).max_by(|&a, &b| {
+                posteriors[a].total_cmp(&posteriors[b])
+                    .then(priors[a].total_cmp(&priors[b]))
+                    .then(candidates[b].0.cmp(&candidates[a].0))
+            }).unwrap();
+            Some(candidates[best].0.clone()).filter(|_| posteriors[best] >= config.threshold)
+        },
+        EmBarcode::Uncorrectable => None,
+    }).collect();
+
+    (assignments, counts)
+}
+
+/// Check that a max edit distance is usable with barcodes of the given length.
+///
+/// A distance at least as large as the barcode length would make every whitelisted barcode a candidate,
+/// so is an error; a distance above a quarter of the barcode length makes the trie search slow, so is
+/// warned about.
+///
+/// # Example
+/// ```
+/// use barcodes::correct::validate_max_edit_distance;
+/// assert!(validate_max_edit_distance(0, 16).is_ok());
+/// assert!(validate_max_edit_distance(2, 16).is_ok());
+/// assert!(validate_max_edit_distance(16, 16).is_err());
+/// ```
+pub fn validate_max_edit_distance(max_edit_distance: usize, barcode_length: usize) -> io::Result<()> {
+    if max_edit_distance >= barcode_length {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Max edit distance ({}) must be less than the barcode length ({})", max_edit_distance, barcode_length)));
+    }
+    if max_edit_distance > barcode_length / 4 {
+        warn!("Max edit distance ({}) is large relative to the barcode length ({}); correction will be slow and error-prone", max_edit_distance, barcode_length);
+    }
+    Ok(())
+}
+
+/// The barcode tags of a read: its uncorrected barcode (`CR:Z`), the barcode it was assigned (`CB:Z`), if
+/// any, and the uncorrected barcode's quality (`CY:Z`). Feature barcodes (see
+/// [`CorrectionConfig::feature_barcodes`]) are tagged `FR:Z`, `FB:Z` and `FY:Z` instead.
+fn barcode_tags(raw_barcode: &[u8], assigned_barcode: Option<&[u8]>, qual: &[u8], config: &CorrectionConfig) -> String {
+    let (raw_tag, assigned_tag, qual_tag) = if config.feature_barcodes {("FR", "FB", "FY")} else {("CR", "CB", "CY")};
+    match assigned_barcode {
+        Some(assigned) => format!("{}:Z:{}\t{}:Z:{}\t{}:Z:{}", raw_tag, String::from_utf8_lossy(raw_barcode), assigned_tag, String::from_utf8_lossy(assigned), qual_tag, String::from_utf8_lossy(qual)),
+        None => format!("{}:Z:{}\t{}:Z:{}", raw_tag, String::from_utf8_lossy(raw_barcode), qual_tag, String::from_utf8_lossy(qual)),
+    }
+}
+
+/// Parse a fastq description made up of tab-separated SAM tags (`TG:T:value`, with a two character tag
+/// name starting with a letter and a type of `A`, `i`, `f`, `Z`, `H` or `B`) into (tag, type, value)
+/// triples. Returns an error if any field isn't such a tag, or if the description contains a space (which
+/// tools splitting the description on whitespace would break the tag at).
+///
+/// # Example
+/// ```
+/// use barcodes::correct::parse_sam_tags;
+/// let tags = parse_sam_tags("CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tXN:i:2").unwrap();
+/// assert_eq!(tags, vec![("CR".to_string(), 'Z', "ACGTACGA".to_string()), ("CB".to_string(), 'Z', "ACGTACGT".to_string()), ("XN".to_string(), 'i', "2".to_string())]);
+/// assert!(parse_sam_tags("CR:Z:ACGTACGA CB:Z:ACGTACGT").is_err());
+/// assert!(parse_sam_tags("1:N:0:ACGTACGT").is_err());
+/// assert!(parse_sam_tags("CR:Z:ACGT\t").is_err());
+/// ```
+pub fn parse_sam_tags(description: &str) -> io::Result<Vec<(String, char, String)>> {
+    let invalid = |field: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Description field {:?} is not a SAM tag (TG:T:value, without spaces)", field));
+
+    description.split('\t').map(|field| {
+        let mut parts = field.splitn(3, ':');
+        let (tag, tag_type, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(tag), Some(tag_type), Some(value)) => (tag, tag_type, value),
+            _ => return Err(invalid(field))
+        };
+        let tag_is_valid = tag.len() == 2 && tag.as_bytes()[0].is_ascii_alphabetic() && tag.as_bytes()[1].is_ascii_alphanumeric();
+        let tag_type = match tag_type.as_bytes() {
+            &[t] if b"AifZHB".contains(&t) => t as char,
+            _ => return Err(invalid(field))
+        };
+        if !tag_is_valid || value.contains(char::is_whitespace) {
+            return Err(invalid(field));
         }
+        Ok((tag.to_string(), tag_type, value.to_string()))
+    }).collect()
+}
+
+/// Header of the tags TSV, which holds the values of each read's barcode tags (with `.` for a missing CB).
+pub const TAGS_TSV_HEADER: &str = "read_id\tCR\tCB\tCY";
+
+fn write_tags_record<W: Write>(tsv: &mut W, read_id: &str, raw_barcode: &[u8], assigned_barcode: Option<&[u8]>, qual: &[u8]) -> io::Result<()> {
+    let assigned_barcode = assigned_barcode.map_or(".".into(), String::from_utf8_lossy);
+    writeln!(tsv, "{}\t{}\t{}\t{}", read_id, String::from_utf8_lossy(raw_barcode), assigned_barcode, String::from_utf8_lossy(qual))
+}
+
+/// The value of a `<tag>:Z:` tag in a record's description, if present.
+fn description_tag<'a>(record: &'a fastq::Record, tag: &str) -> Option<&'a [u8]> {
+    let prefix = format!("{}:Z:", tag);
+    record.desc()?.split_whitespace().find_map(|field| field.strip_prefix(prefix.as_str())).map(|value| value.as_bytes())
+}
+
+/// The sequence and qualities of an output record: the record's own, or with [`BarcodeOutput::Starsolo`], the
+/// barcode (`corrected`, or else the matched barcode `seq`) followed by the UMI, with their qualities. The
+/// record's qualities are shifted by [`CorrectionConfig::qual_shift`] (`qual` is expected to be already).
+fn output_seq_and_qual<'r>(record: &'r fastq::Record, seq: &[u8], qual: &[u8], corrected: Option<&[u8]>, config: &CorrectionConfig) -> (Cow<'r, [u8]>, Cow<'r, [u8]>) {
+    match (config.barcode_output, config.umi_length) {
+        (BarcodeOutput::Starsolo, Some(length)) => {
+            let umi = cmp::min(config.umi_offset, record.seq().len())..cmp::min(config.umi_offset + length, record.seq().len());
+            let barcode = corrected.unwrap_or(seq);
+            (Cow::Owned([barcode, &record.seq()[umi.clone()]].concat()), Cow::Owned([qual, &shift_quality(&record.qual()[umi], config.qual_shift, config.phred_offset)].concat()))
+        },
+        _ => (Cow::Borrowed(record.seq()), shift_quality(record.qual(), config.qual_shift, config.phred_offset))
+    }
+}
+
+/// UMI tags (`UR:Z` and `UY:Z`) of a barcode read, if a UMI is configured and the read is long enough to
+/// contain it.
+fn umi_tags(record: &fastq::Record, config: &CorrectionConfig) -> String {
+    match config.umi_length {
+        Some(length) if record.seq().len() >= config.umi_offset + length => {
+            let umi = config.umi_offset..config.umi_offset + length;
+            format!("\tUR:Z:{}\tUY:Z:{}", String::from_utf8_lossy(&record.seq()[umi.clone()]), String::from_utf8_lossy(&to_phred33(&record.qual()[umi], config.phred_offset)))
+        },
+        _ => String::new()
+    }
+}
+
+/// Spatial coordinate tag (`SPATIAL:Z:<x>_<y>`, or `SPATIAL:Z:NA` if the barcode has no coordinates) of a
+/// read assigned a barcode, if spatial coordinates are configured.
+fn spatial_tag(barcode: &[u8], config: &CorrectionConfig) -> String {
+    match config.spatial_coords.as_ref().map(|coords| coords.get(barcode)) {
+        Some(Some((x, y))) => format!("\tSPATIAL:Z:{}_{}", x, y),
+        Some(None) => "\tSPATIAL:Z:NA".to_string(),
+        None => String::new()
+    }
+}
+
+/// Read a barcode counts file (a TSV of barcode and count), summing the counts of repeated barcodes.
+pub fn read_counts(counts_filename: &str) -> io::Result<HashMap<Vec<u8>, f64>> {
+    let contents = MappedFile::open(counts_filename)?;
+    Ok(parse_counts(&contents, counts_filename)?.into_iter().map(|(barcode, count)| (barcode.to_vec(), count)).collect())
+}
+
+/// Parse the contents of a barcode counts file (see [`read_counts`]), borrowing the barcodes from them.
+fn parse_counts<'a>(contents: &'a [u8], counts_filename: &str) -> io::Result<HashMap<&'a [u8], f64>> {
+    let counts_string = std::str::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut counts: HashMap<&[u8], f64> = HashMap::new();
+    for line in counts_string.trim().split("\n") {
+        let count = line.split_once('\t').and_then(|(barcode, count)| Some((barcode, count.trim_end().parse::<usize>().ok()?)));
+        let (barcode, count) = count.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in counts file {}: {}", counts_filename, line)))?;
+        *counts.entry(barcode.as_bytes()).or_insert(0.0) += count as f64;
     }
 
-    fastq_writer.flush().unwrap();
+    Ok(counts)
+}
 
-}
\ No newline at end of file
+/// Header of the audit log. Columns that don't apply to a read (e.g., the corrected barcode of a read
+/// that wasn't corrected, or the filter reason of a read that wasn't filtered) are written as `.`.
+pub const AUDIT_LOG_HEADER: &str = "read_id\traw_barcode\tcorrected_barcode\tn_candidates\ttop_candidate_posterior\tdistance_to_correction\tfilter_reason";
+
+/// Write the audit log line of a single read, given its best correction (whether or not it was accepted)
+/// and the filter that kept it from being corrected, if any.
+fn write_audit_record<W: Write>(log: &mut W, read_id: &str, raw_barcode: &[u8], best: Option<&Correction>, accepted: bool, filter: Option<Filter>) -> io::Result<()> {
+    let corrected_barcode = match best {
+        Some(c) if accepted => String::from_utf8_lossy(&c.barcode).to_string(),
+        _ => ".".to_string()
+    };
+    let (n_candidates, posterior, distance) = match best {
+        Some(c) => (c.candidates.to_string(), c.posterior.to_string(), c.distance.to_string()),
+        None if filter.is_some() => (".".to_string(), ".".to_string(), ".".to_string()),
+        None => ("0".to_string(), ".".to_string(), ".".to_string())
+    };
+    writeln!(log, "{}\t{}\t{}\t{}\t{}\t{}\t{}", read_id, String::from_utf8_lossy(raw_barcode), corrected_barcode, n_candidates, posterior, distance, filter.map_or(".", |f| f.name()))
+}
+
+/// Header of the posterior output. The best corrected barcode and its posterior are `NA` if there was no
+/// candidate correction.
+pub const POSTERIOR_OUTPUT_HEADER: &str = "read_id\traw_barcode\tbest_corrected_barcode\tposterior_probability";
+
+/// Write the posterior output line of a single read, given its best correction (whether or not it was
+/// accepted).
+fn write_posterior_record<W: Write>(output: &mut W, read_id: &str, raw_barcode: &[u8], best: Option<&Correction>) -> io::Result<()> {
+    let (barcode, posterior) = match best {
+        Some(c) => (String::from_utf8_lossy(&c.barcode).to_string(), c.posterior.to_string()),
+        None => ("NA".to_string(), "NA".to_string())
+    };
+    writeln!(output, "{}\t{}\t{}\t{}", read_id, String::from_utf8_lossy(raw_barcode), barcode, posterior)
+}
+
+/// Log the progress of a run so far. The counts are also given as fields, for log aggregators.
+fn log_progress(stats: &CorrectionStats) {
+    info!(total = stats.total, matched_before = stats.matched_whitelist_before_correction, matched_after = stats.matched_whitelist_after_correction; "Processed {} records so far; {} matched whitelist before correction, {} matched whitelist after correction", stats.total, stats.matched_whitelist_before_correction, stats.matched_whitelist_after_correction);
+}
+
+/// A writer shared with its owner, so that the owner can still get at it (e.g., to finish a gzip stream)
+/// once it's been handed to a fastq writer.
+struct SharedWriter<W>(Rc<RefCell<W>>);
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// The compressor of a fastq output, in either [`OutputFormat`].
+enum OutputEncoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Bgzf(BgzfWriter<W>),
+}
+
+impl<W: Write> OutputEncoder<W> {
+    fn new(inner: W, format: OutputFormat) -> OutputEncoder<W> {
+        match format {
+            OutputFormat::Gzip => OutputEncoder::Gzip(GzEncoder::new(inner, Compression::fast())),
+            OutputFormat::Bgzf => OutputEncoder::Bgzf(BgzfWriter::new(inner)),
+        }
+    }
+
+    /// Write the end of the compressed stream.
+    fn try_finish(&mut self) -> io::Result<()> {
+        match self {
+            OutputEncoder::Gzip(encoder) => encoder.try_finish(),
+            OutputEncoder::Bgzf(writer) => writer.try_finish(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        match self {
+            OutputEncoder::Gzip(encoder) => encoder.get_mut(),
+            OutputEncoder::Bgzf(writer) => writer.get_mut(),
+        }
+    }
+}
+
+impl<W: Write> Write for OutputEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputEncoder::Gzip(encoder) => encoder.write(buf),
+            OutputEncoder::Bgzf(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputEncoder::Gzip(encoder) => encoder.flush(),
+            OutputEncoder::Bgzf(writer) => writer.flush(),
+        }
+    }
+}
+
+type SharedEncoder<'a> = Rc<RefCell<OutputEncoder<Box<dyn Write + 'a>>>>;
+
+/// The gzipped fastq output(s) of a correction run: a main output, plus (when demultiplexing) one output
+/// per whitelist barcode prefix.
+struct FastqOutputs<'a> {
+    main: fastq::Writer<Box<dyn Write + 'a>>,
+    demux_prefix_length: usize,
+    demux: HashMap<Vec<u8>, fastq::Writer<Box<dyn Write + 'a>>>,
+    /// The encoders of all outputs, which must be finished explicitly for errors writing the end of the
+    /// gzip stream not to be lost
+    encoders: Vec<SharedEncoder<'a>>,
+    /// Number of records written to all outputs
+    written_records: usize,
+    /// Number of records between flushes of all outputs (see [`CorrectionConfig::flush_interval`])
+    flush_interval: Option<usize>,
+    /// Number of (compressed) bytes written to the main output
+    main_bytes: Rc<Cell<u64>>,
+}
+
+impl<'a> FastqOutputs<'a> {
+    fn new<W: Write + 'a>(output: W, whitelist: &HashSet<&[u8]>, config: &CorrectionConfig) -> io::Result<FastqOutputs<'a>> {
+        let mut encoders: Vec<SharedEncoder<'a>> = Vec::new();
+        let mut gzipped = |w: Box<dyn Write + 'a>| -> fastq::Writer<Box<dyn Write + 'a>> {
+            let encoder = Rc::new(RefCell::new(OutputEncoder::new(w, config.output_format)));
+            encoders.push(Rc::clone(&encoder));
+            fastq::Writer::from_bufwriter(BufWriter::new(Box::new(SharedWriter(encoder))))
+        };
+
+        let mut demux = HashMap::new();
+        let demux_prefix_length = config.demux_prefix_length.unwrap_or(0);
+        if config.demux_prefix_length.is_some() {
+            if !config.demux_output_pattern.contains(DEMUX_PREFIX_PLACEHOLDER) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Demultiplexed output pattern {} doesn't contain {}", config.demux_output_pattern, DEMUX_PREFIX_PLACEHOLDER)));
+            }
+            for &barcode in whitelist.iter() {
+                let prefix = &barcode[..cmp::min(demux_prefix_length, barcode.len())];
+                if !demux.contains_key(prefix) {
+                    let path = config.demux_output_pattern.replace(DEMUX_PREFIX_PLACEHOLDER, &String::from_utf8_lossy(prefix));
+                    demux.insert(prefix.to_vec(), gzipped(Box::new(File::create(path)?)));
+                }
+            }
+            info!("Demultiplexing reads into {} outputs by barcode prefix", demux.len());
+        }
+
+        let main_bytes = Rc::new(Cell::new(0));
+        let main = gzipped(Box::new(CountingWriter::new(output, Rc::clone(&main_bytes))));
+        Ok(FastqOutputs {main, demux_prefix_length, demux, encoders, written_records: 0, flush_interval: config.flush_interval, main_bytes})
+    }
+
+    /// The output for a read with the given corrected barcode (or None, if it wasn't corrected).
+    fn writer(&mut self, barcode: Option<&[u8]>) -> &mut fastq::Writer<Box<dyn Write + 'a>> {
+        match barcode.map(|b| &b[..cmp::min(self.demux_prefix_length, b.len())]) {
+            Some(prefix) if self.demux.contains_key(prefix) => self.demux.get_mut(prefix).unwrap(),
+            _ => &mut self.main
+        }
+    }
+
+    /// Write a record to the output for the given corrected barcode (see [`FastqOutputs::writer`]).
+    fn write(&mut self, barcode: Option<&[u8]>, name: &str, description: Option<&str>, seq: &[u8], qual: &[u8]) -> io::Result<()> {
+        self.writer(barcode).write(name, description, seq, qual)?;
+        self.written_records += 1;
+        if self.flush_interval.is_some_and(|interval| self.written_records.is_multiple_of(interval)) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush all outputs, through to the end of a compressed block.
+    fn flush(&mut self) -> io::Result<()> {
+        for writer in self.demux.values_mut() {
+            writer.flush()?;
+        }
+        self.main.flush()
+    }
+
+    /// Flush all outputs and finish their gzip streams.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        for encoder in self.encoders.iter() {
+            let mut encoder = encoder.borrow_mut();
+            encoder.try_finish()?;
+            encoder.get_mut().flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The counts used as the prior for correction (in the given mode, plus a pseudocount for each whitelisted
+/// barcode), along with the amount a single read adds to a count.
+fn prior_counts<'a>(raw_counts: &HashMap<&'a [u8], f64>, whitelist: &HashSet<&'a [u8]>, counts_mode: CountsMode, pseudocount: f64) -> (HashMap<&'a [u8], f64>, f64) {
+    let mut counts = raw_counts.clone();
+    // in frequency mode, counts (including those added by later correction rounds) are relative to the total
+    let count_unit = match counts_mode {
+        CountsMode::Raw => 1.0,
+        CountsMode::Frequency => {
+            let total_count: f64 = counts.values().sum();
+            if total_count > 0.0 { 1.0 / total_count } else { 1.0 }
+        }
+    };
+    for count in counts.values_mut() {
+        *count *= count_unit;
+    }
+    // add pseudocount
+    for &whitelisted_barcode in whitelist.iter() {
+        if counts.contains_key(whitelisted_barcode) {
+            *(counts.get_mut(&whitelisted_barcode).unwrap()) += pseudocount;
+        } else {
+            counts.insert(whitelisted_barcode, pseudocount);
+        }
+    }
+    (counts, count_unit)
+}
+
+/// Check a record from a fastq reader, returning an error (along with the read id, if the record could be
+/// parsed) if it is malformed.
+fn check_record(result: fastq::Result<fastq::Record>) -> Result<fastq::Record, (Option<String>, io::Error)> {
+    let record = result.map_err(|e| (None, io::Error::new(io::ErrorKind::InvalidData, format!("Malformed fastq record: {}", e))))?;
+    match record.check() {
+        Ok(()) => Ok(record),
+        Err(e) => {
+            let error = io::Error::new(io::ErrorKind::InvalidData, format!("Malformed fastq record {}: {}", record.id(), e));
+            Err((Some(record.id().to_string()), error))
+        }
+    }
+}
+
+/// The best correction with the counts of the earliest correction round that accepts one (along with that
+/// round), and the number of candidate corrections (which doesn't depend on the round).
+fn best_correction_in_rounds(trie: &Trie, round_counts: &[HashMap<&[u8], f64>], seq: &[u8], qual: &[u8], config: &CorrectionConfig, neighborhoods: Option<&Neighborhoods>) -> (Option<(usize, Correction)>, usize) {
+    let mut best = None;
+    let mut candidates = 0;
+    for (round, counts) in round_counts.iter().enumerate() {
+        let (correction, n) = search_correction(trie, counts, seq, qual, config, None, neighborhoods);
+        best = correction.map(|c| (round, c));
+        candidates = n;
+        if best.as_ref().is_some_and(|(_round, c)| c.posterior >= config.threshold) {
+            break;
+        }
+    }
+    (best, candidates)
+}
+
+/// The counts of each correction round (see [`CorrectionConfig::correction_rounds`]).
+///
+/// Each correction round but the last is a pass over the input that adds the reads corrected in that round
+/// (i.e., those that couldn't be corrected with the counts of any previous round) to the counts.
+fn correction_round_counts<'a>(input_fastq_filename: &str, raw_counts: &HashMap<&'a [u8], f64>, whitelist: &HashSet<&'a [u8]>, whitelist_tries: &PartitionedTrie, barcode_lengths: &[usize], config: &CorrectionConfig) -> io::Result<Vec<HashMap<&'a [u8], f64>>> {
+    let (counts, count_unit) = prior_counts(raw_counts, whitelist, config.counts_mode, config.dirichlet_concentration);
+
+    let mut round_counts: Vec<HashMap<&[u8], f64>> = vec![counts];
+    for round in 1..config.correction_rounds {
+        let mut updated_counts = round_counts[round - 1].clone();
+        let mut corrected_in_round: usize = 0;
+
+        for result in open_fastq(input_fastq_filename)?.records() {
+            let record = match check_record(result) {
+                Ok(record) => record,
+                Err(_) if config.skip_errors => continue,
+                Err((_read_id, e)) => return Err(e),
+            };
+            let (seq, qual) = matching_barcode(&record, config);
+            let (seq, qual, _truncated) = truncate_barcode(seq, qual, barcode_lengths, config);
+            let (seq, qual): (&[u8], &[u8]) = (&seq, &qual);
+
+            if whitelist.contains(&seq) || filter_barcode(seq, qual, barcode_lengths, config).is_some() {
+                continue;
+            }
+
+            if let Some((r, correction)) = whitelist_tries.partition(seq.len()).and_then(|trie| best_correction_in_rounds(trie, &round_counts, seq, qual, config, None).0) {
+                if r == round - 1 && correction.posterior >= config.threshold {
+                    corrected_in_round += 1;
+                    *updated_counts.get_mut(correction.barcode.as_slice()).unwrap() += count_unit;
+                }
+            }
+        }
+
+        info!("Correction round {round} corrected {corrected_in_round} additional reads");
+        round_counts.push(updated_counts);
+    }
+    Ok(round_counts)
+}
+
+/// Correct the barcodes in a gzipped fastq file, writing a gzipped fastq file (or, if
+/// `output_fastq_filename` is `-`, a gzipped fastq stream to stdout) in which each record's
+/// description holds the uncorrected barcode (`CR:Z`), its quality (`CY:Z`) and, if the barcode could be
+/// corrected, the corrected barcode (`CB:Z`; or, if `config.assign_raw_if_uncorrectable` is set, the
+/// uncorrected barcode if it couldn't be corrected). If `config` trims the barcode read, the tags hold the trimmed
+/// barcode, while the record's sequence and quality are written untrimmed.
+///
+/// If `config.demux_prefix_length` is set, reads with a corrected (or whitelisted) barcode are instead
+/// written to the output for their barcode's prefix (see [`CorrectionConfig::demux_output_pattern`]), so
+/// only the remaining reads are written to `output_fastq_filename`.
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use barcodes::correct::{correct_barcodes_in_fastq,CorrectionConfig};
+/// use barcodes::io::open_fastq;
+/// # let dir = std::env::temp_dir();
+/// # let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+/// # fs::write(path("example_whitelist.txt"), "ACGTACGT\nTTTTTTTT\nGGGGCCCC\nGGGGCCCA\n").unwrap();
+/// # fs::write(path("example_counts.tsv"), "ACGTACGT\t10\nTTTTTTTT\t10\nGGGGCCCC\t95\nGGGGCCCA\t4\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("example_in.fastq.gz")).unwrap(), Compression::fast());
+/// # fastq.write_all(b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACGTACGC\n+\nIIIIIIII\n@r3\nGGGGGGGG\n+\nIIIIIIII\n").unwrap();
+/// // r4 is as likely to be GGGGCCCC as GGGGCCCA but for the counts, so its posterior is 96 / 101
+/// # fastq.write_all(b"@r4\nGGGGCCCG\n+\nIIIIIIII\n").unwrap();
+/// # fastq.finish().unwrap();
+/// let config = CorrectionConfig {
+///     max_edit_distance: 1,
+///     corrections_log_path: Some(path("example_corrections.tsv")),
+///     audit_log_path: Some(path("example_audit.tsv.gz")),
+///     ..Default::default()
+/// };
+/// let stats = correct_barcodes_in_fastq(&path("example_in.fastq.gz"), &path("example_whitelist.txt"), &path("example_counts.tsv"), &path("example_out.fastq.gz"), &config).unwrap();
+/// assert_eq!(stats.total, 4);
+/// assert_eq!(stats.matched_whitelist_after_correction, 2);
+/// assert_eq!(stats.near_misses, 1);
+///
+/// // only r2 needed (and received) a correction; as its only candidate, ACGTACGT is weighed against leaving
+/// // it uncorrected
+/// let log = fs::read_to_string(path("example_corrections.tsv")).unwrap();
+/// assert!(log.starts_with("r2\tACGTACGC\tACGTACGT\t0.99"));
+/// assert_eq!(log.lines().count(), 1);
+///
+/// // the audit log records the decision for every read, including the rejected near miss r4
+/// # use std::io::Read;
+/// let mut audit = String::new();
+/// flate2::read::MultiGzDecoder::new(File::open(path("example_audit.tsv.gz")).unwrap()).read_to_string(&mut audit).unwrap();
+/// let audit: Vec<&str> = audit.lines().collect();
+/// assert_eq!(audit.len(), 5);
+/// assert_eq!(audit[1], "r1\tACGTACGT\tACGTACGT\t1\t1\t0\t.");
+/// assert!(audit[2].starts_with("r2\tACGTACGC\tACGTACGT\t1\t0.99") && audit[2].ends_with("\t1\t."));
+/// assert_eq!(audit[3], "r3\tGGGGGGGG\t.\t0\t.\t.\t.");
+/// assert!(audit[4].starts_with("r4\tGGGGCCCG\t.\t2\t0.95"));
+///
+/// // r3 couldn't be corrected, so has no CB tag...
+/// let r3 = open_fastq(&path("example_out.fastq.gz")).unwrap().records().nth(2).unwrap().unwrap();
+/// assert_eq!(r3.desc(), Some("CR:Z:GGGGGGGG\tCY:Z:IIIIIIII"));
+///
+/// // ...unless uncorrectable reads are assigned their raw barcode
+/// let config = CorrectionConfig {
+///     max_edit_distance: 1,
+///     assign_raw_if_uncorrectable: true,
+///     ..Default::default()
+/// };
+/// correct_barcodes_in_fastq(&path("example_in.fastq.gz"), &path("example_whitelist.txt"), &path("example_counts.tsv"), &path("example_out_raw.fastq.gz"), &config).unwrap();
+/// let r3 = open_fastq(&path("example_out_raw.fastq.gz")).unwrap().records().nth(2).unwrap().unwrap();
+/// assert_eq!(r3.desc(), Some("CR:Z:GGGGGGGG\tCB:Z:GGGGGGGG\tCY:Z:IIIIIIII"));
+///
+/// // drawing a progress bar doesn't change the output
+/// let config = CorrectionConfig {
+///     max_edit_distance: 1,
+///     progress: true,
+///     ..Default::default()
+/// };
+/// correct_barcodes_in_fastq(&path("example_in.fastq.gz"), &path("example_whitelist.txt"), &path("example_counts.tsv"), &path("example_out_progress.fastq.gz"), &config).unwrap();
+/// let records = |name: &str| open_fastq(&path(name)).unwrap().records().map(|r| r.unwrap()).collect::<Vec<_>>();
+/// assert_eq!(records("example_out_progress.fastq.gz"), records("example_out.fastq.gz"));
+///
+/// // demultiplexing by the first four bases of the barcode sends r1 and r2 to the ACGT output, leaving
+/// // the uncorrected reads in the main output
+/// let config = CorrectionConfig {
+///     max_edit_distance: 1,
+///     demux_prefix_length: Some(4),
+///     demux_output_pattern: path("example_demux_{prefix}.fastq.gz"),
+///     ..Default::default()
+/// };
+/// correct_barcodes_in_fastq(&path("example_in.fastq.gz"), &path("example_whitelist.txt"), &path("example_counts.tsv"), &path("example_unassigned.fastq.gz"), &config).unwrap();
+/// let ids = |name: &str| records(name).iter().map(|r| r.id().to_string()).collect::<Vec<_>>();
+/// assert_eq!(ids("example_demux_ACGT.fastq.gz"), vec!["r1", "r2"]);
+/// assert!(ids("example_demux_GGGG.fastq.gz").is_empty());
+/// assert!(ids("example_demux_TTTT.fastq.gz").is_empty());
+/// assert_eq!(ids("example_unassigned.fastq.gz"), vec!["r3", "r4"]);
+/// ```
+pub fn correct_barcodes_in_fastq (input_fastq_filename: &str, whitelist_filename: &str, counts_filename: &str, output_fastq_filename: &str, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    correct_barcodes(input_fastq_filename, whitelist_filename, counts_filename, open_output(output_fastq_filename)?, config)
+}
+
+/// Correct the barcodes in a gzipped fastq file like [`correct_barcodes_in_fastq`], writing the gzipped
+/// output fastq to `output` (e.g., stdout, for piping into an aligner).
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::{Read,Write};
+/// # use flate2::write::GzEncoder;
+/// # use flate2::read::MultiGzDecoder;
+/// # use flate2::Compression;
+/// use barcodes::correct::{correct_barcodes,CorrectionConfig};
+/// # let dir = std::env::temp_dir();
+/// # let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+/// # fs::write(path("writer_whitelist.txt"), "ACGTACGT\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("writer_counts.tsv"), "ACGTACGT\t10\nTTTTTTTT\t10\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("writer_in.fastq.gz")).unwrap(), Compression::fast());
+/// # fastq.write_all(b"@r1\nACGTACGA\n+\nIIIIIIII\n").unwrap();
+/// # fastq.finish().unwrap();
+/// let mut output: Vec<u8> = Vec::new();
+/// correct_barcodes(&path("writer_in.fastq.gz"), &path("writer_whitelist.txt"), &path("writer_counts.tsv"), &mut output, &CorrectionConfig::default()).unwrap();
+/// let mut fastq = String::new();
+/// MultiGzDecoder::new(output.as_slice()).read_to_string(&mut fastq).unwrap();
+/// assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\nACGTACGA\n+\nIIIIIIII\n");
+/// ```
+pub fn correct_barcodes<W: Write>(input_fastq_filename: &str, whitelist_filename: &str, counts_filename: &str, output: W, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    correct_barcodes_from(FastqInput::File(input_fastq_filename), whitelist_filename, CountsSource::File(counts_filename), output, config)
+}
+
+/// [`correct_barcodes`], with the barcode counts given in memory as (barcode, count) pairs (e.g., from a
+/// `HashMap`) rather than read from a counts file. The counts of repeated barcodes are summed, and are then
+/// used as the prior just as those of a counts file are (e.g., with the same pseudocount).
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use std::collections::HashMap;
+/// use barcodes::correct::{correct_barcodes,correct_barcodes_with_counts,CorrectionConfig};
+/// # let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// # fs::write(path("memory_counts_whitelist.txt"), "ACGTACGT\nACGTACGA\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("memory_counts.tsv"), "ACGTACGT\t90\nACGTACGA\t10\nTTTTTTTT\t100\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("memory_counts_in.fastq.gz")).unwrap(), Compression::fast());
+/// # fastq.write_all(b"@r1\nACGTACGC\n+\nIIIIIIII\n@r2\nTTTTTTTA\n+\nIIIIIIII\n@r3\nACGTACGA\n+\nIIIIIIII\n").unwrap();
+/// # fastq.finish().unwrap();
+/// let config = CorrectionConfig {threshold: 0.85, ..Default::default()};
+/// let mut from_file: Vec<u8> = Vec::new();
+/// let file_stats = correct_barcodes(&path("memory_counts_in.fastq.gz"), &path("memory_counts_whitelist.txt"), &path("memory_counts.tsv"), &mut from_file, &config).unwrap();
+///
+/// let counts: HashMap<Vec<u8>, usize> = HashMap::from([(b"ACGTACGT".to_vec(), 90), (b"ACGTACGA".to_vec(), 10), (b"TTTTTTTT".to_vec(), 100)]);
+/// let mut from_map: Vec<u8> = Vec::new();
+/// let map_stats = correct_barcodes_with_counts(&path("memory_counts_in.fastq.gz"), &path("memory_counts_whitelist.txt"), counts, &mut from_map, &config).unwrap();
+/// assert_eq!(map_stats.matched_whitelist_after_correction, 3);
+/// assert_eq!(map_stats.matched_whitelist_after_correction, file_stats.matched_whitelist_after_correction);
+/// assert_eq!(from_map, from_file);
+///
+/// // or as pairs, with repeated barcodes summed
+/// let pairs: [(&[u8], usize); 4] = [(b"ACGTACGT", 50), (b"ACGTACGA", 10), (b"TTTTTTTT", 100), (b"ACGTACGT", 40)];
+/// let mut from_pairs: Vec<u8> = Vec::new();
+/// correct_barcodes_with_counts(&path("memory_counts_in.fastq.gz"), &path("memory_counts_whitelist.txt"), pairs, &mut from_pairs, &config).unwrap();
+/// assert_eq!(from_pairs, from_file);
+/// ```
+pub fn correct_barcodes_with_counts<W: Write, B: AsRef<[u8]>>(input_fastq_filename: &str, whitelist_filename: &str, counts: impl IntoIterator<Item = (B, usize)>, output: W, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    let mut summed: HashMap<Vec<u8>, f64> = HashMap::new();
+    for (barcode, count) in counts {
+        *summed.entry(barcode.as_ref().to_vec()).or_insert(0.0) += count as f64;
+    }
+    correct_barcodes_from(FastqInput::File(input_fastq_filename), whitelist_filename, CountsSource::Memory(summed), output, config)
+}
+
+/// Correct the barcodes of a gzipped fastq stream (e.g., received from another process), like
+/// [`correct_barcodes`] does those of a file; the stream is named `input_name` (e.g., for the MultiQC sample
+/// name).
+///
+/// A stream can only be read once, and its size isn't known up front, so multiple correction rounds and
+/// checksum verification are errors, and no progress bar is drawn.
+///
+/// # Example
+/// ```
+/// # use std::fs;
+/// # use std::io::{Cursor,Read,Write};
+/// # use flate2::write::GzEncoder;
+/// # use flate2::read::MultiGzDecoder;
+/// # use flate2::Compression;
+/// use barcodes::correct::{correct_barcodes_in_stream,CorrectionConfig};
+/// # let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// # fs::write(path("stream_whitelist.txt"), "ACGTACGT\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("stream_counts.tsv"), "ACGTACGT\t10\nTTTTTTTT\t10\n").unwrap();
+/// let mut fastq = GzEncoder::new(Vec::new(), Compression::fast());
+/// fastq.write_all(b"@r1\nACGTACGA\n+\nIIIIIIII\n").unwrap();
+/// let input = Cursor::new(fastq.finish().unwrap());
+/// let mut output: Vec<u8> = Vec::new();
+/// let stats = correct_barcodes_in_stream(input, "stream", &path("stream_whitelist.txt"), &path("stream_counts.tsv"), &mut output, &CorrectionConfig::default()).unwrap();
+/// assert_eq!(stats.matched_whitelist_after_correction, 1);
+/// let mut corrected = String::new();
+/// MultiGzDecoder::new(output.as_slice()).read_to_string(&mut corrected).unwrap();
+/// assert!(corrected.starts_with("@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\t"));
+///
+/// // the stream can't be read again for another correction round
+/// let config = CorrectionConfig {correction_rounds: 2, ..Default::default()};
+/// let input = Cursor::new(Vec::new());
+/// assert!(correct_barcodes_in_stream(input, "stream", &path("stream_whitelist.txt"), &path("stream_counts.tsv"), Vec::new(), &config).is_err());
+/// ```
+pub fn correct_barcodes_in_stream<R: Read + Send + 'static, W: Write>(input: R, input_name: &str, whitelist_filename: &str, counts_filename: &str, output: W, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    correct_barcodes_from(FastqInput::Stream(input_name, Box::new(input)), whitelist_filename, CountsSource::File(counts_filename), output, config)
+}
+
+fn correct_barcodes_from<W: Write>(input: FastqInput, whitelist_filename: &str, counts: CountsSource, output: W, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    // start reading the counts (unless they're to be kept on disk, or not used), which are only needed once
+    // a barcode that isn't whitelisted is to be corrected; counts in memory are never kept on disk
+    if config.uniform_prior && config.correction_rounds > 1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "A uniform prior can't be used with multiple correction rounds"));
+    }
+    let counts_on_disk = match (config.max_counts_memory, &counts) {
+        (Some(max_memory), CountsSource::File(counts_filename)) if !config.uniform_prior => std::fs::metadata(counts_filename)?.len() > max_memory as u64,
+        _ => false
+    };
+    // a counts file is mapped, and parsed into counts that borrow their barcodes from the mapping
+    let counts_file = match &counts {
+        CountsSource::File(counts_filename) if !config.uniform_prior && !counts_on_disk => MappedFile::open(counts_filename)?,
+        _ => MappedFile::Read(Vec::new())
+    };
+    thread::scope(|scope| {
+        let (raw_counts, disk_counts_filename) = match &counts {
+            _ if config.uniform_prior => (PendingCounts::empty(), None),
+            CountsSource::File(counts_filename) if counts_on_disk => (PendingCounts::empty(), Some(*counts_filename)),
+            CountsSource::File(counts_filename) => (PendingCounts::spawn(scope, &counts_file, counts_filename), None),
+            CountsSource::Memory(counts) => (PendingCounts::ready(counts.iter().map(|(barcode, &count)| (barcode.as_slice(), count)).collect()), None),
+        };
+        correct_barcodes_with_pending_counts(input, whitelist_filename, raw_counts, disk_counts_filename, output, config)
+    })
+}
+
+/// Correct barcodes (see [`correct_barcodes_from`]), with counts that may still be being read.
+fn correct_barcodes_with_pending_counts<'scope, 'a: 'scope, W: Write>(input: FastqInput, whitelist_filename: &str, raw_counts: PendingCounts<'scope, 'a>, disk_counts_filename: Option<&str>, output: W, config: &CorrectionConfig) -> io::Result<CorrectionStats> {
+    let start = Instant::now();
+
+    let (input_fastq_name, input_fastq_filename, input_stream) = match input {
+        FastqInput::File(filename) => (filename, Some(filename), None),
+        FastqInput::Stream(name, stream) => (name, None, Some(stream)),
+    };
+    // what needs to read the input again, or know its size, needs a file
+    let input_file = |purpose: &str| input_fastq_filename.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs a fastq file, not a stream", purpose)));
+    if config.expected_checksum.is_some() {
+        input_file("Verifying the checksum")?;
+    }
+    if config.correction_rounds > 1 {
+        input_file("Multiple correction rounds")?;
+    }
+
+
+    // read the whitelist
+    let whitelist_file = WhitelistFile::open(whitelist_filename)?;
+    let whitelist = expand_whitelist(configured_barcodes(&whitelist_file, config)?, config.max_iupac_expansions);
+    // a whitelist with barcodes of several lengths is split into one trie per length, and each barcode is
+    // only corrected to whitelisted barcodes of its own length
+    let barcode_lengths = barcode_lengths(&whitelist)?;
+    let shortest_length = barcode_lengths[0];
+    let lengths_description = barcode_lengths.iter().join(" or ");
+    if barcode_lengths.len() > 1 {
+        info!("Whitelist barcodes have lengths {}; barcodes will be corrected to whitelisted barcodes of the same length", lengths_description);
+    }
+    validate_max_edit_distance(config.max_edit_distance, shortest_length)?;
+    if !(config.dirichlet_concentration.is_finite() && config.dirichlet_concentration > 0.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Dirichlet concentration must be positive, not {}", config.dirichlet_concentration)));
+    }
+    if config.barcode_output == BarcodeOutput::Starsolo && config.umi_length.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "STARsolo output needs a UMI length"));
+    }
+    if config.spatial_coords.is_some() && config.strict_sam_tags {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Spatial coordinate tags (SPATIAL:Z) aren't valid SAM tags, so can't be written with strict SAM tags"));
+    }
+    if let Some(coords) = &config.spatial_coords {
+        let missing = whitelist.iter().filter(|barcode| !coords.contains_key(barcode.as_ref())).count();
+        if missing > 0 {
+            warn!("{} of {} whitelisted barcodes have no spatial coordinates (they'll be tagged SPATIAL:Z:NA)", missing, whitelist.len());
+        }
+    }
+    if config.flush_interval == Some(0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Flush interval must be at least 1 record"));
+    }
+    if config.phred_offset < PHRED33_OFFSET {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Phred offset must be at least {}, not {}", PHRED33_OFFSET, config.phred_offset)));
+    }
+    if config.subsample.is_some_and(|fraction| !(fraction > 0.0 && fraction <= 1.0)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Subsample fraction must be in (0, 1], not {}", config.subsample.unwrap())));
+    }
+    if config.min_likelihood_ratio.is_some_and(|ratio| ratio.is_nan() || ratio < 1.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Min likelihood ratio must be at least 1, not {}", config.min_likelihood_ratio.unwrap())));
+    }
+    if let Some(profile) = &config.error_profile {
+        if barcode_lengths != [profile.len()] {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Error profile has {} positions, but whitelist barcodes have length {}", profile.len(), lengths_description)));
+        }
+    }
+    if config.demux_prefix_length.is_some_and(|n| n == 0 || n > shortest_length) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Demultiplexing prefix length must be between 1 and the (shortest) whitelist barcode length ({})", shortest_length)));
+    }
+    let whitelist_trie: PartitionedTrie = if config.assume_sorted_whitelist {
+        PartitionedTrie::from_sorted_words(&whitelist)?
+    } else {
+        whitelist.iter().collect()
+    };
+    let bloom = config.bloom_false_positive_rate.map(|rate| BloomWhitelist::from_words(&whitelist, rate)).transpose()?;
+    let whitelist: HashSet<&[u8]> = whitelist.iter().map(|s| s.as_ref()).collect();
+    let neighborhoods: Option<Neighborhoods> = if config.precompute_neighborhoods {
+        if config.substitution_costs.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Neighborhoods can't be precomputed with substitution costs"));
+        }
+        let neighborhoods: Neighborhoods = barcode_lengths.iter()
+            .flat_map(|&length| precompute_neighborhood(whitelist_trie.partition(length).unwrap(), &whitelist, config.max_edit_distance))
+            .collect();
+        info!("Precomputed the whitelisted neighbors of {} sequences", neighborhoods.len());
+        Some(neighborhoods)
+    } else {
+        None
+    };
+
+    let disk_counts = match (config.max_counts_memory, disk_counts_filename) {
+        (Some(max_memory), Some(counts_filename)) => {
+            if config.correction_rounds > 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Disk-backed counts can't be used with multiple correction rounds"));
+            }
+            if barcode_lengths.len() > 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Disk-backed counts can't be used with whitelist barcodes of several lengths"));
+            }
+            let counts = DiskCounts::from_counts_file(counts_filename, shortest_length, max_memory)?;
+            info!("Keeping the counts of {} barcodes on disk", counts.len());
+            let count_unit = match config.counts_mode {
+                CountsMode::Frequency if counts.total() > 0.0 => 1.0 / counts.total(),
+                _ => 1.0
+            };
+            Some(DiskPriorCounts::new(counts, count_unit, config.dirichlet_concentration))
+        },
+        _ => None
+    };
+    let round_counts_cell: OnceCell<Vec<HashMap<&[u8], f64>>> = OnceCell::new();
+    let round_counts = || -> io::Result<&Vec<HashMap<&[u8], f64>>> {
+        if round_counts_cell.get().is_none() {
+            // (the input is only read again with multiple rounds, which need a file)
+            let counts = correction_round_counts(input_fastq_filename.unwrap_or_default(), raw_counts.get()?, &whitelist, &whitelist_trie, &barcode_lengths, config)?;
+            let _ = round_counts_cell.set(counts);
+        }
+        Ok(round_counts_cell.get().unwrap())
+    };
+    if config.correction_rounds > 1 {
+        round_counts()?;
+    }
+
+    let (fastq_reader, bytes_read, checksum) = match (input_fastq_filename, input_stream) {
+        (_, Some(stream)) => {
+            let (reader, bytes_read) = open_fastq_stream(stream, config.decompression_threads);
+            (reader, bytes_read, None)
+        },
+        (_, None) => open_fastq_checksummed(input_file("Reading")?, config.expected_checksum.as_ref().map(|_| config.checksum_algorithm), config.decompression_threads)?,
+    };
+    let progress = match input_fastq_filename {
+        // the records of a gzipped input are estimated as it's read, and those of an uncompressed one counted
+        Some(filename) if config.progress => Some(if is_gzipped(filename)? {
+            ProgressBar::with_compressed_bytes(std::fs::metadata(filename)?.len())
+        } else {
+            ProgressBar::with_records(open_fastq(filename)?.records().count() as u64)
+        }),
+        _ => None
+    };
+
+    let mut fastq_outputs = FastqOutputs::new(output, &whitelist, config)?;
+
+    // the sample index whitelist, counts and reads, if correcting sample indices
+    let index_whitelist = match &config.index {
+        Some(index) => read_whitelist(&index.whitelist_path)?,
+        None => Vec::new()
+    };
+    let index_raw_counts = match &config.index {
+        Some(index) => read_counts(&index.counts_path)?,
+        None => HashMap::new()
+    };
+    let index_length = match &config.index {
+        Some(index) => {
+            let length = crate::whitelist::barcode_length(&index_whitelist)?;
+            validate_max_edit_distance(index.max_edit_distance, length)?;
+            length
+        },
+        None => 0
+    };
+    let index_trie: Trie = index_whitelist.iter().collect();
+    let mut index_counts: HashMap<&[u8], f64> = index_raw_counts.iter().map(|(index, &count)| (index.as_slice(), count)).collect();
+    for index in index_whitelist.iter() {
+        *index_counts.entry(index.as_slice()).or_insert(0.0) += 1.0;
+    }
+    let index_correction_config = CorrectionConfig {
+        max_edit_distance: config.index.as_ref().map_or(0, |index| index.max_edit_distance),
+        threshold: config.threshold,
+        min_base_qual: config.min_base_qual,
+        ..Default::default()
+    };
+    let mut index_records = match &config.index {
+        Some(index) => Some(open_fastq(&index.fastq_path)?.records()),
+        None => None
+    };
+
+    let mut corrections_log = match &config.corrections_log_path {
+        Some(path) => Some(create_output(path)?),
+        None => None
+    };
+
+    let mut audit_log = match &config.audit_log_path {
+        Some(path) => {
+            let mut log = BufWriter::new(GzEncoder::new(File::create(path)?, Compression::fast()));
+            writeln!(log, "{}", AUDIT_LOG_HEADER)?;
+            Some(log)
+        },
+        None => None
+    };
+
+    let mut posterior_output = match &config.posterior_output_path {
+        Some(path) => {
+            let mut output = BufWriter::new(GzEncoder::new(File::create(path)?, Compression::fast()));
+            writeln!(output, "{}", POSTERIOR_OUTPUT_HEADER)?;
+            Some(output)
+        },
+        None => None
+    };
+    let mut tags_tsv = match &config.tags_tsv_path {
+        Some(path) => {
+            let mut tsv = create_output(path)?;
+            writeln!(tsv, "{}", TAGS_TSV_HEADER)?;
+            Some(tsv)
+        },
+        None => None
+    };
+
+    let mut stats = CorrectionStats {subsample_fraction: config.subsample, parameters: Some(CorrectionParameters::from_config(config)), ..Default::default()};
+    let mut subsample_rng = SplitMix64(config.subsample_seed);
+    let mut reads_per_barcode: HashMap<Vec<u8>, usize> = HashMap::new();
+    // the best correction of a barcode (with its quality), and the number of candidate corrections
+    let mut correction_cache: LruCache<Vec<u8>, (Option<Correction>, usize)> = LruCache::new(config.correction_cache_size);
+
+    let mut stopped_early = false;
+    for result in fastq_reader.records() {
+        if config.max_records.is_some_and(|max_records| stats.total >= max_records) {
+            info!("Stopping after the first {} records", stats.total);
+            stopped_early = true;
+            break;
+        }
+        let record = match check_record(result) {
+            Ok(record) => record,
+            Err((read_id, e)) if config.skip_errors => {
+                warn!("{}; skipping it", e);
+                stats.malformed_records += 1;
+                // a whole record was read, so its sample index read is skipped too
+                if let (Some(_), Some(records)) = (read_id, index_records.as_mut()) {
+                    records.next();
+                }
+                continue;
+            },
+            Err((_read_id, e)) => return Err(e),
+        };
+        if config.subsample.is_some_and(|fraction| subsample_rng.unit() >= fraction) {
+            if let Some(records) = index_records.as_mut() {
+                records.next();
+            }
+            continue;
+        }
+        stats.total += 1;
+
+        if config.passthrough {
+            if let Some(records) = index_records.as_mut() {
+                records.next();
+            }
+            if config.write_fastq {
+                fastq_outputs.write(None, record.id(), record.desc(), record.seq(), record.qual())?;
+            }
+            continue;
+        }
+
+        let index_tag = match (&config.index, index_records.as_mut()) {
+            (Some(index), Some(records)) => {
+                let index_record = records.next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Index fastq has fewer records than the barcode fastq"))?
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if index_record.id() != record.id() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Index fastq record {} doesn't match barcode fastq record {}", index_record.id(), record.id())));
+                }
+
+                let corrected_index = if index_record.seq().len() == index_length {
+                    accepted_correction(&index_trie, &index_counts, index_record.seq(), &to_phred33(index_record.qual(), config.phred_offset), &index_correction_config)
+                } else {
+                    None
+                };
+                match corrected_index {
+                    Some(c) => format!("\t{}:Z:{}", index.tag, String::from_utf8_lossy(&c.barcode)),
+                    None => {
+                        stats.index_uncorrectable += 1;
+                        if index.exclude_uncorrectable {
+                            continue;
+                        }
+                        "\tXU:Z:index".to_string()
+                    }
+                }
+            },
+            _ => String::new()
+        };
+
+        let mut extra_tags = umi_tags(&record, config);
+        extra_tags.push_str(&index_tag);
+
+        let (seq, unshifted_qual) = matching_barcode(&record, config);
+        let (seq, unshifted_qual, truncated) = truncate_barcode(seq, unshifted_qual, &barcode_lengths, config);
+        if truncated {
+            stats.length_mismatches += 1;
+        }
+        let shifted_qual = shift_quality(&unshifted_qual, config.qual_shift, PHRED33_OFFSET);
+        let qual: &[u8] = if config.qual_shift_likelihood { &shifted_qual } else { &unshifted_qual };
+        let (seq, shifted_qual): (&[u8], &[u8]) = (&seq, &shifted_qual);
+        let ground_truth = config.ground_truth_tag.as_deref().and_then(|tag| description_tag(&record, tag));
+        let has_n = seq.contains(&b'N');
+        if has_n {
+            stats.reads_with_n += 1;
+        }
+
+        let whitelisted = match &bloom {
+            // a Bloom filter has false positives, but a negative is final
+            Some(bloom) => bloom.contains(seq) && whitelist_trie.contains_word(seq),
+            None => whitelist.contains(&seq),
+        };
+        // barcodes to force-accept (e.g., spike-ins) are assigned as-is, like whitelisted barcodes
+        let force_accepted = !whitelisted && config.force_accept.as_ref().is_some_and(|barcodes| barcodes.contains(seq));
+        if whitelisted || force_accepted {
+            if whitelisted {
+                stats.matched_whitelist_before_correction += 1;
+                stats.matched_whitelist_after_correction += 1;
+            } else {
+                stats.force_accepted += 1;
+            }
+            if has_n {
+                stats.reads_with_n_corrected += 1;
+            }
+            *reads_per_barcode.entry(seq.to_vec()).or_insert(0) += 1;
+            stats.add_ground_truth(ground_truth, Some(seq));
+            let new_description = format!("{}{}{}", barcode_tags(seq, Some(seq), shifted_qual, config), spatial_tag(seq, config), extra_tags);
+
+            let (name, description) = output_name_and_description(&record, Some(seq), new_description, config);
+            if let (true, Some(description)) = (config.strict_sam_tags, &description) {
+                parse_sam_tags(description).map_err(|e| io::Error::new(e.kind(), format!("Record {}: {}", record.id(), e)))?;
+            }
+            if config.write_fastq {
+                let (output_seq, output_qual) = output_seq_and_qual(&record, seq, shifted_qual, Some(seq), config);
+                fastq_outputs.write(Some(seq), &name, description.as_deref(), &output_seq, &output_qual)?;
+            }
+            if let Some(tsv) = tags_tsv.as_mut() {
+                write_tags_record(tsv, record.id(), seq, Some(seq), shifted_qual)?;
+            }
+            if let Some(log) = audit_log.as_mut() {
+                let exact_match = Correction {barcode: seq.to_vec(), distance: 0, posterior: 1.0, candidates: 1};
+                write_audit_record(log, record.id(), seq, Some(&exact_match), true, None)?;
+            }
+        } else {
+            let mut best: Option<Correction> = None;
+            let mut neighbors: Option<usize> = None;
+            let filter = filter_barcode(seq, qual, &barcode_lengths, config);
+            let corrected = match filter {
+                Some(Filter::LengthMismatch) => {
+                    stats.length_mismatches += 1;
+                    match config.length_mismatch {
+                        LengthMismatch::Error => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Barcode of record {} has length {}, but whitelist barcodes have length {}", record.id(), seq.len(), lengths_description))),
+                        LengthMismatch::Warn => {
+                            warn!("Barcode of record {} has length {}, but whitelist barcodes have length {}; leaving it uncorrected", record.id(), seq.len(), lengths_description);
+                            None
+                        },
+                        LengthMismatch::Skip | LengthMismatch::Truncate => None,
+                    }
+                },
+                Some(Filter::LowQuality) => {
+                    stats.filtered_low_quality += 1;
+                    None
+                },
+                Some(Filter::LowComplexity) => {
+                    stats.filtered_low_complexity += 1;
+                    None
+                },
+                Some(Filter::Homopolymer) => {
+                    stats.filtered_homopolymer += 1;
+                    None
+                },
+                Some(Filter::HighN) => {
+                    stats.filtered_high_n += 1;
+                    None
+                },
+                None => {
+                    let key = [seq, qual].concat();
+                    let (searched, candidates) = match correction_cache.get(&key) {
+                        Some(cached) => {
+                            stats.correction_cache_hits += 1;
+                            cached.clone()
+                        },
+                        None => {
+                            // the filter leaves only barcodes of a whitelisted length
+                            let trie = whitelist_trie.partition(seq.len()).unwrap();
+                            let searched = match &disk_counts {
+                                Some(counts) => {
+                                    let searched = search_correction(trie, counts, seq, qual, config, None, neighborhoods.as_ref());
+                                    counts.check().map_err(|e| io::Error::new(e.kind(), format!("Record {}: {}", record.id(), e)))?;
+                                    searched
+                                },
+                                None => {
+                                    let (best, candidates) = best_correction_in_rounds(trie, round_counts()?, seq, qual, config, neighborhoods.as_ref());
+                                    (best.map(|(_round, c)| c), candidates)
+                                },
+                            };
+                            correction_cache.put(key, searched.clone());
+                            searched
+                        },
+                    };
+                    best = searched;
+                    neighbors = Some(candidates);
+                    match &best {
+                        Some(c) if c.posterior >= config.threshold => Some(c.clone()),
+                        Some(c) => {
+                            if c.posterior >= config.threshold - NEAR_MISS_BAND {
+                                stats.near_misses += 1;
+                            }
+                            None
+                        },
+                        None => None,
+                    }
+                },
+            };
+
+            if let Some(log) = audit_log.as_mut() {
+                write_audit_record(log, record.id(), seq, best.as_ref(), corrected.is_some(), filter)?;
+            }
+            if let (Some(output), Some(_)) = (posterior_output.as_mut(), neighbors) {
+                write_posterior_record(output, record.id(), seq, best.as_ref())?;
+            }
+
+            let corrected_barcode = corrected.as_ref().map(|c| c.barcode.clone());
+            let assigned_barcode = match &corrected_barcode {
+                Some(barcode) => Some(barcode.as_slice()),
+                None if config.assign_raw_if_uncorrectable => Some(seq),
+                None => None
+            };
+            let mut new_description = barcode_tags(seq, assigned_barcode, shifted_qual, config);
+            match corrected {
+                Some(x) => {
+                    stats.matched_whitelist_after_correction += 1;
+                    *stats.corrections_at_distance.entry(x.distance).or_insert(0) += 1;
+                    stats.add_mismatches(seq, &x.barcode);
+                    new_description.push_str(&spatial_tag(&x.barcode, config));
+                    if has_n {
+                        stats.reads_with_n_corrected += 1;
+                    }
+                    if let Some(log) = corrections_log.as_mut() {
+                        writeln!(log, "{}\t{}\t{}\t{}", record.id(), String::from_utf8_lossy(seq), String::from_utf8_lossy(&x.barcode), x.posterior)?;
+                    }
+                },
+                None => {
+                    if filter == Some(Filter::Homopolymer) {
+                        new_description.push_str(&format!("\tXF:Z:{}", Filter::Homopolymer.name()));
+                    }
+                },
+            };
+            if let (Some(tag), Some(n)) = (&config.neighbor_count_tag, neighbors) {
+                new_description.push_str(&format!("\t{}:i:{}", tag, n));
+            }
+            new_description.push_str(&extra_tags);
+
+            if let Some(barcode) = assigned_barcode {
+                *reads_per_barcode.entry(barcode.to_vec()).or_insert(0) += 1;
+            }
+            stats.add_ground_truth(ground_truth, assigned_barcode);
+            
+            let (name, description) = output_name_and_description(&record, corrected_barcode.as_deref(), new_description, config);
+            if let (true, Some(description)) = (config.strict_sam_tags, &description) {
+                parse_sam_tags(description).map_err(|e| io::Error::new(e.kind(), format!("Record {}: {}", record.id(), e)))?;
+            }
+            if config.write_fastq {
+                let (output_seq, output_qual) = output_seq_and_qual(&record, seq, shifted_qual, corrected_barcode.as_deref(), config);
+                fastq_outputs.write(corrected_barcode.as_deref(), &name, description.as_deref(), &output_seq, &output_qual)?;
+            }
+            if let Some(tsv) = tags_tsv.as_mut() {
+                write_tags_record(tsv, record.id(), seq, assigned_barcode, shifted_qual)?;
+            }
+        }
+        
+        if stats.total.is_multiple_of(config.progress_interval) {
+            if let Some(bar) = &progress {
+                bar.update(bytes_read.load(Ordering::Relaxed), stats.total);
+            }
+            if let Some(callback) = config.progress_callback {
+                callback(&stats);
+            }
+            log_progress(&stats);
+        }
+    }
+
+    stats.unique_barcodes = reads_per_barcode.len();
+    stats.unique_whitelisted_barcodes = reads_per_barcode.keys().filter(|&b| whitelist.contains(b.as_slice())).count();
+    stats.estimated_cells = estimate_cells(&reads_per_barcode.values().copied().collect::<Vec<_>>());
+    let reads_with_barcode: usize = reads_per_barcode.values().sum();
+    if stats.total > 0 {
+        stats.saturation = (reads_with_barcode - stats.unique_barcodes) as f64 / stats.total as f64;
+    }
+
+    if let Some(bar) = &progress {
+        bar.finish(stats.total);
+    }
+    if let Some(callback) = config.progress_callback {
+        callback(&stats);
+    }
+
+    // the counts may never have been needed, but should still have been readable
+    raw_counts.get()?;
+
+    if !stopped_early && index_records.is_some_and(|mut records| records.next().is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Index fastq has more records than the barcode fastq"));
+    }
+
+    if let (Some(checksum), Some(expected)) = (checksum, &config.expected_checksum) {
+        let filename = input_file("Verifying the checksum")?;
+        verify_checksum(&finish_checksum(checksum, filename)?, expected, filename)?;
+        info!("Checksum of {} matches the one expected", filename);
+    }
+
+    // every record read should have been written, but for those deliberately left out
+    fastq_outputs.finish()?;
+    stats.written_records = fastq_outputs.written_records;
+    stats.output_bytes = fastq_outputs.main_bytes.get();
+    let expected_records = match &config.index {
+        _ if !config.write_fastq => 0,
+        Some(index) if index.exclude_uncorrectable => stats.total - stats.index_uncorrectable,
+        _ => stats.total,
+    };
+    if stats.written_records != expected_records {
+        return Err(io::Error::other(format!("Wrote {} records, but expected to write {}; the output may be truncated", stats.written_records, expected_records)));
+    }
+    info!("Wrote {} records ({} bytes to the main output)", stats.written_records, stats.output_bytes);
+    if let Some(log) = corrections_log.as_mut() {
+        log.flush()?;
+    }
+    if let Some(tsv) = tags_tsv.as_mut() {
+        tsv.flush()?;
+    }
+    if let Some(log) = audit_log {
+        log.into_inner().map_err(|e| e.into_error())?.finish()?;
+    }
+    if let Some(output) = posterior_output {
+        output.into_inner().map_err(|e| e.into_error())?.finish()?;
+    }
+    stats.elapsed = start.elapsed();
+    stats.trie_search = whitelist_trie.search_stats();
+
+    if stats.malformed_records > 0 {
+        warn!("Skipped {} malformed records", stats.malformed_records);
+    }
+    if let Some(fraction) = stats.subsample_fraction {
+        info!("Records were subsampled to a fraction of {}; the statistics below cover only the subsample", fraction);
+    }
+    if let Some(parameters) = &stats.parameters {
+        info!("Corrected with barcodes {} and parameters {}", env!("CARGO_PKG_VERSION"), parameters.to_json());
+    }
+    info!("Finished processing {} records; {} matched whitelist before correction, {} matched whitelist after correction, {} had a barcode length mismatch, {} were filtered for low quality, {} were filtered as low complexity, {} were filtered for homopolymer runs, {} were filtered for N content, {} were near misses, {} had an N in the barcode ({} of which were corrected)", stats.total, stats.matched_whitelist_before_correction, stats.matched_whitelist_after_correction, stats.length_mismatches, stats.filtered_low_quality, stats.filtered_low_complexity, stats.filtered_homopolymer, stats.filtered_high_n, stats.near_misses, stats.reads_with_n, stats.reads_with_n_corrected);
+    info!("{} unique barcodes were assigned ({} of them whitelisted), from an estimated {} cells; sequencing saturation was {:.4}", stats.unique_barcodes, stats.unique_whitelisted_barcodes, stats.estimated_cells, stats.saturation);
+    info!("Processed {} records in {:.3}s ({:.0} records/s)", stats.total, stats.elapsed.as_secs_f64(), stats.records_per_second());
+    if let Some(path) = &config.stats_json_path {
+        let mut writer = create_output(path)?;
+        writer.write_all(stats.to_json().as_bytes())?;
+        writer.flush()?;
+    }
+    if let Some(path) = &config.multiqc_path {
+        write_multiqc(&stats, &sample_name(input_fastq_name), path)?;
+    }
+    for (distance, n) in stats.corrections_at_distance.iter().sorted() {
+        info!("{} reads were corrected at distance {}", n, distance);
+    }
+    if stats.trie_search.queries > 0 {
+        info!("Searched the whitelist {} times, visiting {:.1} trie nodes and finding {:.2} candidates per search", stats.trie_search.queries, stats.trie_search.nodes_per_query(), stats.trie_search.candidates_per_query());
+    }
+    if !stats.mismatches_at_position.is_empty() {
+        info!("Corrections changed each barcode position this many times: {}", stats.mismatches_at_position.iter().join(", "));
+    }
+    if config.ground_truth_tag.is_some() {
+        let format = |x: Option<f64>| x.map_or("NA".to_string(), |x| format!("{:.4}", x));
+        info!("{} reads had a ground truth barcode, {} were assigned a barcode and {} were assigned the true barcode: precision {}, recall {}, F1 {}", stats.ground_truth_reads, stats.ground_truth_assigned, stats.ground_truth_correct, format(stats.precision()), format(stats.recall()), format(stats.f1()));
+    }
+    if config.index.is_some() {
+        info!("{} reads had a sample index that couldn't be corrected", stats.index_uncorrectable);
+    }
+
+    Ok(stats)
+
+}
+/// Posterior thresholds tried by [`sweep_thresholds`] by default.
+pub const SWEEP_THRESHOLDS: [f64; 7] = [0.80, 0.85, 0.90, 0.95, 0.975, 0.99, 0.999];
+
+/// Header of the TSV written by [`write_threshold_sweep`].
+pub const THRESHOLD_SWEEP_HEADER: &str = "threshold\ttotal_corrected\tunique_barcodes_corrected";
+
+/// The corrections that would be accepted at one posterior threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdSweep {
+    pub threshold: f64,
+    /// Number of reads whose (non-whitelisted) barcode would be corrected
+    pub total_corrected: usize,
+    /// Number of unique whitelisted barcodes that reads would be corrected to
+    pub unique_barcodes_corrected: usize,
+}
+
+/// Find how many reads would be corrected at each of several posterior thresholds, in a single pass over a
+/// gzipped fastq file.
+///
+/// Rather than accepting or rejecting each correction, the posterior of each read's best correction is
+/// tallied against every threshold (along with the best posterior of a correction to each barcode, for the
+/// unique barcode counts). Barcodes are trimmed and filtered according to `config`, whose threshold and
+/// correction rounds are ignored.
+///
+/// # Example
+/// ```
+/// # use std::fs::{self,File};
+/// # use std::io::Write;
+/// # use flate2::write::GzEncoder;
+/// # use flate2::Compression;
+/// use barcodes::correct::{sweep_thresholds,CorrectionConfig};
+/// # let dir = std::env::temp_dir();
+/// # let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+/// # fs::write(path("sweep_whitelist.txt"), "ACGTACGT\nACGTACGA\nTTTTTTTT\n").unwrap();
+/// # fs::write(path("sweep_counts.tsv"), "ACGTACGT\t90\nACGTACGA\t10\nTTTTTTTT\t100\n").unwrap();
+/// # let mut fastq = GzEncoder::new(File::create(path("sweep_in.fastq.gz")).unwrap(), Compression::fast());
+/// // r1 is whitelisted; r2 is ambiguous between ACGTACGT and ACGTACGA (posterior ~0.9); r3 is clearly TTTTTTTT
+/// # fastq.write_all(b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACGTACGC\n+\nIIIIIIII\n@r3\nTTTTTTTA\n+\nIIIIIIII\n").unwrap();
+/// # fastq.finish().unwrap();
+/// let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+/// let sweep = sweep_thresholds(&path("sweep_in.fastq.gz"), &path("sweep_whitelist.txt"), &path("sweep_counts.tsv"), &[0.85, 0.95], &config).unwrap();
+/// assert_eq!((sweep[0].threshold, sweep[0].total_corrected, sweep[0].unique_barcodes_corrected), (0.85, 2, 2));
+/// assert_eq!((sweep[1].threshold, sweep[1].total_corrected, sweep[1].unique_barcodes_corrected), (0.95, 1, 1));
+/// ```
+pub fn sweep_thresholds(input_fastq_filename: &str, whitelist_filename: &str, counts_filename: &str, thresholds: &[f64], config: &CorrectionConfig) -> io::Result<Vec<ThresholdSweep>> {
+    let whitelist_file = WhitelistFile::open(whitelist_filename)?;
+    let whitelist = expand_whitelist(configured_barcodes(&whitelist_file, config)?, config.max_iupac_expansions);
+    let barcode_length = barcode_length(&whitelist)?;
+    validate_max_edit_distance(config.max_edit_distance, barcode_length)?;
+    let whitelist_trie: Trie = whitelist.iter().collect();
+    let whitelist: HashSet<&[u8]> = whitelist.iter().map(|s| s.as_ref()).collect();
+    let counts_file = if config.uniform_prior { MappedFile::Read(Vec::new()) } else { MappedFile::open(counts_filename)? };
+    let raw_counts = if config.uniform_prior { HashMap::new() } else { parse_counts(&counts_file, counts_filename)? };
+    let (counts, _count_unit) = prior_counts(&raw_counts, &whitelist, config.counts_mode, config.dirichlet_concentration);
+
+    let mut total_corrected = vec![0; thresholds.len()];
+    let mut best_posterior_per_barcode: HashMap<Vec<u8>, f64> = HashMap::new();
+
+    for result in open_fastq(input_fastq_filename)?.records() {
+        let record = match check_record(result) {
+            Ok(record) => record,
+            Err(_) if config.skip_errors => continue,
+            Err((_read_id, e)) => return Err(e),
+        };
+        let (seq, qual) = matching_barcode(&record, config);
+        let (seq, qual, _truncated) = truncate_barcode(seq, qual, &[barcode_length], config);
+        let (seq, qual): (&[u8], &[u8]) = (&seq, &qual);
+        if whitelist.contains(&seq) || filter_barcode(seq, qual, &[barcode_length], config).is_some() {
+            continue;
+        }
+
+        if let Some(correction) = best_correction(&whitelist_trie, &counts, seq, qual, config) {
+            for (n, &threshold) in total_corrected.iter_mut().zip(thresholds) {
+                if correction.posterior >= threshold {
+                    *n += 1;
+                }
+            }
+            let best = best_posterior_per_barcode.entry(correction.barcode).or_insert(0.0);
+            *best = best.max(correction.posterior);
+        }
+    }
+
+    Ok(thresholds.iter().zip(total_corrected).map(|(&threshold, total_corrected)| ThresholdSweep {
+        threshold,
+        total_corrected,
+        unique_barcodes_corrected: best_posterior_per_barcode.values().filter(|&&p| p >= threshold).count(),
+    }).collect())
+}
+
+/// Write a threshold sweep as a TSV (see [`THRESHOLD_SWEEP_HEADER`]) to a file, or to stdout if the filename
+/// is `-`.
+pub fn write_threshold_sweep(sweep: &[ThresholdSweep], filename: &str) -> io::Result<()> {
+    let mut writer = create_output(filename)?;
+    writeln!(writer, "{}", THRESHOLD_SWEEP_HEADER)?;
+    for row in sweep {
+        writeln!(writer, "{}\t{}\t{}", row.threshold, row.total_corrected, row.unique_barcodes_corrected)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_counts_read_error_is_returned() {
+        let path = std::env::temp_dir().join("disk_prior_counts_error.tsv");
+        std::fs::write(&path, "ACGTACGT\t90\nACGTACGA\t10\n").unwrap();
+        let counts = DiskCounts::from_counts_file(path.to_str().unwrap(), 8, 1).unwrap();
+        let counts = DiskPriorCounts::new(counts, 1.0, 1.0);
+        let trie = Trie::from_words([b"ACGTACGT", b"ACGTACGA"]);
+        let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+
+        let (best, _candidates) = search_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config, None, None);
+        assert_eq!(best.unwrap().barcode, b"ACGTACGT");
+        assert!(counts.check().is_ok());
+
+        // a truncated table can't be read, which is an error rather than a panic
+        File::options().write(true).open(counts.counts.path()).unwrap().set_len(0).unwrap();
+        search_correction(&trie, &counts, b"ACGTACGC", b"IIIIIIII", &config, None, None);
+        let error = counts.check().unwrap_err();
+        assert!(error.to_string().starts_with("Failed to read the disk-backed counts"));
+        assert!(counts.check().is_ok());
+    }
+
+    /// A path in the temp dir that's unique to the test process.
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("barcodes_test_{}_{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    /// Write gzipped fastq `records` to a [`temp_path`], returning the path.
+    fn write_gz_fastq(name: &str, records: &[u8]) -> String {
+        let path = temp_path(name);
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::fast());
+        encoder.write_all(records).unwrap();
+        encoder.finish().unwrap();
+        path
+    }
+
+    /// Correct the barcodes of the fastq `records` to a whitelist of `barcodes` (each counted 10 times), with
+    /// inputs in [`temp_path`]s named after `name`, returning the stats and the decompressed output fastq.
+    fn correct_records(name: &str, records: &[u8], barcodes: &[&str], config: &CorrectionConfig) -> io::Result<(CorrectionStats, String)> {
+        let input = write_gz_fastq(&format!("{}_in.fastq.gz", name), records);
+        let whitelist = temp_path(&format!("{}_whitelist.txt", name));
+        std::fs::write(&whitelist, barcodes.join("\n")).unwrap();
+        let mut output = Vec::new();
+        let stats = correct_barcodes_with_counts(&input, &whitelist, barcodes.iter().map(|barcode| (barcode, 10)), &mut output, config)?;
+        let mut fastq = String::new();
+        flate2::read::MultiGzDecoder::new(output.as_slice()).read_to_string(&mut fastq).unwrap();
+        Ok((stats, fastq))
+    }
+
+    #[test]
+    fn low_quality_barcodes_are_counted_as_filtered() {
+        let records = b"@r1\nACGTACGA\n+\nIIII#III\n@r2\nACGTACGA\n+\nIIIIIIII\n";
+        let config = CorrectionConfig {min_position_quality: Some(20), ..Default::default()};
+        let (stats, fastq) = correct_records("low_quality", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!(stats.filtered_low_quality, 1);
+        assert!(fastq.starts_with("@r1 CR:Z:ACGTACGA\tCY:Z:IIII#III\n"));
+        assert!(fastq.contains("@r2 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\t"));
+    }
+
+    #[test]
+    fn uncompressed_input_is_corrected_like_gzipped_input() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n@r3\nGGGGCCCC\n+\nIIIIIIII\n";
+        let barcodes = ["ACGTACGT", "TTTTTTTT"];
+        let (gzipped_stats, gzipped) = correct_records("uncompressed", records, &barcodes, &Default::default()).unwrap();
+        let input = temp_path("uncompressed_in.fastq");
+        std::fs::write(&input, records).unwrap();
+        for progress in [false, true] {
+            let mut output = Vec::new();
+            let config = CorrectionConfig {progress, ..Default::default()};
+            let stats = correct_barcodes_with_counts(&input, &temp_path("uncompressed_whitelist.txt"), barcodes.iter().map(|barcode| (barcode, 10)), &mut output, &config).unwrap();
+            let mut fastq = String::new();
+            flate2::read::MultiGzDecoder::new(output.as_slice()).read_to_string(&mut fastq).unwrap();
+            assert_eq!(fastq, gzipped);
+            assert_eq!((stats.total, stats.matched_whitelist_after_correction), (gzipped_stats.total, gzipped_stats.matched_whitelist_after_correction));
+        }
+    }
+
+    #[test]
+    fn barcodes_of_the_wrong_length_are_handled_as_configured() {
+        let corrected = |record: &[u8], length_mismatch: LengthMismatch| {
+            let (stats, fastq) = correct_records("length_mismatch", record, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig {length_mismatch, ..Default::default()})?;
+            Ok::<_, io::Error>((stats.length_mismatches, fastq.lines().next().unwrap().to_string()))
+        };
+        let (too_short, too_long) = (b"@r1\nACGTACG\n+\nIIIIIII\n", b"@r1\nACGTACGAGG\n+\nIIIIIIIIII\n");
+        assert!(corrected(too_short, LengthMismatch::Error).is_err());
+        assert!(corrected(too_long, LengthMismatch::Error).is_err());
+        // left uncorrected, with or without a warning
+        for length_mismatch in [LengthMismatch::Warn, LengthMismatch::Skip] {
+            assert_eq!(corrected(too_short, length_mismatch).unwrap(), (1, "@r1 CR:Z:ACGTACG\tCY:Z:IIIIIII".to_string()));
+            assert_eq!(corrected(too_long, length_mismatch).unwrap(), (1, "@r1 CR:Z:ACGTACGAGG\tCY:Z:IIIIIIIIII".to_string()));
+        }
+        // or, if too long, truncated
+        assert_eq!(corrected(too_short, LengthMismatch::Truncate).unwrap(), (1, "@r1 CR:Z:ACGTACG\tCY:Z:IIIIIII".to_string()));
+        assert_eq!(corrected(too_long, LengthMismatch::Truncate).unwrap(), (1, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII".to_string()));
+    }
+
+    #[test]
+    fn too_long_barcodes_are_truncated() {
+        let config = CorrectionConfig {length_mismatch: LengthMismatch::Truncate, ..Default::default()};
+        let (seq, qual, truncated) = truncate_barcode(Cow::Borrowed(b"ACGTACGTAC"), Cow::Owned(b"IIIIIIIII#".to_vec()), &[4, 8], &config);
+        assert_eq!((seq.as_ref(), qual.as_ref(), truncated), (&b"ACGTACGT"[..], &b"IIIIIIII"[..], true));
+        // barcodes of a whitelist length, or shorter than any, are left as they are, as are all barcodes
+        // unless truncating
+        for (barcode, config) in [(&b"ACGT"[..], &config), (b"ACG", &config), (b"ACGTACGTAC", &CorrectionConfig::default())] {
+            let (seq, _qual, truncated) = truncate_barcode(Cow::Borrowed(barcode), Cow::Borrowed(barcode), &[4, 8], config);
+            assert_eq!((seq.as_ref(), truncated), (barcode, false));
+        }
+    }
+
+    #[test]
+    fn too_long_barcodes_are_truncated_in_batch_and_em_correction() {
+        let records = [fastq::Record::with_attrs("r1", None, b"ACGTACGCA", b"IIIIIIIII")];
+        let whitelist: HashSet<&[u8]> = HashSet::from([&b"ACGTACGT"[..], b"TTTTTTTT"]);
+        let trie: Trie = whitelist.iter().collect();
+        let counts: HashMap<&[u8], usize> = whitelist.iter().map(|&barcode| (barcode, 10)).collect();
+        let config = CorrectionConfig {max_edit_distance: 1, length_mismatch: LengthMismatch::Truncate, ..Default::default()};
+
+        let corrected: Vec<_> = correct_batch(records.iter(), &trie, &counts, &config).collect();
+        assert_eq!(corrected[0].barcode.as_deref(), Some(&b"ACGTACGT"[..]));
+        let (barcodes, _counts) = correct_barcodes_em(&records, &trie, &whitelist, 10, 1e-9, &config);
+        assert_eq!(barcodes[0].as_deref(), Some(&b"ACGTACGT"[..]));
+    }
+
+    #[test]
+    fn too_long_barcodes_are_truncated_in_correction_rounds() {
+        let fastq = write_gz_fastq("truncated_rounds.fastq.gz", b"@r1\nACGTACGCA\n+\nIIIIIIIII\n");
+        let whitelist: HashSet<&[u8]> = HashSet::from([&b"ACGTACGT"[..], b"TTTTTTTT"]);
+        let tries: PartitionedTrie = whitelist.iter().collect();
+        let raw_counts: HashMap<&[u8], f64> = whitelist.iter().map(|&barcode| (barcode, 10.0)).collect();
+        let config = CorrectionConfig {max_edit_distance: 1, correction_rounds: 2, length_mismatch: LengthMismatch::Truncate, ..Default::default()};
+
+        let round_counts = correction_round_counts(&fastq, &raw_counts, &whitelist, &tries, &[8], &config).unwrap();
+        let barcode: &[u8] = b"ACGTACGT";
+        assert_eq!(round_counts[1][barcode], round_counts[0][barcode] + 1.0);
+        std::fs::remove_file(fastq).unwrap();
+    }
+
+    /// Key-value fields of a log record.
+    type Fields = Vec<(String, String)>;
+
+    /// A logger capturing the fields of logged records, along with the thread that logged them so that tests
+    /// running in parallel only see their own records.
+    #[derive(Default)]
+    struct FieldLogger {
+        records: std::sync::Mutex<Vec<(std::thread::ThreadId, Fields)>>,
+    }
+
+    impl FieldLogger {
+        /// The logger shared by all tests, installed as the global logger the first time it's needed.
+        fn shared() -> &'static FieldLogger {
+            static LOGGER: std::sync::OnceLock<FieldLogger> = std::sync::OnceLock::new();
+            static INSTALL: std::sync::Once = std::sync::Once::new();
+            let logger = LOGGER.get_or_init(FieldLogger::default);
+            INSTALL.call_once(|| {
+                log::set_logger(logger).expect("FieldLogger should be the only logger installed by the tests");
+                log::set_max_level(log::LevelFilter::Info);
+            });
+            logger
+        }
+
+        /// Fields of the records logged so far by the current thread.
+        fn records(&self) -> Vec<Fields> {
+            let thread = std::thread::current().id();
+            self.records.lock().unwrap().iter().filter(|(t, _)| *t == thread).map(|(_, fields)| fields.clone()).collect()
+        }
+    }
+
+    impl log::Log for FieldLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            struct FieldVisitor(Fields);
+            impl<'kvs> log::kv::VisitSource<'kvs> for FieldVisitor {
+                fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+                    self.0.push((key.to_string(), value.to_string()));
+                    Ok(())
+                }
+            }
+            let mut fields = FieldVisitor(Vec::new());
+            record.key_values().visit(&mut fields).unwrap();
+            self.records.lock().unwrap().push((std::thread::current().id(), fields.0));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn progress_is_logged_with_fields() {
+        let logger = FieldLogger::shared();
+        let stats = CorrectionStats {total: 4, matched_whitelist_before_correction: 2, matched_whitelist_after_correction: 3, ..Default::default()};
+        log_progress(&stats);
+        let fields: Vec<(&str, &str)> = [("total", "4"), ("matched_before", "2"), ("matched_after", "3")].into();
+        let records = logger.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>(), fields);
+    }
+
+    #[test]
+    fn umi_is_tagged_with_its_qualities() {
+        let config = CorrectionConfig {umi_offset: 8, umi_length: Some(6), ..Default::default()};
+        let record = fastq::Record::with_attrs("r1", None, b"ACGTACGAGGCCAA", b"IIIIIIII??????");
+        assert_eq!(umi_tags(&record, &config), "\tUR:Z:GGCCAA\tUY:Z:??????");
+        // a read too short to hold the UMI has no UMI tags, nor does any read if no UMI is configured
+        let short = fastq::Record::with_attrs("r2", None, b"ACGTACGAGGCC", b"IIIIIIII????");
+        assert_eq!(umi_tags(&short, &config), "");
+        assert_eq!(umi_tags(&record, &CorrectionConfig::default()), "");
+    }
+
+    #[test]
+    fn barcodes_are_read_from_and_corrected_in_read_names() {
+        let config = CorrectionConfig {read_name_delimiter: Some('_'), ..Default::default()};
+        let record = fastq::Record::with_attrs("read1_ACGTACGA", Some("1:N:0"), b"GATTACA", b"IIIIIII");
+        let (seq, qual) = matching_barcode(&record, &config);
+        assert_eq!((seq.as_ref(), qual.as_ref()), (&b"ACGTACGA"[..], &b"IIIIIIII"[..]));
+        // the corrected barcode takes the place of the uncorrected one, and the description is kept
+        let (name, description) = output_name_and_description(&record, Some(b"ACGTACGT"), String::new(), &config);
+        assert_eq!((name.as_str(), description.as_deref()), ("read1_ACGTACGT", Some("1:N:0")));
+        let (name, _description) = output_name_and_description(&record, None, String::new(), &config);
+        assert_eq!(name, "read1_ACGTACGA");
+    }
+
+    #[test]
+    fn barcode_tags_are_written_as_a_tsv() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n@r3\nGGGGCCCC\n+\nIIIIIIII\n";
+        let config = CorrectionConfig {tags_tsv_path: Some(temp_path("tags.tsv.gz")), ..Default::default()};
+        let (_stats, fastq) = correct_records("tags", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        let mut tsv = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(temp_path("tags.tsv.gz")).unwrap()).read_to_string(&mut tsv).unwrap();
+        assert_eq!(tsv, "read_id\tCR\tCB\tCY\nr1\tACGTACGA\tACGTACGT\tIIIIIIII\nr2\tTTTTTTTT\tTTTTTTTT\tIIIIIIII\nr3\tGGGGCCCC\t.\tIIIIIIII\n");
+        // with the same values as the fastq descriptions
+        for (header, row) in fastq.lines().step_by(4).zip(tsv.lines().skip(1)) {
+            let (id, description) = header[1..].split_once(' ').unwrap();
+            let tag = |name: &str| description.split('\t').find_map(|t| t.strip_prefix(name)).unwrap_or(".").to_string();
+            assert_eq!(row, [id.to_string(), tag("CR:Z:"), tag("CB:Z:"), tag("CY:Z:")].join("\t"));
+        }
+
+        // or in place of the fastq
+        let config = CorrectionConfig {tags_tsv_path: Some(temp_path("tags_only.tsv")), write_fastq: false, ..Default::default()};
+        let (_stats, fastq) = correct_records("tags_only", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert!(fastq.is_empty());
+        assert_eq!(std::fs::read_to_string(temp_path("tags_only.tsv")).unwrap(), tsv);
+    }
+
+    #[test]
+    fn malformed_records_are_an_error_unless_skipped() {
+        // r2 has fewer phred scores than bases
+        let records = b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nTTTTTTTA\n+\nIIII\n@r3\nTTTTTTTT\n+\nIIIIIIII\n";
+        assert!(correct_records("malformed", records, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig::default()).is_err());
+        let config = CorrectionConfig {skip_errors: true, ..Default::default()};
+        let (stats, fastq) = correct_records("malformed_skipped", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!((stats.malformed_records, stats.total, stats.matched_whitelist_after_correction), (1, 2, 2));
+        assert_eq!(fastq.lines().step_by(4).map(|header| header.split(' ').next().unwrap()).collect::<Vec<_>>(), vec!["@r1", "@r3"]);
+    }
+
+    #[test]
+    fn assigned_barcodes_are_checked_against_the_ground_truth() {
+        // r1 and r2 are assigned their true barcode, r3 the wrong one, r4 none, and r5 has no ground truth
+        let records = [
+            "@r1 GT:Z:ACGTACGT\nACGTACGT\n+\nIIIIIIII\n", "@r2 GT:Z:TTTTTTTT\nTTTTTTTA\n+\nIIIIIIII\n", "@r3 GT:Z:ACGTACGT\nTTTTTTTC\n+\nIIIIIIII\n",
+            "@r4 GT:Z:TTTTTTTT\nGGGGCCCC\n+\nIIIIIIII\n", "@r5\nACGTACGT\n+\nIIIIIIII\n",
+        ].concat();
+        let config = CorrectionConfig {ground_truth_tag: Some("GT".to_string()), ..Default::default()};
+        let (stats, _fastq) = correct_records("ground_truth", records.as_bytes(), &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!((stats.ground_truth_reads, stats.ground_truth_assigned, stats.ground_truth_correct), (4, 3, 2));
+        assert_eq!(stats.recall(), Some(0.5));
+    }
+
+    #[test]
+    fn barcodes_are_reverse_complemented_before_matching() {
+        let record = fastq::Record::with_attrs("r1", None, b"AAAACCGT", b"ABCDEFGH");
+        let (seq, qual) = matching_barcode(&record, &CorrectionConfig {revcomp: true, ..Default::default()});
+        assert_eq!((seq.as_ref(), qual.as_ref()), (&b"ACGGTTTT"[..], &b"HGFEDCBA"[..]));
+        // after any trimming
+        let config = CorrectionConfig {revcomp: true, trim_5p: 2, ..Default::default()};
+        let (seq, qual) = matching_barcode(&record, &config);
+        assert_eq!((seq.as_ref(), qual.as_ref()), (&b"ACGGTT"[..], &b"HGFEDC"[..]));
+    }
+
+    #[test]
+    fn strict_sam_tags_are_checked() {
+        let config = CorrectionConfig {strict_sam_tags: true, umi_offset: 8, umi_length: Some(6), trim_3p: 6, ..Default::default()};
+        let (_stats, fastq) = correct_records("strict_sam", b"@r1\nACGTACGAGGCCAA\n+\nIIIIIIII??????\n", &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        let (_id, description) = fastq.lines().next().unwrap().split_once(' ').unwrap();
+        let tags: Vec<String> = parse_sam_tags(description).unwrap().iter().map(|(tag, tag_type, value)| format!("{}:{}:{}", tag, tag_type, value)).collect();
+        assert_eq!(tags, vec!["CR:Z:ACGTACGA", "CB:Z:ACGTACGT", "CY:Z:IIIIIIII", "UR:Z:GGCCAA", "UY:Z:??????"]);
+        // the original descriptions of reads with their barcode in the name usually aren't SAM tags
+        let config = CorrectionConfig {strict_sam_tags: true, read_name_delimiter: Some('_'), ..Default::default()};
+        assert!(correct_records("strict_sam_name", b"@read1_ACGTACGA 1:N:0:GATTACA\nGATTACA\n+\nIIIIIII\n", &["ACGTACGT", "TTTTTTTT"], &config).is_err());
+    }
+
+    #[test]
+    fn counts_on_disk_give_the_same_corrections_as_counts_in_memory() {
+        let input = write_gz_fastq("disk_counts_in.fastq.gz", b"@r1\nACGTACGC\n+\nIIIIIIII\n@r2\nTTTTTTTA\n+\nIIIIIII#\n@r3\nACGTACGG\n+\nIIIIIII#\n");
+        let (whitelist, counts) = (temp_path("disk_counts_whitelist.txt"), temp_path("disk_counts.tsv"));
+        std::fs::write(&whitelist, "ACGTACGT\nACGTACGA\nTTTTTTTT\n").unwrap();
+        // with repeated and unwhitelisted barcodes
+        std::fs::write(&counts, "ACGTACGT\t50\nGGGG\t100\nACGTACGA\t1\nTTTTTTTT\t3\nACGTACGA\t1\n").unwrap();
+        for counts_mode in [CountsMode::Raw, CountsMode::Frequency] {
+            let corrected = |max_counts_memory: Option<usize>| {
+                let config = CorrectionConfig {max_edit_distance: 1, threshold: 0.8, counts_mode, max_counts_memory, ..Default::default()};
+                let mut output = Vec::new();
+                correct_barcodes(&input, &whitelist, &counts, &mut output, &config).unwrap();
+                output
+            };
+            let in_memory = corrected(None);
+            assert_eq!(corrected(Some(1)), in_memory);
+            let mut fastq = String::new();
+            flate2::read::MultiGzDecoder::new(in_memory.as_slice()).read_to_string(&mut fastq).unwrap();
+            assert!(fastq.contains("CB:Z:"));
+        }
+    }
+
+    #[test]
+    fn corrections_of_repeated_barcodes_are_cached() {
+        // r3 has the same barcode as r1 and r2, but not the same phred scores
+        let records = b"@r1\nTTTTTTTA\n+\nIIIIIIII\n@r2\nTTTTTTTA\n+\nIIIIIIII\n@r3\nTTTTTTTA\n+\nIIIIIII#\n";
+        let (cached, cached_fastq) = correct_records("cache", records, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig::default()).unwrap();
+        assert_eq!(cached.correction_cache_hits, 1);
+        let config = CorrectionConfig {correction_cache_size: 0, ..Default::default()};
+        let (uncached, uncached_fastq) = correct_records("uncached", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!(uncached.correction_cache_hits, 0);
+        assert_eq!(uncached_fastq, cached_fastq);
+    }
+
+    #[test]
+    fn output_cut_short_is_an_error() {
+        struct ShortWriter {
+            written: Vec<u8>,
+            capacity: usize,
+        }
+        impl Write for ShortWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = buf.len().min(self.capacity - self.written.len());
+                if n == 0 && !buf.is_empty() {
+                    return Err(io::Error::other("No space left on device"));
+                }
+                self.written.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let input = write_gz_fastq("short_output_in.fastq.gz", b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n@r3\nGGGGCCCC\n+\nIIIIIIII\n");
+        let whitelist = temp_path("short_output_whitelist.txt");
+        std::fs::write(&whitelist, "ACGTACGT\nTTTTTTTT\n").unwrap();
+        let counts = [(b"ACGTACGT", 10), (b"TTTTTTTT", 10)];
+
+        let mut output = Vec::new();
+        let stats = correct_barcodes_with_counts(&input, &whitelist, counts, &mut output, &CorrectionConfig::default()).unwrap();
+        assert_eq!((stats.written_records, stats.output_bytes), (3, output.len() as u64));
+        // all but the very end of the gzip stream fits
+        let short = ShortWriter {written: Vec::new(), capacity: output.len() - 4};
+        assert!(correct_barcodes_with_counts(&input, &whitelist, counts, short, &CorrectionConfig::default()).is_err());
+    }
+
+    #[test]
+    fn a_concentrated_dirichlet_prior_makes_rare_barcodes_plausible_corrections() {
+        let whitelist: HashSet<&[u8]> = HashSet::from([&b"AAAAAAAA"[..], b"AAAAAAAC"]);
+        let trie: Trie = whitelist.iter().collect();
+        let raw_counts: HashMap<&[u8], f64> = HashMap::from([(&b"AAAAAAAA"[..], 100.0)]);
+        let posterior = |dirichlet_concentration: f64| {
+            let (counts, _count_unit) = prior_counts(&raw_counts, &whitelist, CountsMode::Raw, dirichlet_concentration);
+            let config = CorrectionConfig {dirichlet_concentration, ..Default::default()};
+            let correction = best_correction(&trie, &counts, b"AAAAAAAG", b"IIIIIIII", &config).unwrap();
+            assert_eq!(correction.barcode, b"AAAAAAAA");
+            correction.posterior
+        };
+        // 101/102 with the default pseudocount of 1, but 1100/2100 with one of 1000
+        assert!((posterior(1.0) - 101.0 / 102.0).abs() < 1e-9);
+        assert!((posterior(1000.0) - 1100.0 / 2100.0).abs() < 1e-9);
+
+        // the concentration must be positive
+        let config = CorrectionConfig {dirichlet_concentration: 0.0, ..Default::default()};
+        assert!(correct_records("dirichlet", b"@r1\nAAAAAAAG\n+\nIIIIIIII\n", &["AAAAAAAA", "AAAAAAAC"], &config).is_err());
+    }
+
+    #[test]
+    fn counts_are_ignored_under_a_uniform_prior() {
+        let input = write_gz_fastq("uniform_prior_in.fastq.gz", b"@r1\nAAAAAACC\n+\nIIIIII##\n");
+        let whitelist = temp_path("uniform_prior_whitelist.txt");
+        std::fs::write(&whitelist, "AAAAAAAA\nAAAAAAAC\n").unwrap();
+        let corrected = |uniform_prior: bool| {
+            let config = CorrectionConfig {uniform_prior, threshold: 0.6, ..Default::default()};
+            let mut output = Vec::new();
+            correct_barcodes_with_counts(&input, &whitelist, [(b"AAAAAAAA", 100)], &mut output, &config).unwrap();
+            let record = fastq::Reader::new(flate2::read::MultiGzDecoder::new(output.as_slice())).records().next().unwrap().unwrap();
+            description_tag(&record, "CB").unwrap().to_vec()
+        };
+        // AAAAAAAA needs two low quality errors, but has a much higher count than AAAAAAAC, which needs one
+        assert_eq!(corrected(false), b"AAAAAAAA");
+        assert_eq!(corrected(true), b"AAAAAAAC");
+    }
+
+    #[test]
+    fn linkers_are_spliced_out_of_barcodes() {
+        let record = fastq::Record::with_attrs("r1", None, b"ACGTACGTGGGGTTTTAAAA", b"ABCDEFGH####IJIJIJIJ");
+        let (seq, qual) = matching_barcode(&record, &CorrectionConfig {linker_offset: 8, linker_length: 4, ..Default::default()});
+        assert_eq!((seq.as_ref(), qual.as_ref()), (&b"ACGTACGTTTTTAAAA"[..], &b"ABCDEFGHIJIJIJIJ"[..]));
+        // a linker running past the end of the barcode is removed up to the end
+        assert_eq!(remove_linker(b"ACGTACGTGG", b"ABCDEFGH##", 8, 4), (b"ACGTACGT".to_vec(), b"ABCDEFGH".to_vec()));
+        assert_eq!(remove_linker(b"ACGT", b"ABCD", 8, 4), (b"ACGT".to_vec(), b"ABCD".to_vec()));
+    }
+
+    #[test]
+    fn bgzf_output_reads_like_any_gzip() {
+        let input = write_gz_fastq("bgzf_output_in.fastq.gz", b"@r1\nACGTACGA\n+\nIIIIIIII\n");
+        let whitelist = temp_path("bgzf_output_whitelist.txt");
+        std::fs::write(&whitelist, "ACGTACGT\nTTTTTTTT\n").unwrap();
+        let mut output = Vec::new();
+        let config = CorrectionConfig {output_format: OutputFormat::Bgzf, ..Default::default()};
+        correct_barcodes_with_counts(&input, &whitelist, [(b"ACGTACGT", 10), (b"TTTTTTTT", 10)], &mut output, &config).unwrap();
+        // with the BC extra field, and an EOF block
+        assert_eq!(&output[12..14], b"BC");
+        assert!(output.ends_with(&crate::io::BGZF_EOF));
+        let mut fastq = String::new();
+        flate2::read::MultiGzDecoder::new(output.as_slice()).read_to_string(&mut fastq).unwrap();
+        assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\nACGTACGA\n+\nIIIIIIII\n");
+    }
+
+    #[test]
+    fn a_reproducible_subsample_of_the_records_is_corrected() {
+        let records: String = (0..200).map(|i| format!("@r{}\nTTTTTTTA\n+\nIIIIIIII\n", i)).collect();
+        let config = CorrectionConfig {subsample: Some(0.5), ..Default::default()};
+        let (stats, fastq) = correct_records("subsample", records.as_bytes(), &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert!(stats.total > 60 && stats.total < 140);
+        assert_eq!(fastq.lines().count(), 4 * stats.total);
+        assert_eq!(stats.subsample_fraction, Some(0.5));
+        assert!(stats.to_json().contains("\"subsample_fraction\": 0.5"));
+        let (_again, again_fastq) = correct_records("subsample_again", records.as_bytes(), &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!(again_fastq, fastq);
+    }
+
+    #[test]
+    fn phred64_barcode_qualities_are_converted_to_phred33() {
+        let config = CorrectionConfig {phred_offset: 64, umi_offset: 8, umi_length: Some(2), ..Default::default()};
+        let record = fastq::Record::with_attrs("r1", None, b"ACGTACGAGG", b"hhhhhhhhBB");
+        let (_seq, qual) = matching_barcode(&record, &config);
+        assert_eq!(qual.as_ref(), b"IIIIIIII##");
+        assert_eq!(umi_tags(&record, &config), "\tUR:Z:GG\tUY:Z:##");
+        // but the record's own qualities are written as they are
+        let (_seq, qual) = output_seq_and_qual(&record, b"ACGTACGA", b"IIIIIIII", Some(b"ACGTACGT"), &config);
+        assert_eq!(qual.as_ref(), b"hhhhhhhhBB");
+    }
+
+    #[test]
+    fn adapters_are_trimmed_before_matching() {
+        let config = CorrectionConfig {adapter: Some(b"GATCTA".to_vec()), ..Default::default()};
+        // with up to one mismatch
+        let record = fastq::Record::with_attrs("r1", None, b"GATCAAACGTACGA", b"######IIIIIIII");
+        let (seq, qual) = matching_barcode(&record, &config);
+        assert_eq!((seq.as_ref(), qual.as_ref()), (&b"ACGTACGA"[..], &b"IIIIIIII"[..]));
+        assert_eq!(adapter_prefix_length(b"GATCTAACGTACGA", &config), 6);
+        assert_eq!(adapter_prefix_length(b"GTTCAAACGTACGA", &config), 0);
+        // barcodes without the adapter, or too short to hold it, are left as they are
+        assert_eq!(adapter_prefix_length(b"TTTTTTTT", &config), 0);
+        assert_eq!(adapter_prefix_length(b"GATC", &config), 0);
+    }
+
+    #[test]
+    fn corrected_barcodes_are_appended_to_read_names_for_cellranger() {
+        let config = CorrectionConfig {barcode_output: BarcodeOutput::Cellranger, ..Default::default()};
+        let record = fastq::Record::with_attrs("r1", Some("1:N:0"), b"ACGTACGA", b"IIIIIIII");
+        let tags = barcode_tags(b"ACGTACGA", Some(b"ACGTACGT"), b"IIIIIIII", &config);
+        let (name, description) = output_name_and_description(&record, Some(b"ACGTACGT"), tags, &config);
+        assert_eq!((name.as_str(), description.as_deref()), ("r1#ACGTACGT", Some("1:N:0")));
+        // a read without a corrected barcode keeps its name
+        let record = fastq::Record::with_attrs("r2", None, b"GGGGCCCC", b"IIIIIIII");
+        let tags = barcode_tags(b"GGGGCCCC", None, b"IIIIIIII", &config);
+        assert_eq!(output_name_and_description(&record, None, tags, &config), ("r2".to_string(), None));
+    }
+
+    #[test]
+    fn force_accepted_barcodes_are_assigned_as_they_are() {
+        let config = CorrectionConfig {force_accept: Some(HashSet::from([b"GATTACAA".to_vec()])), ..Default::default()};
+        let records = b"@r1\nGATTACAA\n+\nIIIIIIII\n@r2\nGATTACAC\n+\nIIIIIIII\n";
+        let (stats, fastq) = correct_records("force_accept", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!((stats.force_accepted, stats.matched_whitelist_after_correction), (1, 0));
+        assert!(fastq.starts_with("@r1 CR:Z:GATTACAA\tCB:Z:GATTACAA\tCY:Z:IIIIIIII\n"));
+        // but barcodes near them aren't corrected to them
+        assert!(fastq.contains("@r2 CR:Z:GATTACAC\tCY:Z:IIIIIIII\n"));
+    }
+
+    #[test]
+    fn starsolo_reads_are_the_barcode_followed_by_the_umi() {
+        let config = CorrectionConfig {barcode_output: BarcodeOutput::Starsolo, trim_3p: 6, umi_offset: 8, umi_length: Some(6), ..Default::default()};
+        let record = fastq::Record::with_attrs("r1", None, b"ACGTACGAGGCCAA", b"IIIIIIII??????");
+        let (seq, qual) = matching_barcode(&record, &config);
+        // the corrected barcode, or else the uncorrected one
+        let (output_seq, output_qual) = output_seq_and_qual(&record, &seq, &qual, Some(b"ACGTACGT"), &config);
+        assert_eq!((output_seq.as_ref(), output_qual.as_ref()), (&b"ACGTACGTGGCCAA"[..], &b"IIIIIIII??????"[..]));
+        let (output_seq, _output_qual) = output_seq_and_qual(&record, &seq, &qual, None, &config);
+        assert_eq!(output_seq.as_ref(), b"ACGTACGAGGCCAA");
+    }
+
+    #[test]
+    fn mismatches_are_counted_by_position() {
+        // three of the four corrections are at the fourth position
+        let records = b"@r1\nACGAACGT\n+\nIIIIIIII\n@r2\nTTTATTTT\n+\nIIIIIIII\n@r3\nACGCACGT\n+\nIIIIIIII\n@r4\nATGTACGT\n+\nIIIIIIII\n";
+        let (stats, _fastq) = correct_records("cycle", records, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig::default()).unwrap();
+        assert_eq!(stats.mismatches_at_position[..4], [0, 1, 0, 3]);
+        assert_eq!(stats.mismatches_at_position.iter().sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn feature_barcodes_are_tagged_separately() {
+        let config = CorrectionConfig {feature_barcodes: true, ..Default::default()};
+        assert_eq!(barcode_tags(b"ACGTACGA", Some(b"ACGTACGT"), b"IIIIIIII", &config), "FR:Z:ACGTACGA\tFB:Z:ACGTACGT\tFY:Z:IIIIIIII");
+        assert_eq!(barcode_tags(b"GGGGCCCC", None, b"IIIIIIII", &config), "FR:Z:GGGGCCCC\tFY:Z:IIIIIIII");
+        assert_eq!(barcode_tags(b"ACGTACGA", Some(b"ACGTACGT"), b"IIIIIIII", &CorrectionConfig::default()), "CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII");
+    }
+
+    #[test]
+    fn spatial_barcodes_are_tagged_with_their_coordinates() {
+        let coords = [(b"ACGTACGT".to_vec(), (12.0, 3.5))].into_iter().collect();
+        let config = CorrectionConfig {spatial_coords: Some(coords), ..Default::default()};
+        assert_eq!(spatial_tag(b"ACGTACGT", &config), "\tSPATIAL:Z:12_3.5");
+        assert_eq!(spatial_tag(b"TTTTTTTT", &config), "\tSPATIAL:Z:NA");
+        assert_eq!(spatial_tag(b"ACGTACGT", &CorrectionConfig::default()), "");
+    }
+
+    #[test]
+    fn flushed_output_is_a_complete_fastq() {
+        let input = write_gz_fastq("flush_in.fastq.gz", b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n");
+        let whitelist = temp_path("flush_whitelist.txt");
+        std::fs::write(&whitelist, "ACGTACGT\nTTTTTTTT\n").unwrap();
+        let corrected = |flush_interval: Option<usize>| {
+            let mut output = Vec::new();
+            let config = CorrectionConfig {flush_interval, ..Default::default()};
+            correct_barcodes_with_counts(&input, &whitelist, [(b"ACGTACGT", 10), (b"TTTTTTTT", 10)], &mut output, &config).unwrap();
+            let mut fastq = String::new();
+            flate2::read::MultiGzDecoder::new(output.as_slice()).read_to_string(&mut fastq).unwrap();
+            (output.len(), fastq)
+        };
+        let (flushed_length, flushed_fastq) = corrected(Some(1));
+        let (unflushed_length, unflushed_fastq) = corrected(None);
+        assert_eq!(flushed_fastq, unflushed_fastq);
+        // if a larger one
+        assert!(flushed_length > unflushed_length);
+        assert!(correct_barcodes_with_counts(&input, &whitelist, [(b"ACGTACGT", 10)], Vec::new(), &CorrectionConfig {flush_interval: Some(0), ..Default::default()}).is_err());
+    }
+
+    #[test]
+    fn input_without_the_expected_checksum_is_an_error() {
+        let input = write_gz_fastq("checksum_in.fastq.gz", b"@r1\nACGTACGA\n+\nIIIIIIII\n");
+        let whitelist = temp_path("checksum_whitelist.txt");
+        std::fs::write(&whitelist, "ACGTACGT\nTTTTTTTT\n").unwrap();
+        let corrected = |expected_checksum: String| {
+            let config = CorrectionConfig {expected_checksum: Some(expected_checksum), ..Default::default()};
+            correct_barcodes_with_counts(&input, &whitelist, [(b"ACGTACGT", 10), (b"TTTTTTTT", 10)], io::sink(), &config)
+        };
+        let expected = crate::checksum::file_checksum(&input, ChecksumAlgorithm::Md5).unwrap();
+        assert!(corrected(expected.clone()).is_ok());
+        assert!(corrected("0".repeat(expected.len())).is_err());
+    }
+
+    #[test]
+    fn qualities_are_shifted_in_the_output() {
+        let corrected = |config: &CorrectionConfig| {
+            let (_stats, fastq) = correct_records("qual_shift", b"@r1\nACGTACGA\n+\nIIIIIIII\n", &["ACGTACGT", "TTTTTTTT"], config).unwrap();
+            fastq
+        };
+        let fastq = corrected(&CorrectionConfig {qual_shift: 3, ..Default::default()});
+        assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:LLLLLLLL\nACGTACGA\n+\nLLLLLLLL\n");
+        // saturating
+        let fastq = corrected(&CorrectionConfig {qual_shift: 100, ..Default::default()});
+        assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:~~~~~~~~\nACGTACGA\n+\n~~~~~~~~\n");
+        // corrections are still weighed with the original qualities...
+        let fastq = corrected(&CorrectionConfig {qual_shift: -100, min_position_quality: Some(30), ..Default::default()});
+        assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:!!!!!!!!\nACGTACGA\n+\n!!!!!!!!\n");
+        // ...unless the shifted qualities should be used
+        let fastq = corrected(&CorrectionConfig {qual_shift: -100, qual_shift_likelihood: true, min_position_quality: Some(30), ..Default::default()});
+        assert!(fastq.starts_with("@r1 CR:Z:ACGTACGA\tCY:Z:!!!!!!!!\n"));
+    }
+
+    #[test]
+    fn only_the_first_records_are_corrected() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n";
+        let (stats, fastq) = correct_records("max_records", records, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig {max_records: Some(1), ..Default::default()}).unwrap();
+        assert_eq!((stats.total, stats.written_records), (1, 1));
+        assert_eq!(fastq, "@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\nACGTACGA\n+\nIIIIIIII\n");
+    }
+
+    #[test]
+    fn barcodes_are_corrected_to_whitelisted_barcodes_of_their_own_length() {
+        // ACGTAC is one substitution from ACGTACGT's prefix, but that isn't a candidate
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nACGTAC\n+\nIIIIII\n@r3\nGGGGGC\n+\nIIIIII\n@r4\nGGGGG\n+\nIIIII\n";
+        let config = CorrectionConfig {length_mismatch: LengthMismatch::Skip, ..Default::default()};
+        let (stats, fastq) = correct_records("two_lengths", records, &["ACGTACGT", "TTTTTTTT", "ACGTAA", "GGGGGG"], &config).unwrap();
+        assert_eq!((stats.total, stats.matched_whitelist_after_correction, stats.length_mismatches), (4, 3, 1));
+        let assigned: Vec<&str> = fastq.lines().step_by(4).map(|header| header.split('\t').find_map(|t| t.strip_prefix("CB:Z:")).unwrap_or("-")).collect();
+        assert_eq!(assigned, vec!["ACGTACGT", "ACGTAA", "GGGGGG", "-"]);
+    }
+
+    #[test]
+    fn passthrough_records_are_copied_untouched() {
+        let records = "@r1 some description\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n";
+        let (stats, fastq) = correct_records("passthrough", records.as_bytes(), &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig {passthrough: true, ..Default::default()}).unwrap();
+        assert_eq!((stats.total, stats.written_records, stats.matched_whitelist_after_correction), (2, 2, 0));
+        assert_eq!(fastq, records);
+    }
+
+    #[test]
+    fn the_number_of_candidate_corrections_is_tagged() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n";
+        let config = CorrectionConfig {neighbor_count_tag: Some("XN".to_string()), ..Default::default()};
+        let (_stats, fastq) = correct_records("neighbor_count", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        // whether or not the barcode was corrected
+        assert!(fastq.starts_with("@r1 CR:Z:ACGTACGA\tCB:Z:ACGTACGT\tCY:Z:IIIIIIII\tXN:i:1\n"));
+        assert!(fastq.contains("@r2 CR:Z:GGGGCCCC\tCY:Z:IIIIIIII\tXN:i:0\n"));
+    }
+
+    #[test]
+    fn precomputed_neighborhoods_give_the_same_corrections() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n@r3\nTTTTTTTT\n+\nIIIIIIII\n";
+        let (searched, searched_fastq) = correct_records("searched_neighborhoods", records, &["ACGTACGT", "TTTTTTTT"], &CorrectionConfig::default()).unwrap();
+        let config = CorrectionConfig {precompute_neighborhoods: true, ..Default::default()};
+        let (precomputed, precomputed_fastq) = correct_records("precomputed_neighborhoods", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        assert_eq!(precomputed_fastq, searched_fastq);
+        assert_eq!(precomputed.matched_whitelist_after_correction, 2);
+        // without searching the trie
+        assert_eq!((searched.trie_search.queries, precomputed.trie_search.queries), (2, 0));
+    }
+
+    #[test]
+    fn posteriors_are_written_for_post_filtering() {
+        let records = b"@r1\nACGTACGA\n+\nIIIIIIII\n@r2\nGGGGCCCC\n+\nIIIIIIII\n";
+        let config = CorrectionConfig {posterior_output_path: Some(temp_path("posteriors.tsv.gz")), ..Default::default()};
+        correct_records("posteriors", records, &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        let mut posteriors = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(temp_path("posteriors.tsv.gz")).unwrap()).read_to_string(&mut posteriors).unwrap();
+        let lines: Vec<&str> = posteriors.lines().collect();
+        assert_eq!(lines[0], POSTERIOR_OUTPUT_HEADER);
+        assert!(lines[1].starts_with("r1\tACGTACGA\tACGTACGT\t0.9"));
+        // with NA for a read without any candidate correction
+        assert_eq!(lines[2], "r2\tGGGGCCCC\tNA\tNA");
+    }
+
+    #[test]
+    fn version_and_parameters_are_recorded_in_the_stats() {
+        let config = CorrectionConfig {max_edit_distance: 1, unmatched_prior: Some(0.01), counts_mode: CountsMode::Frequency, length_mismatch: LengthMismatch::Truncate, ..Default::default()};
+        let (stats, _fastq) = correct_records("parameters", b"@r1\nACGTACGA\n+\nIIIIIIII\n", &["ACGTACGT", "TTTTTTTT"], &config).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["parameters"], serde_json::json!({
+            "max_edit_distance": 1,
+            "threshold": 0.975,
+            "pseudocount": 1,
+            "uncorrected_prior": DEFAULT_UNCORRECTED_PRIOR,
+            "unmatched_prior": 0.01,
+            "correction_model": "product",
+            "counts_mode": "frequency",
+            "min_likelihood_ratio": null,
+            "length_mismatch": "truncate",
+        }));
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self,File};
+use std::io::{self,BufRead,BufReader,BufWriter,Read,Seek,SeekFrom,Write};
+use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+/// Estimated in-memory size of a counts entry beyond its barcode (the count, and the barcode's allocation).
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Number of disk-backed tables created by this process so far, to give each a unique file name.
+static TABLES_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+fn table_path(suffix: &str) -> PathBuf {
+    let n = TABLES_CREATED.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("barcodes_counts_{}_{}.{}", std::process::id(), n, suffix))
+}
+
+/// A barcode counts table kept in a sorted file of fixed-width records (the barcode, then its count as a
+/// little-endian f64) on disk, in which counts are looked up by binary search.
+///
+/// Only barcodes of a single length (that of the whitelist) are kept, since no other barcode can be a
+/// correction. The file is removed when the table is dropped.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::disk_counts::DiskCounts;
+/// let path = std::env::temp_dir().join("disk_counts.tsv");
+/// fs::write(&path, "TTTT\t5\nACGT\t10\nGGGG\t1\nACGT\t2\nAC\t7\n").unwrap();
+/// // a memory cap of a single entry sorts the counts in several runs, which are then merged
+/// let counts = DiskCounts::from_counts_file(path.to_str().unwrap(), 4, 1).unwrap();
+/// assert_eq!(counts.len(), 3);
+/// assert_eq!(counts.total(), 25.0);
+/// assert_eq!(counts.get(b"ACGT").unwrap(), Some(12.0));
+/// assert_eq!(counts.get(b"TTTT").unwrap(), Some(5.0));
+/// assert_eq!(counts.get(b"CCCC").unwrap(), None);
+/// ```
+pub struct DiskCounts {
+    path: PathBuf,
+    file: RefCell<File>,
+    barcode_length: usize,
+    len: u64,
+    total: f64,
+}
+
+impl DiskCounts {
+    /// Build a table from a barcode counts file (a TSV of barcode and count, like
+    /// [`read_counts`](crate::correct::read_counts)), holding at most about `max_memory` bytes of counts in
+    /// memory at once. Counts of repeated barcodes are summed.
+    pub fn from_counts_file(counts_filename: &str, barcode_length: usize, max_memory: usize) -> io::Result<DiskCounts> {
+        let chunk_capacity = (max_memory / (barcode_length + ENTRY_OVERHEAD)).max(1);
+        let mut chunk: Vec<(Vec<u8>, f64)> = Vec::new();
+        let mut runs: Vec<PathBuf> = Vec::new();
+        let mut total = 0.0;
+
+        for line in BufReader::new(File::open(counts_filename)?).lines() {
+            let line = line?;
+            let count = line.split_once('\t').and_then(|(barcode, count)| Some((barcode, count.trim_end().parse::<usize>().ok()?)));
+            let (barcode, count) = count.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in counts file {}: {}", counts_filename, line)))?;
+            total += count as f64;
+            if barcode.len() != barcode_length {
+                continue;
+            }
+
+            chunk.push((barcode.as_bytes().to_vec(), count as f64));
+            if chunk.len() == chunk_capacity {
+                runs.push(write_run(&mut chunk)?);
+            }
+        }
+        if !chunk.is_empty() || runs.is_empty() {
+            runs.push(write_run(&mut chunk)?);
+        }
+
+        let path = table_path("bin");
+        let len = merge_runs(&runs, barcode_length, &path);
+        for run in runs.iter() {
+            fs::remove_file(run)?;
+        }
+
+        Ok(DiskCounts {file: RefCell::new(File::open(&path)?), path, barcode_length, len: len?, total})
+    }
+
+    /// Number of unique barcodes in the table.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Check whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sum of all counts in the counts file (including barcodes of other lengths, which aren't kept).
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    /// Path of the table file (which is removed when the counts are dropped).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn record(&self, index: u64) -> io::Result<(Vec<u8>, f64)> {
+        read_record(&mut *self.file.borrow_mut(), self.barcode_length, Some(index))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, format!("Counts table {} is truncated (no record {})", self.path.display(), index)))
+    }
+
+    /// Look up the count of a barcode (None if it isn't in the table).
+    pub fn get(&self, barcode: &[u8]) -> io::Result<Option<f64>> {
+        if barcode.len() != self.barcode_length {
+            return Ok(None);
+        }
+
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (middle_barcode, count) = self.record(middle)?;
+            match middle_barcode.as_slice().cmp(barcode) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(Some(count)),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for DiskCounts {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read the record at `index` (or, if None, the next record) of a table file, or None at the end of the file.
+fn read_record<R: Read + Seek>(reader: &mut R, barcode_length: usize, index: Option<u64>) -> io::Result<Option<(Vec<u8>, f64)>> {
+    if let Some(index) = index {
+        reader.seek(SeekFrom::Start(index * (barcode_length + 8) as u64))?;
+    }
+
+    let mut barcode = vec![0; barcode_length];
+    match reader.read_exact(&mut barcode) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut count = [0; 8];
+    reader.read_exact(&mut count)?;
+    Ok(Some((barcode, f64::from_le_bytes(count))))
+}
+
+fn write_record<W: Write>(writer: &mut W, barcode: &[u8], count: f64) -> io::Result<()> {
+    writer.write_all(barcode)?;
+    writer.write_all(&count.to_le_bytes())
+}
+
+/// Sort a chunk of counts and write it to a run file, emptying the chunk.
+fn write_run(chunk: &mut Vec<(Vec<u8>, f64)>) -> io::Result<PathBuf> {
+    chunk.sort_by(|a, b| a.0.cmp(&b.0));
+    let path = table_path("run");
+    let mut writer = BufWriter::new(File::create(&path)?);
+    for (barcode, count) in chunk.drain(..) {
+        write_record(&mut writer, &barcode, count)?;
+    }
+    writer.flush()?;
+    Ok(path)
+}
+
+/// Merge sorted run files into a single sorted table, summing the counts of barcodes repeated within or
+/// across runs. Returns the number of unique barcodes.
+fn merge_runs(runs: &[PathBuf], barcode_length: usize, path: &PathBuf) -> io::Result<u64> {
+    let mut readers: Vec<BufReader<File>> = runs.iter().map(|run| File::open(run).map(BufReader::new)).collect::<io::Result<_>>()?;
+    let mut counts: Vec<f64> = vec![0.0; runs.len()];
+    let mut heap: BinaryHeap<Reverse<(Vec<u8>, usize)>> = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some((barcode, count)) = read_record(reader, barcode_length, None)? {
+            counts[i] = count;
+            heap.push(Reverse((barcode, i)));
+        }
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut current: Option<(Vec<u8>, f64)> = None;
+    let mut len = 0;
+    while let Some(Reverse((barcode, i))) = heap.pop() {
+        match current.as_mut() {
+            Some((current_barcode, count)) if *current_barcode == barcode => *count += counts[i],
+            _ => {
+                if let Some((current_barcode, count)) = current.take() {
+                    write_record(&mut writer, &current_barcode, count)?;
+                    len += 1;
+                }
+                current = Some((barcode, counts[i]));
+            }
+        }
+        if let Some((barcode, count)) = read_record(&mut readers[i], barcode_length, None)? {
+            counts[i] = count;
+            heap.push(Reverse((barcode, i)));
+        }
+    }
+    if let Some((barcode, count)) = current {
+        write_record(&mut writer, &barcode, count)?;
+        len += 1;
+    }
+    writer.flush()?;
+
+    Ok(len)
+}
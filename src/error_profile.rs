@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self,Read};
+
+/// Per-position substitution probabilities of a barcode design (e.g., as supplied by the manufacturer).
+///
+/// When used in barcode correction, the probability of a mismatch at a position is taken from the profile,
+/// in place of the probability derived from the phred score.
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::trie::Trie;
+/// use barcodes::correct::{best_correction,CorrectionConfig};
+/// use barcodes::error_profile::ErrorProfile;
+/// let trie = Trie::from_words([b"ACGTACGA", b"TCGTACGT"]);
+/// let counts: HashMap<&[u8], f64> = HashMap::from([(&b"ACGTACGA"[..], 10.0), (&b"TCGTACGT"[..], 10.0)]);
+///
+/// // both candidates are one mismatch away (at position 0 and 7 respectively), so the tie goes to the
+/// // lexicographically smaller one
+/// let config = CorrectionConfig {max_edit_distance: 1, ..Default::default()};
+/// let correction = best_correction(&trie, &counts, b"TCGTACGA", b"IIIIIIII", &config).unwrap();
+/// assert_eq!(correction.barcode, b"ACGTACGA".to_vec());
+///
+/// // a profile under which substitutions at position 0 are very unlikely favors the other candidate
+/// let profile = ErrorProfile::new(vec![1e-6, 0.01, 0.01, 0.01, 0.01, 0.01, 0.01, 0.01]).unwrap();
+/// let config = CorrectionConfig {max_edit_distance: 1, error_profile: Some(profile), ..Default::default()};
+/// let correction = best_correction(&trie, &counts, b"TCGTACGA", b"IIIIIIII", &config).unwrap();
+/// assert_eq!(correction.barcode, b"TCGTACGT".to_vec());
+/// assert!(correction.posterior > 0.99);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorProfile {
+    probabilities: Vec<f64>,
+}
+
+impl ErrorProfile {
+    /// Create a profile from the substitution probability of each position.
+    ///
+    /// Returns an error if any probability is not in (0, 1].
+    pub fn new(probabilities: Vec<f64>) -> io::Result<ErrorProfile> {
+        if let Some(p) = probabilities.iter().find(|&&p| !(p > 0.0 && p <= 1.0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Error profile probability {} is not in (0, 1]", p)));
+        }
+
+        Ok(ErrorProfile {probabilities})
+    }
+
+    /// Substitution probability at a position, or None if the position is beyond the end of the profile.
+    pub fn probability(&self, position: usize) -> Option<f64> {
+        self.probabilities.get(position).copied()
+    }
+
+    /// Number of positions in the profile.
+    pub fn len(&self) -> usize {
+        self.probabilities.len()
+    }
+
+    /// Check whether the profile has no positions.
+    pub fn is_empty(&self) -> bool {
+        self.probabilities.is_empty()
+    }
+}
+
+/// Read an error profile file.
+///
+/// The file is a TSV with one line per barcode position, giving the (0-based) position and its
+/// substitution probability. Every position from 0 up to the last must be present exactly once; blank
+/// lines and lines starting with `#` are skipped.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::error_profile::read_error_profile;
+/// let path = std::env::temp_dir().join("read_error_profile.tsv");
+/// fs::write(&path, "# position\tprobability\n1\t0.02\n0\t0.01\n").unwrap();
+/// let profile = read_error_profile(path.to_str().unwrap()).unwrap();
+/// assert_eq!(profile.len(), 2);
+/// assert_eq!(profile.probability(0), Some(0.01));
+/// assert_eq!(profile.probability(2), None);
+/// ```
+pub fn read_error_profile(filename: &str) -> io::Result<ErrorProfile> {
+    let mut contents = String::new();
+    File::open(filename)?.read_to_string(&mut contents)?;
+
+    let invalid_line = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid error profile line: {}", line));
+
+    let mut positions: Vec<(usize, f64)> = Vec::new();
+    for line in contents.lines().map(|l| l.trim_end()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let (position, probability) = line.split_once('\t').ok_or_else(|| invalid_line(line))?;
+        let position = position.parse::<usize>().map_err(|_| invalid_line(line))?;
+        let probability = probability.parse::<f64>().map_err(|_| invalid_line(line))?;
+        positions.push((position, probability));
+    }
+
+    positions.sort_by_key(|&(position, _)| position);
+    if positions.iter().enumerate().any(|(i, &(position, _))| i != position) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Error profile positions must run from 0 without gaps or duplicates"));
+    }
+
+    ErrorProfile::new(positions.into_iter().map(|(_, probability)| probability).collect())
+}
+
+/// Per-position, per-base error rates of a sequencer (e.g., estimated from a control library), for when
+/// its phred scores are miscalibrated, e.g., by cycle-specific biases.
+///
+/// When used in barcode correction, the probability of a mismatch at a position where the read has a base
+/// covered by the model is the model's rate times the probability derived from the phred score (or taken
+/// from an [`ErrorProfile`]).
+///
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use barcodes::correct::likelihood_of_errors;
+/// use barcodes::error_profile::ErrorModel;
+/// let model = ErrorModel::new(HashMap::from([((3, b'T'), 0.5)])).unwrap();
+/// assert_eq!(model.rate(3, b'T'), Some(0.5));
+/// assert_eq!(model.rate(3, b'G'), None);
+/// // one mismatch, at position 3, where the read has a T
+/// let quality_only = likelihood_of_errors(b"ACGT", b"ACGA", b"III#", None, None, None);
+/// let with_model = likelihood_of_errors(b"ACGT", b"ACGA", b"III#", None, None, Some(&model));
+/// assert!((with_model - 0.5 * quality_only).abs() < 1e-12);
+/// assert!(ErrorModel::new(HashMap::from([((0, b'A'), 1.5)])).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorModel {
+    rates: HashMap<(usize, u8), f64>,
+}
+
+impl ErrorModel {
+    /// Create a model from the error rate of each (0-based position, read base) combination it covers.
+    ///
+    /// Returns an error if any rate is not in (0, 1].
+    pub fn new(rates: HashMap<(usize, u8), f64>) -> io::Result<ErrorModel> {
+        if let Some(r) = rates.values().find(|&&r| !(r > 0.0 && r <= 1.0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Error model rate {} is not in (0, 1]", r)));
+        }
+
+        Ok(ErrorModel {rates})
+    }
+
+    /// Error rate of a base at a position, or None if the model doesn't cover it.
+    pub fn rate(&self, position: usize, base: u8) -> Option<f64> {
+        self.rates.get(&(position, base)).copied()
+    }
+
+    /// Number of (position, base) combinations in the model.
+    pub fn len(&self) -> usize {
+        self.rates.len()
+    }
+
+    /// Check whether the model covers nothing.
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+}
+
+/// Read an error model file.
+///
+/// The file is a TSV with columns `position` (0-based), `base` (the base in the read) and `error_rate`,
+/// with an optional header line. Each combination of position and base may appear at most once; blank
+/// lines and lines starting with `#` are skipped.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::error_profile::read_error_model;
+/// let path = std::env::temp_dir().join("read_error_model.tsv");
+/// fs::write(&path, "position\tbase\terror_rate\n0\tA\t0.5\n0\tG\t2e-1\n# late cycles\n7\tg\t0.9\n").unwrap();
+/// let model = read_error_model(path.to_str().unwrap()).unwrap();
+/// assert_eq!(model.len(), 3);
+/// assert_eq!(model.rate(0, b'G'), Some(0.2));
+/// assert_eq!(model.rate(7, b'G'), Some(0.9));
+/// fs::write(&path, "0\tA\t0.5\n0\tA\t0.2\n").unwrap();
+/// assert!(read_error_model(path.to_str().unwrap()).is_err());
+/// ```
+pub fn read_error_model(filename: &str) -> io::Result<ErrorModel> {
+    let mut contents = String::new();
+    File::open(filename)?.read_to_string(&mut contents)?;
+
+    let invalid_line = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid error model line: {}", line));
+
+    let mut rates: HashMap<(usize, u8), f64> = HashMap::new();
+    let lines = contents.lines().map(|l| l.trim_end()).filter(|l| !l.is_empty() && !l.starts_with('#'));
+    for (i, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if i == 0 && fields.first() == Some(&"position") {
+            continue;
+        }
+        let [position, base, rate] = fields[..] else {
+            return Err(invalid_line(line));
+        };
+        let position = position.parse::<usize>().map_err(|_| invalid_line(line))?;
+        let base = match base.as_bytes() {
+            [base] if b"ACGTN".contains(&base.to_ascii_uppercase()) => base.to_ascii_uppercase(),
+            _ => return Err(invalid_line(line)),
+        };
+        let rate = rate.parse::<f64>().map_err(|_| invalid_line(line))?;
+        if rates.insert((position, base), rate).is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Error model has more than one rate for base {} at position {}", base as char, position)));
+        }
+    }
+
+    ErrorModel::new(rates)
+}
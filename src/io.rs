@@ -0,0 +1,916 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fs::File;
+use std::io::{self,Read,Seek,Write,BufRead,BufReader,BufWriter};
+use std::rc::Rc;
+use std::cell::Cell;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::sync::mpsc::{self,Receiver,SyncSender};
+use std::thread::{self,JoinHandle};
+use flate2::read::MultiGzDecoder;
+use flate2::write::{DeflateEncoder,GzEncoder};
+use flate2::{Compression,Crc};
+use bio::io::fastq;
+use memmap2::Mmap;
+use crate::checksum::{Checksum,ChecksumAlgorithm};
+#[cfg(feature = "libdeflate")]
+use libdeflater::Decompressor;
+
+/// The gzip decoder of fastq inputs: flate2's multi-member decoder, or with the `libdeflate` feature, a
+/// [`LibdeflateReader`].
+#[cfg(not(feature = "libdeflate"))]
+pub type GzReader<R> = MultiGzDecoder<R>;
+/// The gzip decoder of fastq inputs: flate2's multi-member decoder, or with the `libdeflate` feature, a
+/// [`LibdeflateReader`].
+#[cfg(feature = "libdeflate")]
+pub type GzReader<R> = LibdeflateReader<R>;
+
+/// The first bytes of any gzip file.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A fastq reader over a (possibly gzipped) file or stream.
+pub type FastqReader = fastq::Reader<BufReader<Box<dyn Read>>>;
+
+/// Check whether a file is gzipped (starts with [`GZIP_MAGIC`]), rather than plain text.
+///
+/// # Example
+/// ```
+/// use std::fs::{self,File};
+/// use std::io::Write;
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::is_gzipped;
+/// let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// let mut gz = GzEncoder::new(File::create(path("is_gzipped.fastq.gz")).unwrap(), Compression::fast());
+/// gz.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// gz.finish().unwrap();
+/// assert!(is_gzipped(&path("is_gzipped.fastq.gz")).unwrap());
+/// fs::write(path("is_not_gzipped.fastq"), b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// assert!(!is_gzipped(&path("is_not_gzipped.fastq")).unwrap());
+/// ```
+pub fn is_gzipped(filename: &str) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(GZIP_MAGIC.len());
+    File::open(filename)?.take(GZIP_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    Ok(magic == GZIP_MAGIC)
+}
+
+/// Open a fastq file for reading, decompressing it if it's gzipped (see [`is_gzipped`]).
+///
+/// All members of multi-member gzip files (e.g., produced by concatenating several gzipped files)
+/// are read, rather than stopping at the end of the first member.
+///
+/// # Example
+/// ```
+/// use std::fs::File;
+/// use std::io::Write;
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::open_fastq;
+/// let filename = std::env::temp_dir().join("open_fastq_example.fastq.gz");
+/// let mut file = File::create(&filename).unwrap();
+/// for chunk in [b"@r1\nACGT\n+\nIIII\n", b"@r2\nTTTT\n+\nIIII\n"] {
+///     let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+///     gz.write_all(chunk).unwrap();
+///     file.write_all(&gz.finish().unwrap()).unwrap();
+/// }
+/// let reader = open_fastq(filename.to_str().unwrap()).unwrap();
+/// assert_eq!(reader.records().count(), 2);
+///
+/// // whatever the decoder, the records are those of the uncompressed fastq
+/// let uncompressed = bio::io::fastq::Reader::new(&b"@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n"[..]);
+/// let expected: Vec<_> = uncompressed.records().map(|r| r.unwrap()).collect();
+/// let records: Vec<_> = open_fastq(filename.to_str().unwrap()).unwrap().records().map(|r| r.unwrap()).collect();
+/// assert_eq!(records, expected);
+///
+/// // as are those of an uncompressed fastq file
+/// let filename = std::env::temp_dir().join("open_fastq_example.fastq");
+/// std::fs::write(&filename, b"@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n").unwrap();
+/// let records: Vec<_> = open_fastq(filename.to_str().unwrap()).unwrap().records().map(|r| r.unwrap()).collect();
+/// assert_eq!(records, expected);
+/// ```
+pub fn open_fastq(filename: &str) -> io::Result<FastqReader> {
+    let file = File::open(filename)?;
+    let fastq: Box<dyn Read> = if is_gzipped(filename)? {
+        Box::new(GzReader::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(fastq::Reader::from_bufread(BufReader::new(fastq)))
+}
+
+/// Offset of phred scores in standard (Sanger, Illumina 1.8+) fastq quality strings.
+pub const PHRED33_OFFSET: u8 = 33;
+
+/// Offset of phred scores in legacy (Illumina 1.3 to 1.7) fastq quality strings.
+pub const PHRED64_OFFSET: u8 = 64;
+
+/// Number of records whose quality strings are checked to detect the phred encoding.
+pub const PHRED_DETECTION_RECORDS: usize = 1000;
+
+/// Encoding of the phred scores in fastq quality strings.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PhredEncoding {
+    /// Phred+33 (standard)
+    #[value(name = "33")]
+    Phred33,
+    /// Phred+64 (legacy Illumina)
+    #[value(name = "64")]
+    Phred64,
+    /// Detect the encoding from the quality strings of the first records (see [`detect_phred_offset`])
+    Auto,
+}
+
+/// Detect the phred score offset of a gzipped fastq file from the quality strings of its first `max_records`
+/// records.
+///
+/// Phred+33 quality strings use characters below `;` (phred+64 scores can't go below -5), while phred+64
+/// quality strings use characters above `J` (phred+33 scores rarely go above 41). If neither is seen (or
+/// there are no records), the standard phred+33 is assumed.
+///
+/// # Example
+/// ```
+/// use std::fs::File;
+/// use std::io::Write;
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::{detect_phred_offset,PHRED33_OFFSET,PHRED64_OFFSET};
+/// let write = |name: &str, fastq: &[u8]| {
+///     let filename = std::env::temp_dir().join(name);
+///     let mut gz = GzEncoder::new(File::create(&filename).unwrap(), Compression::fast());
+///     gz.write_all(fastq).unwrap();
+///     gz.finish().unwrap();
+///     filename.to_str().unwrap().to_string()
+/// };
+/// assert_eq!(detect_phred_offset(&write("phred33.fastq.gz", b"@r1\nACGT\n+\nFF:#\n"), 1000).unwrap(), PHRED33_OFFSET);
+/// assert_eq!(detect_phred_offset(&write("phred64.fastq.gz", b"@r1\nACGT\n+\nhhhB\n"), 1000).unwrap(), PHRED64_OFFSET);
+/// assert_eq!(detect_phred_offset(&write("phred_ambiguous.fastq.gz", b"@r1\nACGT\n+\nFFFF\n"), 1000).unwrap(), PHRED33_OFFSET);
+/// ```
+pub fn detect_phred_offset(filename: &str, max_records: usize) -> io::Result<u8> {
+    for record in open_fastq(filename)?.records().take(max_records) {
+        let record = record.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if record.qual().iter().any(|&q| q < b';') {
+            return Ok(PHRED33_OFFSET);
+        }
+        if record.qual().iter().any(|&q| q > b'J') {
+            return Ok(PHRED64_OFFSET);
+        }
+    }
+    Ok(PHRED33_OFFSET)
+}
+
+/// Quality string converted to phred+33, from phred+`offset`.
+pub fn to_phred33(qual: &[u8], offset: u8) -> Cow<'_, [u8]> {
+    if offset == PHRED33_OFFSET {
+        Cow::Borrowed(qual)
+    } else {
+        Cow::Owned(qual.iter().map(|&q| q.saturating_sub(offset.saturating_sub(PHRED33_OFFSET))).collect())
+    }
+}
+
+/// Highest character of a fastq quality string (phred 93 in phred+33).
+pub const MAX_QUALITY_CHAR: u8 = b'~';
+
+/// Quality string (in phred+`offset`) with every phred score shifted by `shift`, saturating at 0 and at the
+/// highest score encodable in the string ([`MAX_QUALITY_CHAR`]).
+///
+/// # Example
+/// ```
+/// use barcodes::io::{shift_quality,PHRED33_OFFSET,PHRED64_OFFSET};
+/// assert_eq!(shift_quality(b"#+5I", 2, PHRED33_OFFSET).as_ref(), b"%-7K");
+/// assert_eq!(shift_quality(b"#+5I", -5, PHRED33_OFFSET).as_ref(), b"!&0D");
+/// assert_eq!(shift_quality(b"!}~", 3, PHRED33_OFFSET).as_ref(), b"$~~");
+/// assert_eq!(shift_quality(b"BDh", -3, PHRED64_OFFSET).as_ref(), b"@Ae");
+/// ```
+pub fn shift_quality(qual: &[u8], shift: i8, offset: u8) -> Cow<'_, [u8]> {
+    if shift == 0 {
+        Cow::Borrowed(qual)
+    } else {
+        Cow::Owned(qual.iter().map(|&q| q.saturating_add_signed(shift).clamp(offset, MAX_QUALITY_CHAR)).collect())
+    }
+}
+
+/// Filename standing for stdout when used as an output filename.
+pub const STDOUT_FILENAME: &str = "-";
+
+/// Open an output file, or stdout if the filename is `-`.
+pub fn open_output(filename: &str) -> io::Result<Box<dyn Write>> {
+    if filename == STDOUT_FILENAME {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(File::create(filename)?))
+    }
+}
+
+/// Create a buffered output file (or stdout if the filename is `-`), which is gzipped if the filename
+/// ends with `.gz`.
+pub fn create_output(filename: &str) -> io::Result<BufWriter<Box<dyn Write>>> {
+    let file = open_output(filename)?;
+    let writer: Box<dyn Write> = if filename.ends_with(".gz") {
+        Box::new(GzEncoder::new(file, Compression::fast()))
+    } else {
+        Box::new(file)
+    };
+    Ok(BufWriter::new(writer))
+}
+
+/// The contents of a file, memory-mapped read-only so that the OS pages them in as they're used and shares
+/// them between processes, rather than copying them to the heap. If the file can't be mapped (e.g., it's
+/// empty or a pipe), it's read into memory instead.
+///
+/// The file mustn't be modified while it's mapped: on Unix, reading a part of the mapping that a truncation
+/// removed raises `SIGBUS`.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::io::MappedFile;
+/// let path = std::env::temp_dir().join("mapped_file_example.txt");
+/// fs::write(&path, "ACGT\nTTTT\n").unwrap();
+/// let contents = MappedFile::open(path.to_str().unwrap()).unwrap();
+/// assert_eq!(&contents[..], b"ACGT\nTTTT\n");
+/// fs::write(&path, "").unwrap();
+/// assert!(MappedFile::open(path.to_str().unwrap()).unwrap().is_empty());
+/// ```
+pub enum MappedFile {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl MappedFile {
+    pub fn open(filename: &str) -> io::Result<MappedFile> {
+        let mut file = File::open(filename)?;
+        let metadata = file.metadata()?;
+        if metadata.is_file() && metadata.len() > 0 {
+            // SAFETY: the mapping is read-only, and the file isn't modified while it's mapped (see above)
+            if let Ok(mapping) = unsafe { Mmap::map(&file) } {
+                return Ok(MappedFile::Mapped(mapping));
+            }
+        }
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(MappedFile::Read(contents))
+    }
+}
+
+impl std::ops::Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            MappedFile::Mapped(mapping) => mapping,
+            MappedFile::Read(contents) => contents,
+        }
+    }
+}
+
+/// A checksum shared between a reader feeding it (possibly on a decompression thread) and the reader's owner.
+pub type SharedChecksum = Arc<Mutex<Checksum>>;
+
+/// A reader that keeps count of the bytes read through it, and optionally checksums them.
+pub struct CountingReader<R> {
+    inner: R,
+    bytes_read: Arc<AtomicU64>,
+    checksum: Option<SharedChecksum>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(checksum) = &self.checksum {
+            checksum.lock().map_err(|_| io::Error::other("Checksum lock poisoned"))?.write_all(&buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+/// Number of decompressed bytes a decompression thread reads at a time.
+const DECOMPRESSION_CHUNK_SIZE: usize = 1 << 16;
+
+/// Number of decompressed chunks (or BGZF blocks) a decompression thread may get ahead of the reader by.
+const DECOMPRESSION_QUEUE_LENGTH: usize = 64;
+
+/// Number of BGZF blocks each decompression thread decompresses at a time.
+const BGZF_BLOCKS_PER_THREAD: usize = 16;
+
+/// Check whether a file starts with a BGZF block (a gzip member with a `BC` extra subfield), whose
+/// blocks can be decompressed independently.
+///
+/// # Example
+/// ```
+/// use std::fs::File;
+/// use std::io::Write;
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::{is_bgzf,BgzfWriter};
+/// let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// let mut bgzf = BgzfWriter::new(File::create(path("is_bgzf.fastq.gz")).unwrap());
+/// bgzf.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// bgzf.try_finish().unwrap();
+/// assert!(is_bgzf(&path("is_bgzf.fastq.gz")).unwrap());
+/// let mut gz = GzEncoder::new(File::create(path("is_not_bgzf.fastq.gz")).unwrap(), Compression::fast());
+/// gz.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// gz.finish().unwrap();
+/// assert!(!is_bgzf(&path("is_not_bgzf.fastq.gz")).unwrap());
+/// ```
+pub fn is_bgzf(filename: &str) -> io::Result<bool> {
+    let mut header = [0u8; 16];
+    let mut file = File::open(filename)?;
+    let mut n = 0;
+    while n < header.len() {
+        match file.read(&mut header[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n == header.len() && header[..4] == [0x1f, 0x8b, 8, 4] && header[12..16] == [b'B', b'C', 2, 0])
+}
+
+/// Read a BGZF block's compressed bytes (None at the end of the input).
+fn read_bgzf_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 12];
+    match reader.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut header[1..])?,
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid BGZF block header");
+    if header[..4] != [0x1f, 0x8b, 8, 4] {
+        return Err(invalid());
+    }
+    let mut extra = vec![0u8; u16::from_le_bytes([header[10], header[11]]) as usize];
+    reader.read_exact(&mut extra)?;
+    let remaining = bgzf_block_size(&extra).ok_or_else(invalid)?.checked_sub(header.len() + extra.len()).ok_or_else(invalid)?;
+    let mut block = vec![0u8; remaining];
+    reader.read_exact(&mut block)?;
+    Ok(Some(block))
+}
+
+/// The size of a BGZF block (including its header), from the `BC` subfield of its gzip extra field.
+fn bgzf_block_size(extra: &[u8]) -> Option<usize> {
+    let mut subfields = extra;
+    let mut block_size = None;
+    while subfields.len() >= 4 {
+        let length = u16::from_le_bytes([subfields[2], subfields[3]]) as usize;
+        if subfields[..2] == [b'B', b'C'] && length == 2 && subfields.len() >= 6 {
+            block_size = Some(u16::from_le_bytes([subfields[4], subfields[5]]) as usize + 1);
+        }
+        subfields = &subfields[cmp::min(4 + length, subfields.len())..];
+    }
+    block_size
+}
+
+/// Decompress a BGZF block's compressed bytes (and CRC32 and size footer), checking its CRC32.
+fn inflate_bgzf_block(block: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid BGZF block: {}", message));
+    let (data, footer) = block.split_at(block.len().checked_sub(8).ok_or_else(|| invalid("too short"))?);
+    let crc = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]);
+    let size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]) as usize;
+
+    let inflated = inflate(data, size)?;
+    let mut actual_crc = Crc::new();
+    actual_crc.update(&inflated);
+    if inflated.len() != size || actual_crc.sum() != crc {
+        return Err(invalid("size or CRC32 mismatch"));
+    }
+    Ok(inflated)
+}
+
+/// Decompress a raw deflate stream of (at most) `size` bytes.
+#[cfg(not(feature = "libdeflate"))]
+fn inflate(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::with_capacity(size);
+    flate2::read::DeflateDecoder::new(data).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+/// Decompress a raw deflate stream of (at most) `size` bytes.
+#[cfg(feature = "libdeflate")]
+fn inflate(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let mut inflated = vec![0u8; size];
+    let n = Decompressor::new().deflate_decompress(data, &mut inflated)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid BGZF block: {}", e)))?;
+    inflated.truncate(n);
+    Ok(inflated)
+}
+
+/// A gzip decoder that decompresses BGZF blocks with libdeflate, which is faster than flate2 but only
+/// decompresses whole buffers, so needs to know the size of a gzip member up front. That's only known for
+/// BGZF blocks, so from the first member that isn't one on, the input is decompressed by flate2's
+/// multi-member decoder.
+///
+/// # Example
+/// ```
+/// use std::io::{Read,Write};
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::{BgzfWriter,LibdeflateReader};
+/// let mut bgzf = BgzfWriter::new(Vec::new());
+/// bgzf.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// bgzf.try_finish().unwrap();
+/// // followed by a plain gzip member
+/// let mut gz = GzEncoder::new(bgzf.get_ref().clone(), Compression::fast());
+/// gz.write_all(b"@r2\nTTTT\n+\nIIII\n").unwrap();
+/// let compressed = gz.finish().unwrap();
+/// let mut fastq = String::new();
+/// LibdeflateReader::new(compressed.as_slice()).read_to_string(&mut fastq).unwrap();
+/// assert_eq!(fastq, "@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n");
+/// ```
+#[cfg(feature = "libdeflate")]
+pub struct LibdeflateReader<R: Read> {
+    compressed: Option<R>,
+    fallback: Option<MultiGzDecoder<io::Chain<io::Cursor<Vec<u8>>, R>>>,
+    block: Vec<u8>,
+    position: usize,
+}
+
+#[cfg(feature = "libdeflate")]
+impl<R: Read> LibdeflateReader<R> {
+    /// Create a decoder of the gzip stream `compressed`.
+    pub fn new(compressed: R) -> LibdeflateReader<R> {
+        LibdeflateReader {compressed: Some(compressed), fallback: None, block: Vec::new(), position: 0}
+    }
+
+    /// Decompress the next member if it's a BGZF block, or otherwise hand the rest of the input to flate2.
+    /// Returns false at the end of the input.
+    fn next_member(&mut self) -> io::Result<bool> {
+        let Some(compressed) = self.compressed.as_mut() else {
+            return Ok(false);
+        };
+        // read up to `length` bytes of the member into `member`
+        let read_to = |compressed: &mut R, member: &mut Vec<u8>, length: usize| {
+            compressed.take((length - member.len()) as u64).read_to_end(member).map(|_| member.len() == length)
+        };
+
+        let mut member = Vec::new();
+        if read_to(compressed, &mut member, 12)? && member[..4] == [0x1f, 0x8b, 8, 4] {
+            let header_size = 12 + u16::from_le_bytes([member[10], member[11]]) as usize;
+            if read_to(compressed, &mut member, header_size)? {
+                if let Some(block_size) = bgzf_block_size(&member[12..]).filter(|&size| size >= header_size) {
+                    if read_to(compressed, &mut member, block_size)? {
+                        self.block = inflate_bgzf_block(&member[header_size..])?;
+                        self.position = 0;
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        if member.is_empty() {
+            self.compressed = None;
+            return Ok(false);
+        }
+        let compressed = self.compressed.take().expect("the compressed input is only taken once");
+        self.fallback = Some(MultiGzDecoder::new(io::Cursor::new(member).chain(compressed)));
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "libdeflate")]
+impl<R: Read> Read for LibdeflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.block.len() {
+                let n = cmp::min(buf.len(), self.block.len() - self.position);
+                buf[..n].copy_from_slice(&self.block[self.position..self.position + n]);
+                self.position += n;
+                return Ok(n);
+            }
+            if let Some(fallback) = self.fallback.as_mut() {
+                return fallback.read(buf);
+            }
+            if !self.next_member()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// A reader of data decompressed on another thread, which sends it through a bounded queue (so it only gets
+/// so far ahead of the reader).
+///
+/// Dropping the reader stops the decompression thread (once it next tries to queue data) and waits for it.
+pub struct ThreadedReader {
+    receiver: Option<Receiver<io::Result<Vec<u8>>>>,
+    handle: Option<JoinHandle<()>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ThreadedReader {
+    /// Decompress a gzip stream on a thread of its own.
+    fn gzip<R: Read + Send + 'static>(compressed: R) -> ThreadedReader {
+        ThreadedReader::spawn(move |sender| {
+            let mut decoder = GzReader::new(compressed);
+            loop {
+                let mut chunk = vec![0u8; DECOMPRESSION_CHUNK_SIZE];
+                match decoder.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        if sender.send(Ok(chunk)).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Decompress a BGZF stream on `threads` threads, a batch of blocks at a time.
+    fn bgzf<R: Read + Send + 'static>(mut compressed: R, threads: usize) -> ThreadedReader {
+        ThreadedReader::spawn(move |sender| {
+            loop {
+                let mut batch = Vec::with_capacity(threads * BGZF_BLOCKS_PER_THREAD);
+                while batch.len() < threads * BGZF_BLOCKS_PER_THREAD {
+                    match read_bgzf_block(&mut compressed) {
+                        Ok(Some(block)) => batch.push(block),
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                            return;
+                        }
+                    }
+                }
+                if batch.is_empty() {
+                    return;
+                }
+
+                let inflated: Vec<io::Result<Vec<u8>>> = thread::scope(|scope| {
+                    let handles: Vec<_> = batch.chunks(BGZF_BLOCKS_PER_THREAD)
+                        .map(|blocks| scope.spawn(move || blocks.iter().map(|block| inflate_bgzf_block(block)).collect::<Vec<_>>()))
+                        .collect();
+                    handles.into_iter().flat_map(|handle| handle.join().unwrap_or_else(|_| vec![Err(io::Error::other("BGZF decompression thread panicked"))])).collect()
+                });
+                for block in inflated {
+                    let failed = block.is_err();
+                    if sender.send(block).is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    fn spawn<F: FnOnce(SyncSender<io::Result<Vec<u8>>>) + Send + 'static>(decompress: F) -> ThreadedReader {
+        let (sender, receiver) = mpsc::sync_channel(DECOMPRESSION_QUEUE_LENGTH);
+        let handle = thread::spawn(move || decompress(sender));
+        ThreadedReader {receiver: Some(receiver), handle: Some(handle), chunk: Vec::new(), position: 0}
+    }
+}
+
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.as_ref().and_then(|receiver| receiver.recv().ok()) {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        let n = cmp::min(buf.len(), self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Drop for ThreadedReader {
+    fn drop(&mut self) {
+        self.receiver.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A writer that keeps count of the bytes written through it.
+pub struct CountingWriter<W> {
+    inner: W,
+    bytes_written: Rc<Cell<u64>>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Wrap a writer, adding the number of bytes written through it to `bytes_written`.
+    pub fn new(inner: W, bytes_written: Rc<Cell<u64>>) -> CountingWriter<W> {
+        CountingWriter {inner, bytes_written}
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes_written.set(self.bytes_written.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Open a fastq file for reading, like [`open_fastq`], also returning a counter of the number of (compressed)
+/// bytes read from the file so far (e.g., for reporting progress).
+pub fn open_fastq_counted(filename: &str) -> io::Result<(FastqReader, Arc<AtomicU64>)> {
+    let (reader, bytes_read, _checksum) = open_fastq_checksummed(filename, None, 0)?;
+    Ok((reader, bytes_read))
+}
+
+/// Open a fastq file for reading, like [`open_fastq_counted`], also checksumming the (compressed) bytes as
+/// they're read if an algorithm is given, so the file needn't be read twice to verify it.
+///
+/// If `threads` is more than 0, a gzipped file is decompressed on other threads while the records are
+/// parsed: a BGZF file (see [`is_bgzf`]) on `threads` threads, each decompressing its own blocks, and any
+/// other gzip file (whose members can't be split) on a single thread. An uncompressed file is always read
+/// directly.
+///
+/// The checksum only covers the bytes read so far: once the records are read, [`finish_checksum`] checksums
+/// anything left unread (e.g., trailing bytes after the last gzip member).
+///
+/// # Example
+/// ```
+/// use std::fs::File;
+/// use std::io::Write;
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::checksum::{file_checksum,ChecksumAlgorithm};
+/// use barcodes::io::{finish_checksum,open_fastq_checksummed};
+/// let filename = std::env::temp_dir().join("open_fastq_checksummed_example.fastq.gz");
+/// let filename = filename.to_str().unwrap();
+/// let mut gz = GzEncoder::new(File::create(filename).unwrap(), Compression::fast());
+/// gz.write_all(b"@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n").unwrap();
+/// gz.finish().unwrap();
+/// let (reader, _bytes_read, checksum) = open_fastq_checksummed(filename, Some(ChecksumAlgorithm::Md5), 0).unwrap();
+/// assert_eq!(reader.records().count(), 2);
+/// let checksum = finish_checksum(checksum.unwrap(), filename).unwrap();
+/// assert_eq!(checksum, file_checksum(filename, ChecksumAlgorithm::Md5).unwrap());
+///
+/// // decompressing on other threads gives the same records, with the same checksum
+/// let (reader, bytes_read, checksum) = open_fastq_checksummed(filename, Some(ChecksumAlgorithm::Md5), 2).unwrap();
+/// assert_eq!(reader.records().count(), 2);
+/// assert_eq!(bytes_read.load(std::sync::atomic::Ordering::Relaxed), std::fs::metadata(filename).unwrap().len());
+/// assert_eq!(finish_checksum(checksum.unwrap(), filename).unwrap(), file_checksum(filename, ChecksumAlgorithm::Md5).unwrap());
+///
+/// // as does a BGZF file, with many blocks decompressed on several threads
+/// # use barcodes::io::BgzfWriter;
+/// let bgzf_filename = std::env::temp_dir().join("open_fastq_checksummed_example.bgzf.fastq.gz");
+/// let bgzf_filename = bgzf_filename.to_str().unwrap();
+/// let mut bgzf = BgzfWriter::new(File::create(bgzf_filename).unwrap());
+/// for i in 0..50000 {
+///     write!(bgzf, "@r{}\nACGTACGT\n+\nIIIIIIII\n", i).unwrap();
+/// }
+/// bgzf.try_finish().unwrap();
+/// let (reader, _bytes_read, _checksum) = open_fastq_checksummed(bgzf_filename, None, 4).unwrap();
+/// let ids: Vec<String> = reader.records().map(|r| r.unwrap().id().to_string()).collect();
+/// assert_eq!(ids.len(), 50000);
+/// assert!(ids.iter().enumerate().all(|(i, id)| *id == format!("r{}", i)));
+/// ```
+pub fn open_fastq_checksummed(filename: &str, algorithm: Option<ChecksumAlgorithm>, threads: usize) -> io::Result<(FastqReader, Arc<AtomicU64>, Option<SharedChecksum>)> {
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let checksum = algorithm.map(|algorithm| Arc::new(Mutex::new(Checksum::new(algorithm))));
+    let file = CountingReader {inner: File::open(filename)?, bytes_read: Arc::clone(&bytes_read), checksum: checksum.clone()};
+    let decompressed: Box<dyn Read> = if !is_gzipped(filename)? {
+        Box::new(file)
+    } else if threads == 0 {
+        Box::new(GzReader::new(file))
+    } else if is_bgzf(filename)? {
+        Box::new(ThreadedReader::bgzf(file, threads))
+    } else {
+        Box::new(ThreadedReader::gzip(file))
+    };
+    Ok((fastq::Reader::from_bufread(BufReader::new(decompressed)), bytes_read, checksum))
+}
+
+/// Open a fastq stream (e.g., received from another task) for reading, like [`open_fastq_counted`]. The
+/// stream is decompressed if it starts with [`GZIP_MAGIC`].
+///
+/// If `threads` is more than 0, a gzipped stream is decompressed on a single other thread: unlike a file, it
+/// can't be checked for BGZF blocks up front.
+///
+/// # Example
+/// ```
+/// use std::io::{Cursor,Write};
+/// use flate2::write::GzEncoder;
+/// use flate2::Compression;
+/// use barcodes::io::open_fastq_stream;
+/// let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+/// gz.write_all(b"@r1\nACGT\n+\nIIII\n@r2\nTTTT\n+\nIIII\n").unwrap();
+/// let compressed = gz.finish().unwrap();
+/// let length = compressed.len() as u64;
+/// for threads in [0, 1] {
+///     let (reader, bytes_read) = open_fastq_stream(Cursor::new(compressed.clone()), threads);
+///     assert_eq!(reader.records().count(), 2);
+///     assert_eq!(bytes_read.load(std::sync::atomic::Ordering::Relaxed), length);
+/// }
+/// // an uncompressed stream is read as is
+/// let (reader, _bytes_read) = open_fastq_stream(Cursor::new(b"@r1\nACGT\n+\nIIII\n".to_vec()), 1);
+/// assert_eq!(reader.records().count(), 1);
+/// ```
+pub fn open_fastq_stream<R: Read + Send + 'static>(input: R, threads: usize) -> (FastqReader, Arc<AtomicU64>) {
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let mut stream = BufReader::new(CountingReader {inner: input, bytes_read: Arc::clone(&bytes_read), checksum: None});
+    // if the stream can't be read, leave it to the decoder to report the error
+    let gzipped = stream.fill_buf().map_or(true, |start| start.starts_with(&GZIP_MAGIC));
+    let decompressed: Box<dyn Read> = if !gzipped {
+        Box::new(stream)
+    } else if threads == 0 {
+        Box::new(GzReader::new(stream))
+    } else {
+        Box::new(ThreadedReader::gzip(stream))
+    };
+    (fastq::Reader::from_bufread(BufReader::new(decompressed)), bytes_read)
+}
+
+/// Finish the checksum of a file read through [`open_fastq_checksummed`], checksumming whatever part of the
+/// file hasn't been read. Returns the checksum as lowercase hex.
+pub fn finish_checksum(checksum: SharedChecksum, filename: &str) -> io::Result<String> {
+    let mut checksum = Arc::try_unwrap(checksum).map_err(|_| io::Error::other("Checksum is still in use by its reader"))?
+        .into_inner().map_err(|_| io::Error::other("Checksum lock poisoned"))?;
+    let mut file = File::open(filename)?;
+    file.seek(io::SeekFrom::Start(checksum.len()))?;
+    io::copy(&mut file, &mut checksum)?;
+    Ok(checksum.finish())
+}
+
+/// Max number of uncompressed bytes in a BGZF block (as used by htslib, so that even incompressible data
+/// fits in a block of at most 64 KiB).
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Size of a BGZF block's header (with the `BC` extra field) and footer around its compressed data.
+const BGZF_BLOCK_OVERHEAD: usize = 18 + 8;
+
+/// The empty block that marks the end of a BGZF file.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// A writer of BGZF (blocked gzip, as used by samtools and htslib): a series of gzip members of at most
+/// 64 KiB, each with a `BC` extra field giving its size, ending with an empty [`BGZF_EOF`] block.
+///
+/// Being valid multi-member gzip, the output can also be read by any gzip reader. Like a
+/// [`GzEncoder`], the writer must be finished (see [`BgzfWriter::try_finish`]) to end the file.
+///
+/// # Example
+/// ```
+/// use std::io::{Read,Write};
+/// use flate2::read::MultiGzDecoder;
+/// use barcodes::io::{BgzfWriter,BGZF_EOF};
+/// let mut writer = BgzfWriter::new(Vec::new());
+/// writer.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+/// writer.try_finish().unwrap();
+/// let bgzf = writer.get_ref();
+/// // a gzip header with the extra flag, and the BC subfield
+/// assert_eq!(&bgzf[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+/// assert_eq!(&bgzf[12..14], b"BC");
+/// assert!(bgzf.ends_with(&BGZF_EOF));
+/// let mut fastq = String::new();
+/// MultiGzDecoder::new(bgzf.as_slice()).read_to_string(&mut fastq).unwrap();
+/// assert_eq!(fastq, "@r1\nACGT\n+\nIIII\n");
+/// ```
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Create a BGZF writer over `inner`.
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {inner, buffer: Vec::with_capacity(BGZF_BLOCK_SIZE), finished: false}
+    }
+
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Compress the buffered data (if any) into a block.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut deflated = deflate(&self.buffer, Compression::fast())?;
+        if deflated.len() > u16::MAX as usize + 1 - BGZF_BLOCK_OVERHEAD {
+            deflated = deflate(&self.buffer, Compression::none())?;
+        }
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        let block_size = (BGZF_BLOCK_OVERHEAD + deflated.len() - 1) as u16;
+        self.inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C', 0x02, 0x00])?;
+        self.inner.write_all(&block_size.to_le_bytes())?;
+        self.inner.write_all(&deflated)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write out any buffered data and the EOF block. Further calls do nothing.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.write_block()?;
+            self.inner.write_all(&BGZF_EOF)?;
+            self.finished = true;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Compress data as a raw deflate stream.
+fn deflate(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), level);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Write to a finished BGZF writer"));
+        }
+        let n = cmp::min(buf.len(), BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Write out the buffered data as a (possibly short) block, and flush the underlying writer.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FASTQ: &[u8] = b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nTTTTTTTT\n+\nIIIIIIII\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bgzip(data: &[u8]) -> Vec<u8> {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(data).unwrap();
+        writer.try_finish().unwrap();
+        writer.get_ref().clone()
+    }
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        GzReader::new(compressed).read_to_end(&mut decompressed).unwrap();
+        decompressed
+    }
+
+    /// The output doesn't depend on the decoder the crate is built with (run with and without the
+    /// `libdeflate` feature).
+    #[test]
+    fn decompressed_output_is_the_same_whatever_the_decoder() {
+        let large: Vec<u8> = FASTQ.iter().copied().cycle().take(1 << 20).collect();
+        let inputs = [
+            gzip(FASTQ),
+            [gzip(&FASTQ[..20]), gzip(&FASTQ[20..])].concat(),
+            bgzip(FASTQ),
+            [bgzip(&FASTQ[..20]), gzip(&FASTQ[20..])].concat(),
+            [gzip(&FASTQ[..20]), bgzip(&FASTQ[20..])].concat(),
+            bgzip(&large),
+            gzip(&large),
+        ];
+        for compressed in &inputs {
+            let mut expected = Vec::new();
+            MultiGzDecoder::new(compressed.as_slice()).read_to_end(&mut expected).unwrap();
+            assert_eq!(decompress(compressed), expected);
+        }
+        assert_eq!(decompress(&inputs[3]), FASTQ);
+        assert_eq!(decompress(&inputs[5]), large);
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error_whatever_the_decoder() {
+        for mut compressed in [gzip(FASTQ), bgzip(FASTQ)] {
+            let crc = compressed.len() - 8;
+            compressed[crc] ^= 0xff;
+            assert!(GzReader::new(compressed.as_slice()).read_to_end(&mut Vec::new()).is_err());
+        }
+    }
+}
@@ -1,3 +1,16 @@
 pub mod transform;
 pub mod trie;
-pub mod correct;
\ No newline at end of file
+pub mod correct;
+pub mod whitelist;
+pub mod io;
+pub mod progress;
+pub mod bam;
+pub mod error_profile;
+pub mod multiqc;
+pub mod benchmark;
+pub mod disk_counts;
+pub mod cache;
+pub mod bloom;
+pub mod checksum;
+#[cfg(feature = "async")]
+pub mod async_correct;
\ No newline at end of file
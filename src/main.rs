@@ -1,4 +1,7 @@
+use std::io::IsTerminal;
+use std::path::Path;
 use clap::{Parser,Subcommand};
+use log::{info,error};
 
 
 #[derive(Parser)]
@@ -9,6 +12,7 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)] // parsed once, so the size of the largest variant doesn't matter
 enum Commands {
     /// Extract barcodes (e.g., 10X cell barcodes) from sequencing reads. 
     /// Depending on the experimental workflow used, barcodes are sometimes embedded in reads 
@@ -37,29 +41,482 @@ enum Commands {
     /// Correct barcodes, using an algorithm similar to that employed in CellRanger's 
     /// ATAC workflow.
     CorrectBarcodes {
-        /// Input fastq file
+        /// Input fastq file (optionally gzipped)
+        #[arg(long, required_unless_present = "benchmark")]
+        fastq_in: Option<String>,
+
+        /// Output (gzipped) fastq file, or - to write to stdout (logging always goes to stderr). When
+        /// demultiplexing, only reads that aren't assigned to a barcode prefix are written here (by default,
+        /// to unassigned.fastq.gz alongside the demultiplexed outputs)
+        #[arg(long, required_unless_present_any = ["demux_prefix_length", "benchmark", "tags_only", "sweep_threshold"])]
+        fastq_out: Option<String>,
+
+        /// Barcode whitelist
+        #[arg(long, required_unless_present = "feature_whitelist")]
+        whitelist: Option<String>,
+
+        /// Whitelist of feature barcodes (e.g., the antibody or hashtag oligos of CITE-seq or cell hashing),
+        /// to correct feature barcodes rather than cell barcodes, writing them as FR:Z/FB:Z rather than
+        /// CR:Z/CB:Z tags
+        #[arg(long, value_name = "PATH", conflicts_with = "whitelist")]
+        feature_whitelist: Option<String>,
+
+        /// Barcode counts
+        #[arg(long, required_unless_present = "uniform_prior")]
+        counts: Option<String>,
+
+        /// Max Hamming distance
         #[arg(long)]
-        fastq_in: String,
+        max_distance: usize,
 
-        /// Output fastq file
+        /// Leave barcodes uncorrected if any position has a phred score below this value
+        #[arg(long, value_name = "PHRED")]
+        min_position_quality: Option<u8>,
+
+        /// Skip degenerate (IUPAC) whitelist barcodes that would expand to more than this many sequences
+        #[arg(long, default_value_t = barcodes::whitelist::DEFAULT_MAX_IUPAC_EXPANSIONS)]
+        max_iupac_expansions: usize,
+
+        /// How to handle reads whose barcode length differs from the whitelist barcode length
+        #[arg(long, value_enum, default_value_t = barcodes::correct::LengthMismatch::Warn)]
+        length_mismatch: barcodes::correct::LengthMismatch,
+
+        /// Number of correction rounds. After each round, the counts are updated with that round's
+        /// corrections, and reads that couldn't be corrected are retried in the next round
+        #[arg(long, default_value_t = 1)]
+        correction_rounds: usize,
+
+        /// Write a TSV of each corrected read's id, uncorrected barcode, corrected barcode and posterior
+        /// (gzipped if the filename ends with .gz)
         #[arg(long)]
-        fastq_out: String,
+        corrections_log: Option<String>,
 
-        /// Barcode whitelist
+        /// How barcode counts are used as the prior for correction
+        #[arg(long, value_enum, default_value_t = barcodes::correct::CountsMode::Raw)]
+        counts_mode: barcodes::correct::CountsMode,
+
+        /// Shorthand for --counts-mode frequency
         #[arg(long)]
-        whitelist: String,
+        normalize_counts: bool,
 
-        /// Barcode counts
+        /// Trim this adapter (or primer) sequence from the 5' end of barcode reads that start with it before
+        /// correction (the output record is not trimmed)
+        #[arg(long, value_name = "SEQUENCE")]
+        trim_adapter: Option<String>,
+
+        /// Max number of mismatches between the start of a barcode read and the adapter for it to be
+        /// trimmed
+        #[arg(long, default_value_t = barcodes::correct::DEFAULT_ADAPTER_MISMATCHES, requires = "trim_adapter")]
+        adapter_mismatch: usize,
+
+        /// Trim this many bases from the 5' end of the barcode read before correction
+        /// (the output record is not trimmed)
+        #[arg(long = "trim-5p", default_value_t = 0)]
+        trim_5p: usize,
+
+        /// Trim this many bases from the 3' end of the barcode read before correction
+        /// (the output record is not trimmed)
+        #[arg(long = "trim-3p", default_value_t = 0)]
+        trim_3p: usize,
+
+        /// Offset (in the trimmed barcode read) of a constant linker to remove before correction,
+        /// joining the segments on either side of it
+        #[arg(long = "linker-offset", default_value_t = 0, requires = "linker_length")]
+        linker_offset: usize,
+
+        /// Length of the linker to remove before correction
+        #[arg(long = "linker-length", default_value_t = 0)]
+        linker_length: usize,
+
+        /// Don't correct low complexity barcodes (Shannon entropy below 0.5 bits per base)
         #[arg(long)]
-        counts: String,
+        filter_low_complexity: bool,
 
-        /// Max Hamming distance
+        /// Don't correct barcodes containing a run of at least this many identical bases
+        /// (these are tagged with XF:Z:homopolymer)
+        #[arg(long, value_name = "N")]
+        max_homopolymer_run: Option<usize>,
+
+        /// Give reads whose barcode can't be corrected their uncorrected barcode as CB:Z,
+        /// rather than omitting CB:Z
+        #[arg(long)]
+        assign_raw_if_uncorrectable: bool,
+
+        /// Give mismatches at positions with a phred score below this value the probability of a
+        /// random base (0.25), rather than the probability derived from the phred score
+        #[arg(long, value_name = "PHRED")]
+        min_base_qual: Option<u8>,
+
+        /// Don't correct barcodes in which more than this fraction of bases are N
+        #[arg(long, default_value_t = 1.0)]
+        max_n_fraction: f64,
+
+        /// TSV of per-position substitution probabilities (position, probability) to use in place of
+        /// the phred-derived error probabilities
+        #[arg(long, value_name = "TSV")]
+        error_profile: Option<String>,
+
+        /// TSV of per-position, per-base error rates (position, base, error_rate) by which the
+        /// phred-derived error probabilities of mismatches at those positions and read bases are multiplied
+        #[arg(long, value_name = "TSV")]
+        error_model: Option<String>,
+
+        /// Write a gzipped TSV describing the correction decision for every read (read id, raw barcode,
+        /// corrected barcode, number of candidates, top candidate posterior, distance, filter reason)
+        #[arg(long, value_name = "PATH")]
+        audit_log: Option<String>,
+
+        /// Demultiplex reads with a corrected barcode into one output per whitelist barcode prefix of this
+        /// length
+        #[arg(long, value_name = "N")]
+        demux_prefix_length: Option<usize>,
+
+        /// Path pattern of the demultiplexed outputs ({prefix} is replaced by the barcode prefix)
+        #[arg(long, default_value = barcodes::correct::DEFAULT_DEMUX_OUTPUT_PATTERN)]
+        demux_output_pattern: String,
+
+        /// Compression format of the corrected fastq output(s)
+        #[arg(long, value_enum, default_value_t = barcodes::correct::OutputFormat::Gzip)]
+        output_format: barcodes::correct::OutputFormat,
+
+        /// Where to write the corrected barcode in the output records: as tags in the description, appended
+        /// to the read name after a # (e.g., read1#ACGTACGT), or as tags with the read replaced by the
+        /// corrected barcode and the UMI, for STARsolo's CB_UMI_Simple mode (requires --umi-length)
+        #[arg(long, value_enum, default_value_t = barcodes::correct::BarcodeOutput::Tags)]
+        barcode_output: barcodes::correct::BarcodeOutput,
+
+        /// Fastq (optionally gzipped) of sample index reads (in the same order as --fastq-in) to correct alongside
+        /// the cell barcodes
+        #[arg(long, requires_all = ["index_whitelist", "index_counts"])]
+        index_fastq: Option<String>,
+
+        /// Whitelist of sample indices
+        #[arg(long, requires = "index_fastq")]
+        index_whitelist: Option<String>,
+
+        /// TSV of sample index counts
+        #[arg(long, requires = "index_fastq")]
+        index_counts: Option<String>,
+
+        /// Name of the tag holding the corrected sample index
+        #[arg(long, default_value = "SI")]
+        index_tag: String,
+
+        /// Max Hamming distance between an uncorrected sample index and its correction
+        #[arg(long, default_value_t = 1)]
+        index_max_distance: usize,
+
+        /// Leave reads whose sample index can't be corrected out of the output, rather than tagging them
+        /// with XU:Z:index
+        #[arg(long)]
+        exclude_uncorrectable_index: bool,
+
+        /// Assume the whitelist is sorted, so it can be loaded faster (an out-of-order whitelist is an
+        /// error)
+        #[arg(long)]
+        assume_sorted_whitelist: bool,
+
+        /// Offset of the UMI in the barcode read
+        #[arg(long, default_value_t = 0, requires = "umi_length")]
+        umi_offset: usize,
+
+        /// Length of the UMI in the barcode read; if given, the UMI and its quality are written as
+        /// UR:Z and UY:Z tags
+        #[arg(long)]
+        umi_length: Option<usize>,
+
+        /// Read the barcode from the last field of the read name, split on this delimiter (e.g., _ for
+        /// read1_ACGTACGT), and write the corrected barcode back into the name rather than as tags
+        #[arg(long, value_name = "DELIMITER")]
+        barcode_in_read_name: Option<char>,
+
+        /// Write the final statistics to this path as JSON
+        #[arg(long, value_name = "PATH")]
+        stats_json: Option<String>,
+
+        /// Concentration of the Dirichlet prior over whitelisted barcodes, i.e., the pseudocount added to each
+        /// one's count: above 1 smooths the prior toward uniform, reducing overcorrection when sequencing is
+        /// sparse, and below 1 concentrates it on barcodes with high counts
+        #[arg(long, value_name = "ALPHA", default_value_t = barcodes::correct::DEFAULT_DIRICHLET_CONCENTRATION)]
+        dirichlet_concentration: f64,
+
+        /// Give every whitelisted barcode the same prior (ignoring --counts, which needn't be given), so that
+        /// corrections are chosen by the likelihood of the errors alone
+        #[arg(long, conflicts_with = "correction_rounds")]
+        uniform_prior: bool,
+
+        /// Prior weight (relative to the pseudocount given to each whitelisted barcode) of leaving a
+        /// barcode with a single correction candidate uncorrected
+        #[arg(long, default_value_t = barcodes::correct::DEFAULT_UNCORRECTED_PRIOR)]
+        uncorrected_prior: f64,
+
+        /// Prior probability that a read's true barcode isn't in the whitelist, weighed against every
+        /// correction (in place of --uncorrected-prior)
+        #[arg(long, value_name = "P")]
+        unmatched_prior: Option<f64>,
+
+        /// Only accept a correction if its likelihood times count is at least this many times the
+        /// runner-up candidate's (as well as passing the posterior threshold)
+        #[arg(long, value_name = "RATIO")]
+        min_likelihood_ratio: Option<f64>,
+
+        /// Write the final statistics as MultiQC custom content to this path (a bar graph of correction
+        /// outcomes), with the correction distance histogram (a line graph) alongside it
+        #[arg(long, value_name = "PATH")]
+        multiqc_output: Option<String>,
+
+        /// Write a TSV of each read's barcode tags (read_id, CR, CB, CY) to this path (gzipped if it ends
+        /// with .gz)
+        #[arg(long, value_name = "PATH")]
+        tags_tsv: Option<String>,
+
+        /// Only write the --tags-tsv, not the corrected fastq
+        #[arg(long, requires = "tags_tsv", conflicts_with_all = ["fastq_out", "demux_prefix_length"])]
+        tags_only: bool,
+
+        /// TSV of the costs of substituting each base (rows: read base, columns: whitelist base; both in ACGT
+        /// order). If given, correction candidates are those within a total substitution cost of
+        /// --max-distance, rather than within that many mismatches
+        #[arg(long, value_name = "PATH")]
+        substitution_costs: Option<String>,
+
+        /// Skip (and count) malformed fastq records, rather than stopping with an error
+        #[arg(long)]
+        skip_errors: bool,
+
+        /// For simulated reads, the tag (e.g., GT, for GT:Z:<barcode>) in the input fastq descriptions holding
+        /// each read's true barcode; the precision, recall and F1 of the assigned barcodes are reported
+        #[arg(long, value_name = "TAG")]
+        ground_truth_tag: Option<String>,
+
+        /// Instead of correcting barcodes, write a TSV (to this path, or - for stdout) of how many reads, and
+        /// unique barcodes, would be corrected at each of a range of posterior thresholds
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["fastq_out", "benchmark"])]
+        sweep_threshold: Option<String>,
+
+        /// Reverse complement barcodes (after trimming) before matching them against the whitelist, e.g., if
+        /// the barcode read is on the opposite strand. CR and CY are given in this orientation
+        #[arg(long)]
+        revcomp: bool,
+
+        /// Check that every output description is a strictly tab-separated block of SAM tags without spaces
+        /// (as expected by samtools import -T or an aligner's comment passthrough), stopping with an error if
+        /// not
+        #[arg(long)]
+        strict_sam_tags: bool,
+
+        /// If the counts file is larger than this many bytes, keep the counts in a sorted table on disk rather
+        /// than in memory, using at most about this much memory to build it (not compatible with
+        /// --correction-rounds above 1)
+        #[arg(long, value_name = "BYTES")]
+        max_counts_memory: Option<usize>,
+
+        /// Max number of uncorrected barcodes (with their base qualities) whose correction is cached, so
+        /// that repeated barcodes skip the whitelist search (0 disables the cache)
+        #[arg(long, default_value_t = barcodes::correct::DEFAULT_CORRECTION_CACHE_SIZE)]
+        correction_cache_size: usize,
+
+        /// Read the barcodes from this column of a tab-separated whitelist (a 1-based column number, or a
+        /// column name, which implies --whitelist-header); lines starting with # are skipped
+        #[arg(long, value_name = "COLUMN")]
+        whitelist_column: Option<barcodes::whitelist::WhitelistColumn>,
+
+        /// The first (non-comment) line of the tab-separated whitelist is a header
+        #[arg(long, requires = "whitelist_column")]
+        whitelist_header: bool,
+
+        /// Test each barcode against a Bloom filter of the whitelist, rather than a hash set, before
+        /// looking for an exact match in the whitelist trie
+        #[arg(long)]
+        use_bloom_filter: bool,
+
+        /// False positive rate of the whitelist Bloom filter
+        #[arg(long, default_value_t = 0.01, requires = "use_bloom_filter")]
+        bloom_false_positive_rate: f64,
+
+        /// Only correct (and write) a random fraction of the records, e.g., to quickly estimate the correction
+        /// rate
+        #[arg(long, value_name = "FRACTION")]
+        subsample: Option<f64>,
+
+        /// Seed of the random number generator used for subsampling
+        #[arg(long, default_value_t = barcodes::correct::DEFAULT_SUBSAMPLE_SEED, requires = "subsample")]
+        seed: u64,
+
+        /// Encoding of the phred scores in the input quality strings (auto detects it from the first
+        /// records)
+        #[arg(long, value_enum, default_value_t = barcodes::io::PhredEncoding::Phred33)]
+        phred_encoding: barcodes::io::PhredEncoding,
+
+        /// File of barcodes (one per line, e.g., of spike-in controls) to assign as-is, even though they
+        /// aren't whitelisted
+        #[arg(long, value_name = "PATH")]
+        force_accept: Option<String>,
+
+        /// TSV of the spatial coordinates of whitelisted barcodes (columns: barcode, x, y), to tag reads
+        /// with the coordinates of their barcode as SPATIAL:Z:<x>_<y> (or SPATIAL:Z:NA)
+        #[arg(long, value_name = "TSV", conflicts_with = "strict_sam_tags")]
+        spatial_coords: Option<String>,
+
+        /// Flush the fastq output(s) every this many records, so that a killed job leaves output that can be
+        /// decompressed up to the last flush (frequent flushes make the output larger)
+        #[arg(long, value_name = "RECORDS")]
+        flush_interval: Option<usize>,
+
+        /// Expected checksum (as hex) of the input fastq, which is checksummed as it's read; if it differs,
+        /// correction fails once the input has been read, without finishing the output
+        #[arg(long, value_name = "HEX")]
+        expected_checksum: Option<String>,
+
+        /// Hash function of --expected-checksum
+        #[arg(long, value_enum, default_value_t = barcodes::checksum::ChecksumAlgorithm::Md5)]
+        checksum_algorithm: barcodes::checksum::ChecksumAlgorithm,
+
+        /// Shift every phred score of the CY:Z tag and the output quality strings by this much (saturating
+        /// at 0 and at the highest encodable score)
+        #[arg(long, value_name = "PHRED", default_value_t = 0, allow_negative_numbers = true)]
+        qual_shift: i8,
+
+        /// Also weigh corrections with the shifted qualities, rather than the original ones
+        #[arg(long, requires = "qual_shift")]
+        qual_shift_likelihood: bool,
+
+        /// Only correct the first N records (after --subsample, if given), e.g., for quick parameter sweeps
+        #[arg(long, value_name = "N")]
+        sample_first_n: Option<usize>,
+
+        /// Decompress the input fastq on other threads while correcting it: a BGZF input on this many threads,
+        /// and any other gzip input on one (0 decompresses it as it's read)
+        #[arg(long, value_name = "THREADS", default_value_t = 0)]
+        decompression_threads: usize,
+
+        /// Copy the input records to the output untouched, without correcting or tagging them (e.g., to time
+        /// the I/O alone, or to diff against a tagged run)
+        #[arg(long)]
+        passthrough: bool,
+
+        /// Tag each record whose barcode was searched for a correction with the number of candidate
+        /// corrections found in the whitelist (e.g., XN, for XN:i:<n>), whether or not it was corrected
+        #[arg(long, value_name = "TAG")]
+        neighbor_count_tag: Option<String>,
+
+        /// Precompute the whitelisted neighbors of every sequence within --max-distance of the whitelist, so
+        /// that correcting a barcode is a lookup rather than a trie search (this takes a lot of memory)
+        #[arg(long, conflicts_with = "substitution_costs")]
+        precompute_neighborhoods: bool,
+
+        /// Write a gzipped TSV of the best correction of every read searched for one, and its posterior
+        /// probability, whether or not it was accepted (e.g., to post-filter with another threshold)
+        #[arg(long, value_name = "PATH")]
+        posterior_output: Option<String>,
+
+        /// How correction candidates are weighed: by the product of the error probabilities of their
+        /// mismatched bases, or as a multinomial draw weighted by exp(-sum of their mismatches' phred scores)
+        #[arg(long, value_enum, default_value_t = barcodes::correct::CorrectionModel::Product)]
+        correction_model: barcodes::correct::CorrectionModel,
+
+        /// Don't draw a progress bar (one is drawn by default when stderr is a terminal)
+        #[arg(long)]
+        no_progress: bool,
+
+        /// Instead of correcting --fastq-in, correct N synthetic reads with barcodes drawn from the
+        /// whitelist (80% exact matches, 15% one substitution away, 5% random), discard the output and
+        /// report the throughput
+        #[arg(long, value_name = "N", conflicts_with_all = ["fastq_in", "fastq_out"])]
+        benchmark: Option<usize>,
+    },
+    /// Merge several (optionally gzipped) barcode whitelists into a single sorted, deduplicated,
+    /// gzipped whitelist.
+    MergeWhitelists {
+        /// Input whitelists
+        #[arg(required = true)]
+        whitelists: Vec<String>,
+
+        /// Output (gzipped) whitelist
         #[arg(long)]
+        output: String,
+    },
+    /// Write the barcodes found in both of two (optionally gzipped) whitelists.
+    WhitelistIntersect {
+        /// First whitelist
+        list1: String,
+
+        /// Second whitelist
+        list2: String,
+
+        /// Output whitelist (gzipped if the filename ends with .gz)
+        #[arg(long)]
+        output: String,
+    },
+    /// Write the barcodes found in only one of two (optionally gzipped) whitelists.
+    WhitelistDiff {
+        /// First whitelist
+        list1: String,
+
+        /// Second whitelist
+        list2: String,
+
+        /// Output whitelist (gzipped if the filename ends with .gz)
+        #[arg(long)]
+        output: String,
+    },
+    /// Write the nucleotide frequencies and Shannon entropy of each position across the barcodes of a
+    /// whitelist, warning about low diversity positions (below 1 bit).
+    WhitelistStats {
+        /// Whitelist (optionally gzipped)
+        whitelist: String,
+
+        /// Output TSV, or - for stdout
+        #[arg(long, default_value = "-")]
+        output: String,
+    },
+    /// Write every pair of barcodes in a whitelist within a Hamming distance of each other, with their
+    /// distance, as a TSV.
+    WhitelistDistanceMatrix {
+        /// Whitelist (optionally gzipped)
+        whitelist: String,
+
+        /// Max Hamming distance between the barcodes of a pair
+        #[arg(long, default_value_t = 2)]
         max_distance: usize,
+
+        /// Output TSV (gzipped if the filename ends with .gz), or - for stdout
+        #[arg(long)]
+        output: String,
+    },
+    /// Write a BED4 fragment file (chrom, start, end, barcode) for snATAC-seq from a BAM file of aligned
+    /// read pairs carrying corrected barcodes (CB:Z tags).
+    BamToFragments {
+        /// Input BAM file
+        #[arg(long)]
+        bam: String,
+
+        /// Output fragment file (gzipped if the filename ends with .gz)
+        #[arg(long)]
+        fragments: String,
+    },
+    /// Print the checksum of a file (as md5sum or sha256sum would), e.g., to record the expected checksum of
+    /// an input fastq.
+    Checksum {
+        /// Input file
+        file: String,
+
+        /// Hash function
+        #[arg(long, value_enum, default_value_t = barcodes::checksum::ChecksumAlgorithm::Md5)]
+        algorithm: barcodes::checksum::ChecksumAlgorithm,
     }
 }
 
 
+fn compare_whitelists(list1: &str, list2: &str, output: &str, operation: barcodes::whitelist::SetOperation) {
+    match barcodes::whitelist::compare_whitelists(list1, list2, output, operation) {
+        Ok((_, _, n)) => info!("Wrote {} barcodes to {}", n, output),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
 fn main() {
 
@@ -72,8 +529,218 @@ fn main() {
         Some(Commands::ParseBarcodes {fastq_in, fastq_out, whitelist, counts}) => {
             barcodes::transform::transform_fastq_file(fastq_in, whitelist, fastq_out, 10000, counts);
         },
-        Some(Commands::CorrectBarcodes {fastq_in, fastq_out, whitelist, counts, max_distance}) => {
-            barcodes::correct::correct_barcodes_in_fastq(fastq_in, whitelist, counts, fastq_out, *max_distance);
+        Some(Commands::CorrectBarcodes {fastq_in, fastq_out, whitelist, counts, max_distance, min_position_quality, max_iupac_expansions, length_mismatch, correction_rounds, corrections_log, counts_mode, normalize_counts, trim_adapter, adapter_mismatch, trim_5p, trim_3p, linker_offset, linker_length, filter_low_complexity, max_homopolymer_run, assign_raw_if_uncorrectable, min_base_qual, max_n_fraction, error_profile, error_model, audit_log, demux_prefix_length, demux_output_pattern, output_format, barcode_output, index_fastq, index_whitelist, index_counts, index_tag, index_max_distance, exclude_uncorrectable_index, assume_sorted_whitelist, umi_offset, umi_length, barcode_in_read_name, stats_json, dirichlet_concentration, uniform_prior, uncorrected_prior, unmatched_prior, min_likelihood_ratio, multiqc_output, tags_tsv, tags_only, substitution_costs, skip_errors, ground_truth_tag, sweep_threshold, revcomp, strict_sam_tags, max_counts_memory, correction_cache_size, whitelist_column, whitelist_header, use_bloom_filter, bloom_false_positive_rate, subsample, seed, phred_encoding, force_accept, feature_whitelist, spatial_coords, flush_interval, expected_checksum, checksum_algorithm, qual_shift, qual_shift_likelihood, sample_first_n, decompression_threads, passthrough, neighbor_count_tag, precompute_neighborhoods, posterior_output, correction_model, no_progress, benchmark}) => {
+            // with a uniform prior, the counts aren't read
+            let counts = counts.as_deref().unwrap_or_default();
+            let error_profile = match error_profile.as_deref().map(barcodes::error_profile::read_error_profile).transpose() {
+                Ok(profile) => profile,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let error_model = match error_model.as_deref().map(barcodes::error_profile::read_error_model).transpose() {
+                Ok(model) => model,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let substitution_costs = match substitution_costs.as_deref().map(barcodes::trie::read_substitution_costs).transpose() {
+                Ok(costs) => costs,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            // a feature whitelist takes the place of the (cell barcode) whitelist
+            let whitelist = whitelist.as_ref().or(feature_whitelist.as_ref()).unwrap();
+            let spatial_coords = match spatial_coords.as_deref().map(barcodes::whitelist::read_spatial_coords).transpose() {
+                Ok(coords) => coords,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let force_accept = match force_accept.as_deref().map(barcodes::whitelist::read_whitelist).transpose() {
+                Ok(barcodes) => barcodes.map(|barcodes| barcodes.into_iter().collect()),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let phred_offset = match (phred_encoding, fastq_in) {
+                (barcodes::io::PhredEncoding::Phred64, _) => barcodes::io::PHRED64_OFFSET,
+                (barcodes::io::PhredEncoding::Auto, Some(fastq_in)) => match barcodes::io::detect_phred_offset(fastq_in, barcodes::io::PHRED_DETECTION_RECORDS) {
+                    Ok(offset) => {
+                        info!("Detected phred+{} quality encoding", offset);
+                        offset
+                    },
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                },
+                _ => barcodes::io::PHRED33_OFFSET,
+            };
+            let config = barcodes::correct::CorrectionConfig {
+                max_edit_distance: *max_distance,
+                min_position_quality: *min_position_quality,
+                max_iupac_expansions: *max_iupac_expansions,
+                length_mismatch: *length_mismatch,
+                correction_rounds: *correction_rounds,
+                corrections_log_path: corrections_log.clone(),
+                counts_mode: if *normalize_counts { barcodes::correct::CountsMode::Frequency } else { *counts_mode },
+                adapter: trim_adapter.as_ref().map(|adapter| adapter.to_ascii_uppercase().into_bytes()),
+                adapter_mismatches: *adapter_mismatch,
+                trim_5p: *trim_5p,
+                trim_3p: *trim_3p,
+                linker_offset: *linker_offset,
+                linker_length: *linker_length,
+                filter_low_complexity: *filter_low_complexity,
+                max_homopolymer_run: *max_homopolymer_run,
+                assign_raw_if_uncorrectable: *assign_raw_if_uncorrectable,
+                min_base_qual: *min_base_qual,
+                max_n_fraction: *max_n_fraction,
+                error_profile,
+                error_model,
+                audit_log_path: audit_log.clone(),
+                demux_prefix_length: *demux_prefix_length,
+                demux_output_pattern: demux_output_pattern.clone(),
+                output_format: *output_format,
+                barcode_output: *barcode_output,
+                index: index_fastq.as_ref().map(|fastq_path| barcodes::correct::IndexConfig {
+                    fastq_path: fastq_path.clone(),
+                    whitelist_path: index_whitelist.clone().unwrap(),
+                    counts_path: index_counts.clone().unwrap(),
+                    tag: index_tag.clone(),
+                    max_edit_distance: *index_max_distance,
+                    exclude_uncorrectable: *exclude_uncorrectable_index,
+                }),
+                assume_sorted_whitelist: *assume_sorted_whitelist,
+                umi_offset: *umi_offset,
+                umi_length: *umi_length,
+                read_name_delimiter: *barcode_in_read_name,
+                stats_json_path: stats_json.clone(),
+                dirichlet_concentration: *dirichlet_concentration,
+                uniform_prior: *uniform_prior,
+                uncorrected_prior: *uncorrected_prior,
+                unmatched_prior: *unmatched_prior,
+                min_likelihood_ratio: *min_likelihood_ratio,
+                multiqc_path: multiqc_output.clone(),
+                tags_tsv_path: tags_tsv.clone(),
+                write_fastq: !*tags_only,
+                substitution_costs,
+                skip_errors: *skip_errors,
+                ground_truth_tag: ground_truth_tag.clone(),
+                revcomp: *revcomp,
+                strict_sam_tags: *strict_sam_tags,
+                max_counts_memory: *max_counts_memory,
+                correction_cache_size: *correction_cache_size,
+                whitelist_column: whitelist_column.clone(),
+                whitelist_header: *whitelist_header,
+                bloom_false_positive_rate: use_bloom_filter.then_some(*bloom_false_positive_rate),
+                subsample: *subsample,
+                subsample_seed: *seed,
+                phred_offset,
+                force_accept,
+                feature_barcodes: feature_whitelist.is_some(),
+                spatial_coords,
+                flush_interval: *flush_interval,
+                expected_checksum: expected_checksum.clone(),
+                checksum_algorithm: *checksum_algorithm,
+                qual_shift: *qual_shift,
+                qual_shift_likelihood: *qual_shift_likelihood,
+                max_records: *sample_first_n,
+                decompression_threads: *decompression_threads,
+                passthrough: *passthrough,
+                neighbor_count_tag: neighbor_count_tag.clone(),
+                precompute_neighborhoods: *precompute_neighborhoods,
+                posterior_output_path: posterior_output.clone(),
+                correction_model: *correction_model,
+                progress: !*no_progress && std::io::stderr().is_terminal(),
+                ..Default::default()
+            };
+            if let Some(n) = benchmark {
+                match barcodes::benchmark::benchmark(*n, whitelist, counts, &config) {
+                    Ok(result) => info!("Corrected {} synthetic reads in {:.3}s: {:.0} records/s, {:.2} MB/s", result.records, result.elapsed.as_secs_f64(), result.records_per_second(), result.megabytes_per_second()),
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            let fastq_in = fastq_in.as_deref().unwrap();
+            if let Some(path) = sweep_threshold {
+                let sweep = barcodes::correct::sweep_thresholds(fastq_in, whitelist, counts, &barcodes::correct::SWEEP_THRESHOLDS, &config);
+                if let Err(e) = sweep.and_then(|sweep| barcodes::correct::write_threshold_sweep(&sweep, path)) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if *tags_only {
+                if let Err(e) = barcodes::correct::correct_barcodes(fastq_in, whitelist, counts, std::io::sink(), &config) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            let fastq_out = fastq_out.clone().unwrap_or_else(|| {
+                let demux_dir = Path::new(demux_output_pattern).parent().unwrap_or(Path::new(""));
+                demux_dir.join("unassigned.fastq.gz").to_string_lossy().to_string()
+            });
+            if let Err(e) = barcodes::correct::correct_barcodes_in_fastq(fastq_in, whitelist, counts, &fastq_out, &config) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Commands::MergeWhitelists {whitelists, output}) => {
+            let whitelists: Vec<&str> = whitelists.iter().map(|s| s.as_str()).collect();
+            match barcodes::whitelist::merge_whitelists(&whitelists, output) {
+                Ok(n) => info!("Wrote {} unique barcodes to {}", n, output),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(Commands::WhitelistIntersect {list1, list2, output}) => {
+            compare_whitelists(list1, list2, output, barcodes::whitelist::SetOperation::Intersection);
+        },
+        Some(Commands::WhitelistDiff {list1, list2, output}) => {
+            compare_whitelists(list1, list2, output, barcodes::whitelist::SetOperation::SymmetricDifference);
+        },
+        Some(Commands::WhitelistStats {whitelist, output}) => {
+            if let Err(e) = barcodes::whitelist::write_position_stats(whitelist, output) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Commands::WhitelistDistanceMatrix {whitelist, max_distance, output}) => {
+            match barcodes::whitelist::write_distance_matrix(whitelist, *max_distance, output) {
+                Ok(n) => info!("Wrote {} pairs of barcodes within distance {} to {}", n, max_distance, output),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Some(Commands::BamToFragments {bam, fragments}) => {
+            if let Err(e) = barcodes::bam::bam_to_fragments(bam, fragments) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Commands::Checksum {file, algorithm}) => {
+            match barcodes::checksum::file_checksum(file, *algorithm) {
+                Ok(checksum) => println!("{}  {}", checksum, file),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
         },
         None => {}
     }
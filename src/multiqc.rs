@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use itertools::Itertools;
+use crate::correct::CorrectionStats;
+
+/// Quote and escape a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Path of the distance histogram section, alongside the main MultiQC output (keeping a `_mqc.json` suffix,
+/// which MultiQC looks for).
+///
+/// # Example
+/// ```
+/// use barcodes::multiqc::distance_section_path;
+/// assert_eq!(distance_section_path("qc/sample_mqc.json"), "qc/sample_distance_mqc.json");
+/// assert_eq!(distance_section_path("qc/sample.json"), "qc/sample_distance.json");
+/// ```
+pub fn distance_section_path(path: &str) -> String {
+    for suffix in ["_mqc.json", ".json"] {
+        if let Some(stem) = path.strip_suffix(suffix) {
+            return format!("{}_distance{}", stem, suffix);
+        }
+    }
+    format!("{}_distance", path)
+}
+
+/// Sample name for MultiQC, from the name of the input fastq file (without its directory or extensions).
+pub fn sample_name(fastq_filename: &str) -> String {
+    let name = fastq_filename.rsplit('/').next().unwrap_or(fastq_filename);
+    ["fastq.gz", "fq.gz", "fastq", "fq"].iter()
+        .find_map(|extension| name.strip_suffix(extension).and_then(|n| n.strip_suffix('.')))
+        .unwrap_or(name)
+        .to_string()
+}
+
+/// Write barcode correction statistics as MultiQC custom content.
+///
+/// MultiQC reads one section per file, so the read counts by correction outcome (a bar graph) are written
+/// to `path`, and the number of corrections at each Hamming distance (a line graph) to
+/// [`distance_section_path`]`(path)`.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use std::collections::HashMap;
+/// use barcodes::correct::CorrectionStats;
+/// use barcodes::multiqc::{write_multiqc,distance_section_path};
+/// let path = std::env::temp_dir().join("write_multiqc_mqc.json").to_str().unwrap().to_string();
+/// let stats = CorrectionStats {
+///     total: 10,
+///     matched_whitelist_before_correction: 6,
+///     matched_whitelist_after_correction: 9,
+///     corrections_at_distance: HashMap::from([(1, 2), (2, 1)]),
+///     ..Default::default()
+/// };
+/// write_multiqc(&stats, "sample1", &path).unwrap();
+/// let bargraph = fs::read_to_string(&path).unwrap();
+/// assert!(bargraph.contains("\"plot_type\": \"bargraph\""));
+/// assert!(bargraph.contains("\"sample1\": {\"whitelisted\": 6, \"corrected\": 3, \"uncorrected\": 1}"));
+/// let linegraph = fs::read_to_string(distance_section_path(&path)).unwrap();
+/// assert!(linegraph.contains("\"plot_type\": \"linegraph\""));
+/// assert!(linegraph.contains("\"sample1\": {\"1\": 2, \"2\": 1}"));
+/// ```
+pub fn write_multiqc(stats: &CorrectionStats, sample: &str, path: &str) -> io::Result<()> {
+    let corrected = stats.matched_whitelist_after_correction - stats.matched_whitelist_before_correction;
+    let uncorrected = stats.total - stats.matched_whitelist_after_correction;
+    let bargraph = format!(
+        "{{\n  \"id\": \"barcode_correction\",\n  \"section_name\": \"Barcode correction\",\n  \"description\": \"Reads by whether their barcode was whitelisted as read, corrected to a whitelisted barcode, or left uncorrected.\",\n  \"plot_type\": \"bargraph\",\n  \"pconfig\": {{\"id\": \"barcode_correction_bargraph\", \"title\": \"Barcode correction\", \"ylab\": \"Reads\"}},\n  \"data\": {{\n    {}: {{\"whitelisted\": {}, \"corrected\": {}, \"uncorrected\": {}}}\n  }}\n}}\n",
+        json_string(sample), stats.matched_whitelist_before_correction, corrected, uncorrected
+    );
+    fs::write(path, bargraph)?;
+
+    let distances: Vec<String> = stats.corrections_at_distance.iter().sorted().map(|(distance, n)| format!("\"{}\": {}", distance, n)).collect();
+    let linegraph = format!(
+        "{{\n  \"id\": \"barcode_correction_distance\",\n  \"section_name\": \"Barcode correction distance\",\n  \"description\": \"Number of reads corrected at each Hamming distance.\",\n  \"plot_type\": \"linegraph\",\n  \"pconfig\": {{\"id\": \"barcode_correction_distance_linegraph\", \"title\": \"Barcode correction distance\", \"xlab\": \"Hamming distance\", \"ylab\": \"Reads\"}},\n  \"data\": {{\n    {}: {{{}}}\n  }}\n}}\n",
+        json_string(sample), distances.join(", ")
+    );
+    fs::write(distance_section_path(path), linegraph)
+}
@@ -0,0 +1,70 @@
+use indicatif::ProgressStyle;
+
+/// Template of the progress bar, showing the records processed out of the (estimated) total.
+const TEMPLATE: &str = "[{bar:40}] {percent:>3}% {human_pos}/{human_len} records ({eta} left)";
+
+/// A progress bar, drawn on stderr (if it's a terminal), showing the number of records processed out of the
+/// total number of records in the input.
+///
+/// The total is either known up front (e.g., counted in a pre-pass over an uncompressed input), or
+/// estimated as the input is read, from the number of records in the compressed bytes read so far (see
+/// [`estimate_total_records`]).
+pub struct ProgressBar {
+    bar: indicatif::ProgressBar,
+    /// Size of the compressed input, if the total number of records is estimated from it
+    total_bytes: Option<u64>,
+}
+
+impl ProgressBar {
+
+    /// Create a progress bar for an input of `total_records` records.
+    pub fn with_records(total_records: u64) -> ProgressBar {
+        ProgressBar {bar: new_bar(total_records), total_bytes: None}
+    }
+
+    /// Create a progress bar for a compressed input of `total_bytes` bytes, estimating its total number of
+    /// records as it's read.
+    pub fn with_compressed_bytes(total_bytes: u64) -> ProgressBar {
+        ProgressBar {bar: new_bar(0), total_bytes: Some(total_bytes)}
+    }
+
+    /// Update the progress bar, given the number of (compressed) bytes read and records processed so far.
+    pub fn update(&self, bytes_read: u64, records: usize) {
+        if let Some(total_bytes) = self.total_bytes {
+            self.bar.set_length(estimate_total_records(records as u64, bytes_read, total_bytes));
+        }
+        self.bar.set_position(records as u64);
+    }
+
+    /// Complete the progress bar, now that all `records` have been processed.
+    pub fn finish(&self, records: usize) {
+        self.bar.set_length(records as u64);
+        self.bar.set_position(records as u64);
+        self.bar.finish();
+    }
+}
+
+/// Create an indicatif progress bar of `length` records in our style.
+fn new_bar(length: u64) -> indicatif::ProgressBar {
+    let style = ProgressStyle::with_template(TEMPLATE).expect("the progress bar template is valid").progress_chars("=> ");
+    indicatif::ProgressBar::new(length).with_style(style)
+}
+
+/// Estimate the total number of records in a compressed input of `total_bytes` bytes, given that the first
+/// `bytes_read` bytes held `records` records. The estimate is never less than the records already seen.
+///
+/// # Example
+/// ```
+/// use barcodes::progress::estimate_total_records;
+/// assert_eq!(estimate_total_records(1000, 250, 1000), 4000);
+/// assert_eq!(estimate_total_records(1000, 1000, 1000), 1000);
+/// // nothing read yet, so no estimate
+/// assert_eq!(estimate_total_records(0, 0, 1000), 0);
+/// ```
+pub fn estimate_total_records(records: u64, bytes_read: u64, total_bytes: u64) -> u64 {
+    if bytes_read == 0 {
+        return records;
+    }
+    let estimate = (records as f64 * total_bytes as f64 / bytes_read as f64).round() as u64;
+    estimate.max(records)
+}
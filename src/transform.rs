@@ -1,14 +1,14 @@
 use std::fs::File;
-use std::io::{Read,Write,BufReader,BufWriter};
+use std::io::{Write,BufWriter};
 use std::collections::{HashSet,HashMap};
 use std::fmt;
 use log::info;
-use itertools::Itertools;
-use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use bio::io::fastq;
 use bio::alphabets::dna::revcomp;
+use crate::io::open_fastq;
+use crate::whitelist::{read_whitelist,expand_whitelist,barcode_length,DEFAULT_MAX_IUPAC_EXPANSIONS};
 
 /// Definition of the transformation needed to get a barcode out of a fastq sequence.
 ///
@@ -37,7 +37,7 @@ impl fmt::Display for Transform {
 /// use barcodes::transform::Transform;
 /// use barcodes::transform::transform_record;
 /// let record = Record::with_attrs("read_name", Some("read_description"), b"CATGATGTTTTT", b"FAFFFFFFFFFF");
-/// let transform_params = transform {trim_from_start: 1, trim_from_end: 5, reverse_complement: false};
+/// let transform_params = Transform {trim_from_start: 1, trim_from_end: 5, reverse_complement: false};
 /// let transformed_record = transform_record(&record, &transform_params);
 /// assert_eq!(transformed_record, Record::with_attrs("read_name", Some("read_description"), b"ATGATG", b"AFFFFF"));
 /// ```
@@ -78,21 +78,16 @@ pub fn transform_record (record: &fastq::Record, params: &Transform) -> fastq::R
 fn infer_transform (fastq_filename: &str, whitelist_filename: &str, check_n_records: usize) -> Transform {
 
     // read the whitelist
-    let mut whitelist_file = File::open(whitelist_filename).unwrap();
-    let mut whitelist: String = String::new();
-    whitelist_file.read_to_string(&mut whitelist).unwrap();
-    let whitelist: HashSet<&[u8]> = whitelist.split("\n").map(|s| s.trim_end().as_bytes()).collect();
+    let whitelist = expand_whitelist(read_whitelist(whitelist_filename).unwrap(), DEFAULT_MAX_IUPAC_EXPANSIONS);
     
     // infer the expected barcode length
-    let barcode_lengths: Vec<usize> = whitelist.iter().map(|s| s.len()).dedup().collect();
-    assert_eq!(barcode_lengths.len(), 1);
-    let barcode_length = barcode_lengths[0];
+    let barcode_length = barcode_length(&whitelist).unwrap();
+    let whitelist: HashSet<&[u8]> = whitelist.iter().map(|s| s.as_ref()).collect();
 
     // read the first check_n_records records of the fastq file
     let mut transform_counts: Vec<(Transform, usize)> = Vec::new(); // this will store all the possible transforms that would yield a barcode of the correct length
 
-    let fastq = BufReader::new(GzDecoder::new(File::open(fastq_filename).unwrap()));
-    let fastq_reader = fastq::Reader::from_bufread(fastq);
+    let fastq_reader = open_fastq(fastq_filename).unwrap();
     let mut checked: usize = 0;
 
     for (i, result) in fastq_reader.records().enumerate() {
@@ -147,13 +142,10 @@ pub fn transform_fastq_file (input_fastq_filename: &str, whitelist_filename: &st
     let transform_params = infer_transform(input_fastq_filename, whitelist_filename, check_n_records);
 
     // read the whitelist
-    let mut whitelist_file = File::open(whitelist_filename).unwrap();
-    let mut whitelist: String = String::new();
-    whitelist_file.read_to_string(&mut whitelist).unwrap();
-    let whitelist: HashSet<&[u8]> = whitelist.split("\n").map(|s| s.trim_end().as_bytes()).collect();
+    let whitelist = expand_whitelist(read_whitelist(whitelist_filename).unwrap(), DEFAULT_MAX_IUPAC_EXPANSIONS);
+    let whitelist: HashSet<&[u8]> = whitelist.iter().map(|s| s.as_ref()).collect();
 
-    let fastq_in = BufReader::new(GzDecoder::new(File::open(input_fastq_filename).unwrap()));
-    let fastq_reader = fastq::Reader::from_bufread(fastq_in);
+    let fastq_reader = open_fastq(input_fastq_filename).unwrap();
 
     let fastq_out = BufWriter::new(GzEncoder::new(File::create(output_fastq_filename).unwrap(), Compression::fast()));
     let mut fastq_writer = fastq::Writer::from_bufwriter(fastq_out);
@@ -182,7 +174,7 @@ pub fn transform_fastq_file (input_fastq_filename: &str, whitelist_filename: &st
             matched_whitelist += 1;
         }
 
-        if total % 1000000 == 0 {
+        if total.is_multiple_of(1000000) {
             info!("Processed {} reads so far ({} match whitelist)", total, matched_whitelist);
         }
 
@@ -194,10 +186,10 @@ pub fn transform_fastq_file (input_fastq_filename: &str, whitelist_filename: &st
 
     let mut counts_writer = BufWriter::new(File::create(output_counts_filename).unwrap());
     for (k, v) in counts.iter() {
-        counts_writer.write(k).unwrap();
-        counts_writer.write(b"\t").unwrap();
-        counts_writer.write(v.to_string().as_bytes()).unwrap();
-        counts_writer.write(b"\n").unwrap();
+        counts_writer.write_all(k).unwrap();
+        counts_writer.write_all(b"\t").unwrap();
+        counts_writer.write_all(v.to_string().as_bytes()).unwrap();
+        counts_writer.write_all(b"\n").unwrap();
     }
 
     counts_writer.flush().unwrap();
@@ -1,6 +1,99 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap,HashMap};
+use std::io;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use itertools::Itertools;
+
+/// Order of the bases in a [`SubstitutionCosts`] matrix.
+const SUBSTITUTION_BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// Tolerance for floating point error when comparing a summed substitution cost to the max cost.
+const COST_TOLERANCE: f64 = 1e-9;
+
+/// Costs of substituting one base for another, for a weighted Hamming distance (e.g., to make transitions
+/// cheaper than transversions).
+///
+/// Rows are the base in the word being searched for and columns the base in the Trie, both in ACGT order.
+/// Substitutions involving any other byte (e.g., N) cost 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubstitutionCosts {
+    costs: [[f64; 4]; 4],
+}
+
+impl SubstitutionCosts {
+    /// Create a cost matrix. Returns an error if a cost is negative or not finite, or if a base has a
+    /// non-zero cost of substituting for itself.
+    pub fn new(costs: [[f64; 4]; 4]) -> io::Result<SubstitutionCosts> {
+        for (i, row) in costs.iter().enumerate() {
+            for (j, &cost) in row.iter().enumerate() {
+                if !cost.is_finite() || cost < 0.0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Substitution cost {} is not a non-negative number", cost)));
+                }
+                if i == j && cost != 0.0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Substituting {} for itself must cost 0", SUBSTITUTION_BASES[i] as char)));
+                }
+            }
+        }
+
+        Ok(SubstitutionCosts {costs})
+    }
+
+    /// Costs with one value for transitions (A<->G, C<->T) and another for transversions.
+    pub fn transition_transversion(transition: f64, transversion: f64) -> io::Result<SubstitutionCosts> {
+        let mut costs = [[transversion; 4]; 4];
+        for (i, row) in costs.iter_mut().enumerate() {
+            row[i] = 0.0;
+            // A<->G and C<->T are two apart in ACGT order
+            row[(i + 2) % 4] = transition;
+        }
+        SubstitutionCosts::new(costs)
+    }
+
+    /// Cost of reading `from` where the true base is `to`.
+    pub fn cost(&self, from: u8, to: u8) -> f64 {
+        if from == to {
+            return 0.0;
+        }
+        let index = |base: u8| SUBSTITUTION_BASES.iter().position(|&b| b == base);
+        match (index(from), index(to)) {
+            (Some(i), Some(j)) => self.costs[i][j],
+            _ => 1.0
+        }
+    }
+}
+
+/// Read a substitution cost matrix: a TSV of four rows of four costs, in ACGT order (see
+/// [`SubstitutionCosts`]). Blank lines and lines starting with `#` are skipped.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::trie::{read_substitution_costs,SubstitutionCosts};
+/// let path = std::env::temp_dir().join("read_substitution_costs.tsv");
+/// fs::write(&path, "# A\tC\tG\tT\n0\t1\t0.5\t1\n1\t0\t1\t0.5\n0.5\t1\t0\t1\n1\t0.5\t1\t0\n").unwrap();
+/// let costs = read_substitution_costs(path.to_str().unwrap()).unwrap();
+/// assert_eq!(costs, SubstitutionCosts::transition_transversion(0.5, 1.0).unwrap());
+/// assert_eq!(costs.cost(b'A', b'G'), 0.5);
+/// assert_eq!(costs.cost(b'N', b'G'), 1.0);
+/// ```
+pub fn read_substitution_costs(filename: &str) -> io::Result<SubstitutionCosts> {
+    let contents = std::fs::read_to_string(filename)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Substitution cost matrix must have four rows of four tab-separated costs");
+
+    let rows: Vec<&str> = contents.lines().map(|l| l.trim_end()).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+    if rows.len() != 4 {
+        return Err(invalid());
+    }
+    let mut costs = [[0.0; 4]; 4];
+    for (row, line) in costs.iter_mut().zip(rows) {
+        let values: Vec<f64> = line.split('\t').map(|v| v.parse::<f64>().map_err(|_| invalid())).collect::<io::Result<_>>()?;
+        *row = values.try_into().map_err(|_| invalid())?;
+    }
+
+    SubstitutionCosts::new(costs)
+}
 
 /// Implementation of a Trie, where all items in the Trie *must* be of the same length
+#[derive(Clone)]
 struct TrieNode {
     children: HashMap<u8, Box<TrieNode>> // need to use boxes since recursive so don't know size. HashMap has known size (it's a smart pointer)
 }
@@ -25,8 +118,8 @@ impl TrieNode {
         self.children.insert(byte, Box::new(n));
     }
 
-    pub fn get_child(&self, byte: u8) -> Option<&Box<TrieNode>> {
-        self.children.get(&byte)
+    pub fn get_child(&self, byte: u8) -> Option<&TrieNode> {
+        self.children.get(&byte).map(|n| n.as_ref())
     }
 
     pub fn get_child_mut(&mut self, byte: u8) -> Option<&mut Box<TrieNode>> {
@@ -43,15 +136,270 @@ impl TrieNode {
 pub struct Trie {
     word_length: usize,
     word_count: usize,
-    root: TrieNode
+    root: TrieNode,
+    searches: SearchCounters,
+}
+
+/// Work done by the Hamming distance searches of a [`Trie`], e.g., to see how it grows with the max
+/// distance.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SearchStats {
+    /// Number of searches
+    pub queries: usize,
+    /// Number of nodes visited, over all searches (a search visits at least the root)
+    pub nodes_visited: usize,
+    /// Number of words found, over all searches
+    pub candidates: usize,
+}
+
+impl SearchStats {
+    /// Mean number of nodes visited per search (0 if there were none).
+    pub fn nodes_per_query(&self) -> f64 {
+        if self.queries == 0 { 0.0 } else { self.nodes_visited as f64 / self.queries as f64 }
+    }
+
+    /// Mean number of words found per search (0 if there were none).
+    pub fn candidates_per_query(&self) -> f64 {
+        if self.queries == 0 { 0.0 } else { self.candidates as f64 / self.queries as f64 }
+    }
+}
+
+impl std::iter::Sum for SearchStats {
+    fn sum<I: Iterator<Item = SearchStats>>(stats: I) -> Self {
+        stats.fold(SearchStats::default(), |total, s| SearchStats {
+            queries: total.queries + s.queries,
+            nodes_visited: total.nodes_visited + s.nodes_visited,
+            candidates: total.candidates + s.candidates,
+        })
+    }
+}
+
+/// Running [`SearchStats`] of a Trie, which can be shared between threads searching it.
+#[derive(Debug, Default)]
+struct SearchCounters {
+    queries: AtomicUsize,
+    nodes_visited: AtomicUsize,
+    candidates: AtomicUsize,
+}
+
+impl SearchCounters {
+    fn add(&self, nodes_visited: usize, candidates: usize) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.nodes_visited.fetch_add(nodes_visited, Ordering::Relaxed);
+        self.candidates.fetch_add(candidates, Ordering::Relaxed);
+    }
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: AsRef<[u8]>> FromIterator<W> for Trie {
+    fn from_iter<I: IntoIterator<Item = W>>(words: I) -> Self {
+        let mut trie = Trie::new();
+        for word in words {
+            trie.add_word(word.as_ref());
+        }
+        trie
+    }
 }
 
 impl Trie {
     
     pub fn new () -> Trie {
-        Trie {root: TrieNode::new(), word_count: 0, word_length: 0}
+        Trie {root: TrieNode::new(), word_count: 0, word_length: 0, searches: SearchCounters::default()}
+    }
+
+    /// Build a Trie from a collection of words (equivalent to collecting them into a Trie).
+    ///
+    /// # Examples
+    /// ```
+    /// use std::collections::HashSet;
+    /// use barcodes::trie::Trie;
+    /// let whitelist: HashSet<&[u8]> = HashSet::from([&b"ACGT"[..], &b"TTTT"[..]]);
+    /// let t: Trie = whitelist.iter().collect();
+    /// assert_eq!(t.len(), 2);
+    /// assert!(t.contains_word(b"ACGT"));
+    /// assert!(!t.contains_word(b"CCCC"));
+    /// let t = Trie::from_words(vec![b"ACGT".to_vec()]);
+    /// assert!(t.contains_word(b"ACGT"));
+    /// ```
+    pub fn from_words<I: IntoIterator<Item = W>, W: AsRef<[u8]>>(words: I) -> Trie {
+        words.into_iter().collect()
     }
     
+    /// Build a Trie from words that are already sorted, which is faster than adding them one at a time.
+    ///
+    /// Rather than walking down from the root for each word, the Trie is built level by level from the runs
+    /// of words sharing a prefix. Duplicate words are allowed. Returns an error if a word is out of order,
+    /// or if the words are not all of the same length.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let words = [b"ACGA", b"ACGT", b"ACGT", b"GGCC", b"TTTT"];
+    /// let sorted = Trie::from_sorted_words(&words).unwrap();
+    /// let unsorted = Trie::from_words([b"TTTT", b"ACGT", b"GGCC", b"ACGA"]);
+    /// assert_eq!(sorted.len(), unsorted.len());
+    /// assert_eq!(sorted.word_length(), unsorted.word_length());
+    /// assert!(sorted.iter().eq(unsorted.iter()));
+    /// assert!(Trie::from_sorted_words(&[b"ACGT", b"ACGA"]).is_err());
+    /// ```
+    pub fn from_sorted_words<W: AsRef<[u8]>>(words: &[W]) -> io::Result<Trie> {
+        let words: Vec<&[u8]> = words.iter().map(|w| w.as_ref()).collect();
+
+        let mut word_count = words.len().min(1);
+        for (i, pair) in words.windows(2).enumerate() {
+            if pair[0].len() != pair[1].len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Word {} ({}) has a different length than the words before it", i + 2, String::from_utf8_lossy(pair[1]))));
+            }
+            if pair[0] > pair[1] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Word {} ({}) is out of order", i + 2, String::from_utf8_lossy(pair[1]))));
+            }
+            if pair[0] != pair[1] {
+                word_count += 1;
+            }
+        }
+
+        let mut trie = Trie::new();
+        trie.word_count = word_count;
+        trie.word_length = words.first().map_or(0, |w| w.len());
+        Trie::add_sorted_words(&mut trie.root, &words, 0);
+        Ok(trie)
+    }
+
+    /// Build a Trie from a collection of words across `threads` threads.
+    ///
+    /// The words are split by their first byte, a subtrie is built for each first byte on one of the
+    /// threads, and the subtries are then merged under a single root. Returns an error if the words are not
+    /// all of the same length.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// // 10,000 barcodes, as the base-4 digits of scattered numbers
+    /// let words: Vec<Vec<u8>> = (0..10_000_u64)
+    ///     .map(|i| (0..12).map(|j| b"ACGT"[((i * 2_654_435_761 % 16_777_213) >> (2 * j)) as usize % 4]).collect())
+    ///     .collect();
+    /// let serial = Trie::from_words(&words);
+    /// let parallel = Trie::build_parallel(&words, 4).unwrap();
+    /// assert_eq!(parallel.len(), serial.len());
+    /// assert!(parallel.iter().eq(serial.iter()));
+    /// assert!(words.iter().all(|w| parallel.contains_word(w)));
+    /// assert!(!parallel.contains_word(b"ACGTACGTACG"));
+    /// assert!(Trie::build_parallel(&[b"ACGT".to_vec(), b"ACG".to_vec()], 2).is_err());
+    /// ```
+    pub fn build_parallel<W: AsRef<[u8]> + Sync>(words: &[W], threads: usize) -> io::Result<Trie> {
+        let word_length = words.first().map_or(0, |w| w.as_ref().len());
+        if let Some((i, word)) = words.iter().enumerate().find(|(_i, w)| w.as_ref().len() != word_length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Word {} ({}) has a different length than the first word", i + 1, String::from_utf8_lossy(word.as_ref()))));
+        }
+
+        // spread the first bytes over the threads, largest first, each to the least loaded thread
+        let mut by_first_byte: HashMap<u8, Vec<&[u8]>> = HashMap::new();
+        for word in words.iter().map(|w| w.as_ref()).filter(|w| !w.is_empty()) {
+            by_first_byte.entry(word[0]).or_default().push(word);
+        }
+        let mut groups: Vec<Vec<&[u8]>> = vec![Vec::new(); threads.max(1)];
+        for (_byte, group) in by_first_byte.into_iter().sorted_by_key(|(byte, group)| (std::cmp::Reverse(group.len()), *byte)) {
+            groups.iter_mut().min_by_key(|g| g.len()).unwrap().extend(group);
+        }
+
+        let subtries: Vec<Trie> = std::thread::scope(|scope| {
+            let handles: Vec<_> = groups.iter().map(|group| scope.spawn(move || Trie::from_words(group))).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        let mut trie = Trie::new();
+        for subtrie in subtries {
+            trie.word_count += subtrie.word_count;
+            Trie::merge_nodes(&mut trie.root, subtrie.root);
+        }
+        if trie.word_count > 0 {
+            trie.word_length = word_length;
+        }
+        Ok(trie)
+    }
+
+    /// Merge the children of `other` into `node`. Since all words are of the same length, a word ends
+    /// wherever a node has no children, so merging the children keeps every word's end.
+    fn merge_nodes(node: &mut TrieNode, other: TrieNode) {
+        for (byte, child) in other.children {
+            match node.children.get_mut(&byte) {
+                Some(existing) => Trie::merge_nodes(existing, *child),
+                None => { node.children.insert(byte, child); },
+            }
+        }
+    }
+
+    /// Add the words of another Trie (of words of the same length) to this one. Subtries only in `other` are
+    /// copied over whole, and words in both Tries are counted once.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let mut base = Trie::from_words([b"ACGT", b"ACGA", b"TTTT"]);
+    /// let supplement = Trie::from_words([b"ACGA", b"ACCC", b"GGGG"]);
+    /// base.merge(&supplement);
+    /// assert_eq!(base.len(), 5);
+    /// let union = Trie::from_words([b"ACGT", b"ACGA", b"TTTT", b"ACCC", b"GGGG"]);
+    /// assert!(base.iter().eq(union.iter()));
+    /// assert!(!base.contains_word(b"ACGC"));
+    ///
+    /// let mut empty = Trie::new();
+    /// empty.merge(&supplement);
+    /// assert!(empty.iter().eq(supplement.iter()));
+    /// assert_eq!(empty.word_length(), 4);
+    /// ```
+    pub fn merge(&mut self, other: &Trie) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            self.word_length = other.word_length;
+        } else {
+            assert_eq!(self.word_length, other.word_length);
+        }
+
+        self.word_count += Trie::merge_node_copy(&mut self.root, &other.root);
+    }
+
+    /// Merge copies of the children of `other` into `node`, returning the number of words added.
+    fn merge_node_copy(node: &mut TrieNode, other: &TrieNode) -> usize {
+        let mut added = 0;
+        for (byte, child) in other.children.iter() {
+            match node.children.get_mut(byte) {
+                Some(existing) => added += Trie::merge_node_copy(existing, child),
+                None => {
+                    added += Trie::count_words(child);
+                    node.children.insert(*byte, child.clone());
+                },
+            }
+        }
+        added
+    }
+
+    /// Number of words below a node.
+    fn count_words(node: &TrieNode) -> usize {
+        if node.is_end_of_word() {
+            1
+        } else {
+            node.children.values().map(|child| Trie::count_words(child)).sum()
+        }
+    }
+
+    fn add_sorted_words(node: &mut TrieNode, words: &[&[u8]], depth: usize) {
+        // words sharing a byte at this depth are contiguous, so each run becomes one child
+        for run in words.chunk_by(|a, b| a.get(depth) == b.get(depth)) {
+            if let Some(&byte) = run[0].get(depth) {
+                node.add_child(byte);
+                Trie::add_sorted_words(node.get_child_mut(byte).unwrap(), run, depth + 1);
+            }
+        }
+    }
+
     pub fn contains_word(&self, word: &[u8]) -> bool {
         //! Check if the Trie contains a given word.
         //! # Examples
@@ -61,7 +409,13 @@ impl Trie {
         //! t.add_word(b"hello");
         //! assert_eq!(t.contains_word(b"hello"), true);
         //! assert_eq!(t.contains_word(b"goodbye"), false);
+        //! assert_eq!(t.contains_word(b"hell"), false);
+        //! assert_eq!(Trie::new().contains_word(b""), false);
         //! ```
+        if self.is_empty() {
+            return false;
+        }
+
         let mut node = &self.root;
 
         for &byte in word {
@@ -72,7 +426,33 @@ impl Trie {
             }
         }
 
-        true
+        node.is_end_of_word()
+    }
+
+    pub fn longest_prefix_match(&self, query: &[u8]) -> usize {
+        //! The length of the longest prefix of a query that's a path in the Trie, i.e., that's shared with some
+        //! word in it (so a barcode that's one base off near its end still has a long prefix match).
+        //! # Examples
+        //! ```
+        //! use barcodes::trie::Trie;
+        //! let t = Trie::from_words([b"ACGTACGT", b"ACGTTTTT", b"GGGGCCCC"]);
+        //! assert_eq!(t.longest_prefix_match(b"ACGTACGT"), 8);
+        //! assert_eq!(t.longest_prefix_match(b"ACGTACGA"), 7);
+        //! assert_eq!(t.longest_prefix_match(b"ACGTTTAT"), 6);
+        //! assert_eq!(t.longest_prefix_match(b"ACGGACGT"), 3);
+        //! assert_eq!(t.longest_prefix_match(b"GAAAAAAA"), 1);
+        //! assert_eq!(t.longest_prefix_match(b"TTTTTTTT"), 0);
+        //! assert_eq!(t.longest_prefix_match(b"ACG"), 3);
+        //! assert_eq!(Trie::new().longest_prefix_match(b"ACGT"), 0);
+        //! ```
+        let mut node = &self.root;
+        for (depth, byte) in query.iter().enumerate() {
+            match node.get_child(*byte) {
+                Some(child) => node = child,
+                None => return depth
+            }
+        }
+        query.len()
     }
 
     pub fn add_word(&mut self, word: &[u8]) {
@@ -101,6 +481,11 @@ impl Trie {
         self.word_count
     }
 
+    /// Check whether the Trie is empty
+    pub fn is_empty(&self) -> bool {
+        self.word_count == 0
+    }
+
     /// Get the length of an item in the Trie (all items are of the same length)
     pub fn word_length(&self) -> usize {
         if self.word_count == 0 {
@@ -110,17 +495,234 @@ impl Trie {
         self.word_length
     }
 
+    /// Iterate over the words in the Trie, in lexicographic order.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let t = Trie::from_words([b"TTTT", b"ACGT", b"ACGA", b"ACGT"]);
+    /// let words: Vec<Vec<u8>> = t.iter().collect();
+    /// assert_eq!(words, vec![b"ACGA".to_vec(), b"ACGT".to_vec(), b"TTTT".to_vec()]);
+    /// assert_eq!(Trie::new().iter().count(), 0);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        // depth-first, with each node's children pushed in reverse order so that the smallest is visited first
+        let mut stack: Vec<(&TrieNode, Vec<u8>)> = if self.is_empty() { Vec::new() } else { vec![(&self.root, Vec::new())] };
+
+        std::iter::from_fn(move || {
+            while let Some((node, prefix)) = stack.pop() {
+                if node.is_end_of_word() {
+                    return Some(prefix);
+                }
+
+                let mut children_ids = node.get_children_ids();
+                children_ids.sort_unstable_by(|a, b| b.cmp(a));
+                for child_id in children_ids {
+                    let mut child_prefix = prefix.clone();
+                    child_prefix.push(child_id);
+                    stack.push((node.get_child(child_id).unwrap(), child_prefix));
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Get the words within a Hamming distance of `word`, along with their distance. A word of a different
+    /// length than the words in the Trie has no such neighbors.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let t = Trie::from_words([b"ACGT", b"ACGA", b"TTTT"]);
+    /// let mut matches = t.get_words_within_hamming_distance(b"ACGC", 1);
+    /// matches.sort();
+    /// assert_eq!(matches, vec![("ACGA".to_string(), 1), ("ACGT".to_string(), 1)]);
+    /// assert!(t.get_words_within_hamming_distance(b"", 1).is_empty());
+    /// assert!(t.get_words_within_hamming_distance(b"ACGTACGT", 1).is_empty());
+    /// assert!(Trie::new().get_words_within_hamming_distance(b"ACGT", 1).is_empty());
+    /// ```
     pub fn get_words_within_hamming_distance(&self, word: &[u8], max_distance: usize) -> Vec<(String, usize)> {
-        assert_eq!(word.len(), self.word_length);
-        self._get_within_hamming_distance(&self.root, word, &String::from(""), 0, max_distance)
+        if self.is_empty() || word.len() != self.word_length {
+            return Vec::new();
+        }
+        let mut nodes_visited = 0;
+        let matches = self._get_within_hamming_distance(&self.root, word, "", 0, max_distance, &mut nodes_visited);
+        self.searches.add(nodes_visited, matches.len());
+        matches
+    }
+
+    /// The work done by the Hamming distance searches of the Trie so far (see
+    /// [`get_words_within_hamming_distance`](Self::get_words_within_hamming_distance)).
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let t = Trie::from_words([b"ACGTACGT", b"ACGTACGA", b"TTGTACGT", b"GGGGCCCC", b"GGGGCCAA"]);
+    /// let mut nodes_visited = Vec::new();
+    /// for max_distance in 0..8 {
+    ///     t.reset_search_stats();
+    ///     t.get_words_within_hamming_distance(b"ACGTACGC", max_distance);
+    ///     t.get_words_within_hamming_distance(b"GGGGCCCC", max_distance);
+    ///     let stats = t.search_stats();
+    ///     assert_eq!(stats.queries, 2);
+    ///     nodes_visited.push(stats.nodes_visited);
+    /// }
+    /// // a larger max distance never visits fewer nodes, and eventually visits them all (twice)
+    /// assert!(nodes_visited.windows(2).all(|pair| pair[0] <= pair[1]));
+    /// assert!(nodes_visited[0] < nodes_visited[7]);
+    /// assert_eq!(nodes_visited[7], 2 * 28);
+    /// // at distance 1, ACGTACGC matches two words, and GGGGCCCC itself
+    /// t.reset_search_stats();
+    /// t.get_words_within_hamming_distance(b"ACGTACGC", 1);
+    /// t.get_words_within_hamming_distance(b"GGGGCCCC", 1);
+    /// assert_eq!(t.search_stats().candidates, 3);
+    /// assert_eq!(t.search_stats().candidates_per_query(), 1.5);
+    /// ```
+    pub fn search_stats(&self) -> SearchStats {
+        SearchStats {
+            queries: self.searches.queries.load(Ordering::Relaxed),
+            nodes_visited: self.searches.nodes_visited.load(Ordering::Relaxed),
+            candidates: self.searches.candidates.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the [`search_stats`](Self::search_stats) to zero.
+    pub fn reset_search_stats(&self) {
+        self.searches.queries.store(0, Ordering::Relaxed);
+        self.searches.nodes_visited.store(0, Ordering::Relaxed);
+        self.searches.candidates.store(0, Ordering::Relaxed);
+    }
+
+    /// Get the words within a Hamming distance of `word`, along with their distance, nearest first (words at
+    /// the same distance are in lexicographic order). This lets a caller weighing the words stop once the
+    /// remaining, more distant words can't matter.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let t = Trie::from_words([b"TTGA", b"ACGT", b"ACGA", b"TCGA"]);
+    /// assert_eq!(t.get_words_by_hamming_distance(b"ACGC", 2), vec![
+    ///     ("ACGA".to_string(), 1),
+    ///     ("ACGT".to_string(), 1),
+    ///     ("TCGA".to_string(), 2),
+    /// ]);
+    /// ```
+    pub fn get_words_by_hamming_distance(&self, word: &[u8], max_distance: usize) -> Vec<(String, usize)> {
+        let mut words = self.get_words_within_hamming_distance(word, max_distance);
+        words.sort_unstable_by(|(a, a_distance), (b, b_distance)| a_distance.cmp(b_distance).then(a.cmp(b)));
+        words
+    }
+
+    /// Get the word nearest to `word` (in Hamming distance), if any is within `max_distance`, along with its
+    /// distance and whether it is the only word at that distance. If several words are equally near, the
+    /// lexicographically smallest is returned.
+    ///
+    /// Unlike [`get_words_within_hamming_distance`](Self::get_words_within_hamming_distance), branches of the
+    /// search that can't beat the nearest word found so far are pruned, so an exact match ends the search
+    /// after a single walk down the Trie.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::Trie;
+    /// let t = Trie::from_words([b"ACGT", b"ACGA", b"TCGA", b"GGGG"]);
+    /// assert_eq!(t.get_nearest_word(b"ACGT", 2), Some(("ACGT".to_string(), 0, true)));
+    /// assert_eq!(t.get_nearest_word(b"ACGG", 2), Some(("ACGA".to_string(), 1, false)));
+    /// assert_eq!(t.get_nearest_word(b"TCGC", 2), Some(("TCGA".to_string(), 1, true)));
+    /// assert_eq!(t.get_nearest_word(b"CCCC", 2), None);
+    /// assert_eq!(t.get_nearest_word(b"ACG", 2), None);
+    /// ```
+    pub fn get_nearest_word(&self, word: &[u8], max_distance: usize) -> Option<(String, usize, bool)> {
+        if self.is_empty() || word.len() != self.word_length {
+            return None;
+        }
+        if self.contains_word(word) {
+            return Some((String::from_utf8_lossy(word).to_string(), 0, true));
+        }
+
+        let mut nearest: Option<(Vec<u8>, usize, bool)> = None;
+        self._get_nearest(&self.root, word, &mut Vec::new(), 0, max_distance, &mut nearest);
+        nearest.map(|(w, distance, unique)| (String::from_utf8_lossy(&w).to_string(), distance, unique))
+    }
+
+    fn _get_nearest(&self, node: &TrieNode, word: &[u8], prefix: &mut Vec<u8>, current_distance: usize, max_distance: usize, nearest: &mut Option<(Vec<u8>, usize, bool)>) {
+        if node.is_end_of_word() {
+            match nearest {
+                Some((_, distance, unique)) if *distance == current_distance => *unique = false,
+                _ => *nearest = Some((prefix.clone(), current_distance, true)),
+            }
+            return;
+        }
+
+        let mut children_ids = node.get_children_ids();
+        children_ids.sort_unstable();
+        for child_id in children_ids {
+            let distance = current_distance + if child_id == word[0] {0} else {1};
+            // once the nearest word is known not to be unique, only a strictly nearer word matters
+            let bound = match nearest {
+                Some((_, d, true)) => *d,
+                Some((_, d, false)) => d.saturating_sub(1),
+                None => max_distance,
+            };
+            if distance > bound {
+                continue
+            }
+            prefix.push(child_id);
+            self._get_nearest(node.get_child(child_id).unwrap(), &word[1..], prefix, distance, max_distance, nearest);
+            prefix.pop();
+        }
+    }
+
+    /// Get the words whose substitution cost from `word` (the sum of the costs of its mismatches) is at most
+    /// `max_cost`, along with that cost.
+    ///
+    /// # Examples
+    /// ```
+    /// use barcodes::trie::{Trie,SubstitutionCosts};
+    /// let t = Trie::from_words([b"ACGT", b"GCGT", b"CCGT"]);
+    /// let costs = SubstitutionCosts::transition_transversion(0.5, 1.0).unwrap();
+    /// // the transition (A->G) neighbor is within budget, but the transversion (A->C) neighbor isn't, even
+    /// // though both are one mismatch away
+    /// let matches = t.get_words_within_substitution_cost(b"ACGT", 0.5, &costs);
+    /// let mut words: Vec<&str> = matches.iter().map(|(w, _cost)| w.as_str()).collect();
+    /// words.sort();
+    /// assert_eq!(words, vec!["ACGT", "GCGT"]);
+    /// assert_eq!(t.get_words_within_hamming_distance(b"ACGT", 1).len(), 3);
+    /// ```
+    pub fn get_words_within_substitution_cost(&self, word: &[u8], max_cost: f64, costs: &SubstitutionCosts) -> Vec<(String, f64)> {
+        if self.is_empty() || word.len() != self.word_length {
+            return Vec::new();
+        }
+        let mut matches = Vec::new();
+        self._get_within_substitution_cost(&self.root, word, &mut Vec::new(), 0.0, max_cost, costs, &mut matches);
+        matches
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn _get_within_substitution_cost(&self, node: &TrieNode, word: &[u8], prefix: &mut Vec<u8>, current_cost: f64, max_cost: f64, costs: &SubstitutionCosts, matches: &mut Vec<(String, f64)>) {
+        if node.is_end_of_word() {
+            matches.push((String::from_utf8_lossy(prefix).to_string(), current_cost));
+            return;
+        }
+
+        for child_id in node.get_children_ids() {
+            let cost = current_cost + costs.cost(word[0], child_id);
+            if cost > max_cost + COST_TOLERANCE {
+                continue
+            }
+            prefix.push(child_id);
+            self._get_within_substitution_cost(node.get_child(child_id).unwrap(), &word[1..], prefix, cost, max_cost, costs, matches);
+            prefix.pop();
+        }
     }
 
-    fn _get_within_hamming_distance(&self, node: &TrieNode, word: &[u8], prefix: &String, current_distance: usize, max_distance: usize) -> Vec<(String, usize)> {
+    fn _get_within_hamming_distance(&self, node: &TrieNode, word: &[u8], prefix: &str, current_distance: usize, max_distance: usize, nodes_visited: &mut usize) -> Vec<(String, usize)> {
         let mut matches: Vec<(String, usize)> = Vec::new();
-        
+        *nodes_visited += 1;
+
         if node.is_end_of_word() {
             assert!(current_distance <= max_distance);
-            matches.push((prefix.clone(), current_distance));
+            matches.push((prefix.to_string(), current_distance));
         } else {
             for child_id in node.get_children_ids() {
                 let cost = if child_id == word[0] {0} else {1};
@@ -128,9 +730,9 @@ impl Trie {
                     continue
                 }
                 let child_node = node.get_child(child_id).unwrap();
-                let mut new_prefix = prefix.clone();
+                let mut new_prefix = prefix.to_string();
                 new_prefix.push(child_id as char);
-                let mut m = self._get_within_hamming_distance(child_node, &word[1..word.len()], &new_prefix, current_distance + cost, max_distance);
+                let mut m = self._get_within_hamming_distance(child_node, &word[1..word.len()], &new_prefix, current_distance + cost, max_distance, nodes_visited);
                 matches.append(&mut m);
             }
         }
@@ -139,4 +741,88 @@ impl Trie {
     }
 
 
-}
\ No newline at end of file
+}
+
+/// Words of several lengths, in one [`Trie`] per length (the words of a Trie all have the same length), e.g.,
+/// a combinatorial-index whitelist with barcodes of two lengths. A query is only ever compared to the words
+/// of its own length.
+///
+/// # Examples
+/// ```
+/// use barcodes::trie::PartitionedTrie;
+/// let tries = PartitionedTrie::from_words([&b"ACGT"[..], b"TTTT", b"ACGTAC"]);
+/// assert_eq!(tries.lengths().collect::<Vec<_>>(), vec![4, 6]);
+/// assert_eq!(tries.len(), 3);
+/// assert!(tries.contains_word(b"ACGTAC"));
+/// assert!(!tries.contains_word(b"ACGTA"));
+/// assert_eq!(tries.partition(4).unwrap().get_words_within_hamming_distance(b"ACGA", 1), vec![("ACGT".to_string(), 1)]);
+/// assert!(tries.partition(5).is_none());
+/// let sorted = PartitionedTrie::from_sorted_words(&[&b"ACGT"[..], b"ACGTAC", b"TTTT"]).unwrap();
+/// assert_eq!(sorted.len(), 3);
+/// assert_eq!(sorted.search_stats().queries, 0);
+/// ```
+#[derive(Default)]
+pub struct PartitionedTrie {
+    tries: BTreeMap<usize, Trie>,
+}
+
+impl<W: AsRef<[u8]>> FromIterator<W> for PartitionedTrie {
+    fn from_iter<I: IntoIterator<Item = W>>(words: I) -> Self {
+        let mut tries: BTreeMap<usize, Trie> = BTreeMap::new();
+        for word in words {
+            let word = word.as_ref();
+            tries.entry(word.len()).or_default().add_word(word);
+        }
+        PartitionedTrie {tries}
+    }
+}
+
+impl PartitionedTrie {
+    /// Build one Trie per word length from a collection of words.
+    pub fn from_words<I: IntoIterator<Item = W>, W: AsRef<[u8]>>(words: I) -> PartitionedTrie {
+        words.into_iter().collect()
+    }
+
+    /// Build one Trie per word length from words that are already sorted (see [`Trie::from_sorted_words`]);
+    /// the words of each length are still sorted once the others are taken out.
+    pub fn from_sorted_words<W: AsRef<[u8]>>(words: &[W]) -> io::Result<PartitionedTrie> {
+        let mut partitions: BTreeMap<usize, Vec<&[u8]>> = BTreeMap::new();
+        for word in words {
+            partitions.entry(word.as_ref().len()).or_default().push(word.as_ref());
+        }
+        let tries = partitions.into_iter()
+            .map(|(length, words)| Ok((length, Trie::from_sorted_words(&words)?)))
+            .collect::<io::Result<_>>()?;
+        Ok(PartitionedTrie {tries})
+    }
+
+    /// Get the Trie of the words of a length, if there are any.
+    pub fn partition(&self, length: usize) -> Option<&Trie> {
+        self.tries.get(&length)
+    }
+
+    /// Iterate over the word lengths, shortest first.
+    pub fn lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        self.tries.keys().copied()
+    }
+
+    /// Get the number of words, over all lengths.
+    pub fn len(&self) -> usize {
+        self.tries.values().map(|t| t.len()).sum()
+    }
+
+    /// Check whether there are no words.
+    pub fn is_empty(&self) -> bool {
+        self.tries.is_empty()
+    }
+
+    /// Check whether a word is in the Trie of its length.
+    pub fn contains_word(&self, word: &[u8]) -> bool {
+        self.partition(word.len()).is_some_and(|t| t.contains_word(word))
+    }
+
+    /// Get the work done by the Hamming distance searches of all the Tries (see [`Trie::search_stats`]).
+    pub fn search_stats(&self) -> SearchStats {
+        self.tries.values().map(|t| t.search_stats()).sum()
+    }
+}
@@ -0,0 +1,542 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self,Read,Write,BufWriter};
+use std::collections::{BTreeSet,HashMap,HashSet};
+use log::{info,warn};
+use itertools::Itertools;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::io::{create_output,MappedFile,GZIP_MAGIC};
+use crate::trie::Trie;
+
+/// Default limit on the number of concrete sequences a single degenerate whitelist barcode may expand to.
+pub const DEFAULT_MAX_IUPAC_EXPANSIONS: usize = 10000;
+
+/// The text of a whitelist: the mapped file itself, or if it's gzipped, the decompressed file.
+enum WhitelistText {
+    Mapped(MappedFile),
+    Decompressed(String),
+}
+
+impl WhitelistText {
+    fn as_str(&self) -> io::Result<&str> {
+        match self {
+            WhitelistText::Mapped(contents) => std::str::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            WhitelistText::Decompressed(text) => Ok(text),
+        }
+    }
+}
+
+/// A (optionally gzipped) whitelist file, whose barcodes are borrowed from the file's contents rather than
+/// copied: an uncompressed file is memory-mapped (see [`MappedFile`]), so its barcodes aren't on the heap at
+/// all.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::WhitelistFile;
+/// let path = std::env::temp_dir().join("whitelist_file.txt");
+/// fs::write(&path, "ACGT\n\nTTTT\n").unwrap();
+/// let whitelist = WhitelistFile::open(path.to_str().unwrap()).unwrap();
+/// assert_eq!(whitelist.barcodes().unwrap(), vec![b"ACGT".as_slice(), b"TTTT"]);
+/// ```
+pub struct WhitelistFile {
+    filename: String,
+    text: WhitelistText,
+}
+
+impl WhitelistFile {
+    pub fn open(whitelist_filename: &str) -> io::Result<WhitelistFile> {
+        let contents = MappedFile::open(whitelist_filename)?;
+        let text = if contents.starts_with(&GZIP_MAGIC) {
+            let mut whitelist = String::new();
+            MultiGzDecoder::new(&contents[..]).read_to_string(&mut whitelist)?;
+            WhitelistText::Decompressed(whitelist)
+        } else {
+            WhitelistText::Mapped(contents)
+        };
+        Ok(WhitelistFile {filename: whitelist_filename.to_string(), text})
+    }
+
+    /// The barcodes of a whitelist with one barcode per line, skipping blank lines.
+    pub fn barcodes(&self) -> io::Result<Vec<&[u8]>> {
+        Ok(self.text.as_str()?.lines().map(|s| s.trim_end().as_bytes()).filter(|s| !s.is_empty()).collect())
+    }
+
+    /// The barcodes in one column of a tab-separated whitelist (see [`read_whitelist_column`]).
+    pub fn column(&self, column: &WhitelistColumn, header: bool) -> io::Result<Vec<&[u8]>> {
+        let filename = &self.filename;
+        let mut lines = self.text.as_str()?.lines().map(|s| s.trim_end()).filter(|s| !s.is_empty() && !s.starts_with('#'));
+
+        let index = match column {
+            WhitelistColumn::Index(index) => {
+                if header {
+                    lines.next();
+                }
+                *index
+            },
+            WhitelistColumn::Name(name) => {
+                let header = lines.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Whitelist {} has no header", filename)))?;
+                header.split('\t').position(|s| s == name).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Whitelist {} has no column named {}", filename, name)))?
+            },
+        };
+
+        lines.map(|line| {
+            line.split('\t').nth(index).map(str::as_bytes).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Whitelist {} has no column {} on line: {}", filename, index + 1, line)))
+        }).collect()
+    }
+}
+
+/// Read a (optionally gzipped) whitelist file (one barcode per line), skipping blank lines.
+pub fn read_whitelist(whitelist_filename: &str) -> io::Result<Vec<Vec<u8>>> {
+    let whitelist = WhitelistFile::open(whitelist_filename)?;
+    Ok(whitelist.barcodes()?.into_iter().map(<[u8]>::to_vec).collect())
+}
+
+/// Column of a tab-separated whitelist holding the barcodes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WhitelistColumn {
+    /// Zero-based column index
+    Index(usize),
+    /// Column name, looked up in the header
+    Name(String),
+}
+
+impl std::str::FromStr for WhitelistColumn {
+    type Err = String;
+
+    /// Parse a one-based column number (as with `cut -f`), or otherwise a column name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(0) => Err("Column numbers start at 1".to_string()),
+            Ok(n) => Ok(WhitelistColumn::Index(n - 1)),
+            Err(_) if s.is_empty() => Err("Column name is empty".to_string()),
+            Err(_) => Ok(WhitelistColumn::Name(s.to_string())),
+        }
+    }
+}
+
+/// Read the barcodes in one column of a (optionally gzipped) tab-separated whitelist file, e.g., a BED file
+/// or a table of barcode annotations, skipping blank lines and comment lines (starting with `#`).
+///
+/// If `header` is set, or the column is given by name, the first line that isn't a comment is a header.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::{read_whitelist_column,WhitelistColumn};
+/// let path = std::env::temp_dir().join("whitelist_column.tsv");
+/// fs::write(&path, "# exported barcodes\nwell\tbarcode\tcells\nA1\tACGT\t10\n\n# a comment\nA2\tTTTT\t3\n").unwrap();
+/// let path = path.to_str().unwrap();
+/// let by_name = read_whitelist_column(path, &WhitelistColumn::Name("barcode".to_string()), true).unwrap();
+/// assert_eq!(by_name, vec![b"ACGT".to_vec(), b"TTTT".to_vec()]);
+/// let by_index = read_whitelist_column(path, &"2".parse().unwrap(), true).unwrap();
+/// assert_eq!(by_index, by_name);
+/// assert!(read_whitelist_column(path, &WhitelistColumn::Name("umi".to_string()), true).is_err());
+/// assert!(read_whitelist_column(path, &WhitelistColumn::Index(3), true).is_err());
+/// ```
+pub fn read_whitelist_column(whitelist_filename: &str, column: &WhitelistColumn, header: bool) -> io::Result<Vec<Vec<u8>>> {
+    let whitelist = WhitelistFile::open(whitelist_filename)?;
+    Ok(whitelist.column(column, header)?.into_iter().map(<[u8]>::to_vec).collect())
+}
+
+/// Read a (optionally gzipped) tab-separated file of barcodes and their spatial coordinates (columns:
+/// barcode, x, y), e.g., of the spots of a Visium slide or the beads of a Slide-seq puck, skipping blank
+/// lines.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::read_spatial_coords;
+/// let path = std::env::temp_dir().join("spatial_coords.tsv");
+/// fs::write(&path, "ACGT\t1\t2.5\n\nTTTT\t-3\t40\n").unwrap();
+/// let coords = read_spatial_coords(path.to_str().unwrap()).unwrap();
+/// assert_eq!(coords[b"ACGT".as_slice()], (1.0, 2.5));
+/// assert_eq!(coords[b"TTTT".as_slice()], (-3.0, 40.0));
+/// fs::write(&path, "ACGT\t1\n").unwrap();
+/// assert!(read_spatial_coords(path.to_str().unwrap()).is_err());
+/// ```
+pub fn read_spatial_coords(filename: &str) -> io::Result<HashMap<Vec<u8>, (f64, f64)>> {
+    let whitelist = WhitelistFile::open(filename)?;
+    whitelist.text.as_str()?.lines().map(|s| s.trim_end()).filter(|s| !s.is_empty()).map(|line| {
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next().and_then(|x| x.parse().ok()), fields.next().and_then(|y| y.parse().ok())) {
+            (Some(barcode), Some(x), Some(y)) => Ok((barcode.as_bytes().to_vec(), (x, y))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in spatial coordinates file {}: {}", filename, line)))
+        }
+    }).collect()
+}
+
+/// Merge several (optionally gzipped) whitelists into a single sorted, deduplicated, gzipped whitelist.
+///
+/// Returns the number of unique barcodes written.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::{merge_whitelists,read_whitelist};
+/// let dir = std::env::temp_dir();
+/// let a = dir.join("merge_whitelists_a.txt");
+/// let b = dir.join("merge_whitelists_b.txt");
+/// let merged = dir.join("merge_whitelists_merged.txt.gz");
+/// fs::write(&a, "TTTT\nACGT\n").unwrap();
+/// fs::write(&b, "ACGT\nCCCC\n").unwrap();
+/// let n = merge_whitelists(&[a.to_str().unwrap(), b.to_str().unwrap()], merged.to_str().unwrap()).unwrap();
+/// assert_eq!(n, 3);
+/// assert_eq!(read_whitelist(merged.to_str().unwrap()).unwrap(), vec![b"ACGT".to_vec(), b"CCCC".to_vec(), b"TTTT".to_vec()]);
+/// ```
+pub fn merge_whitelists(paths: &[&str], output_path: &str) -> io::Result<usize> {
+    let mut merged: BTreeSet<Vec<u8>> = BTreeSet::new();
+    for path in paths {
+        let whitelist = read_whitelist(path)?;
+        info!("Read {} barcodes from {}", whitelist.len(), path);
+        merged.extend(whitelist);
+    }
+
+    let mut writer = BufWriter::new(GzEncoder::new(File::create(output_path)?, Compression::default()));
+    for barcode in merged.iter() {
+        writer.write_all(barcode)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+
+    Ok(merged.len())
+}
+
+/// How [`compare_whitelists`] combines two whitelists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    /// Barcodes in both whitelists
+    Intersection,
+    /// Barcodes in exactly one of the whitelists
+    SymmetricDifference,
+}
+
+/// Write the intersection or symmetric difference of two (optionally gzipped) whitelists, sorted, to a file
+/// (gzipped if the filename ends with `.gz`).
+///
+/// Returns the number of unique barcodes in each whitelist and in the output.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::{compare_whitelists,read_whitelist,SetOperation};
+/// let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// fs::write(path("compare_whitelists_a.txt"), "TTTT\nACGT\nGGGG\nACGT\n").unwrap();
+/// fs::write(path("compare_whitelists_b.txt"), "ACGT\nCCCC\nGGGG\n").unwrap();
+/// let sizes = compare_whitelists(&path("compare_whitelists_a.txt"), &path("compare_whitelists_b.txt"), &path("compare_whitelists_both.txt.gz"), SetOperation::Intersection).unwrap();
+/// assert_eq!(sizes, (3, 3, 2));
+/// assert_eq!(read_whitelist(&path("compare_whitelists_both.txt.gz")).unwrap(), vec![b"ACGT".to_vec(), b"GGGG".to_vec()]);
+/// let sizes = compare_whitelists(&path("compare_whitelists_a.txt"), &path("compare_whitelists_b.txt"), &path("compare_whitelists_either.txt"), SetOperation::SymmetricDifference).unwrap();
+/// assert_eq!(sizes, (3, 3, 2));
+/// assert_eq!(fs::read_to_string(path("compare_whitelists_either.txt")).unwrap(), "CCCC\nTTTT\n");
+/// ```
+pub fn compare_whitelists(path_a: &str, path_b: &str, output_path: &str, operation: SetOperation) -> io::Result<(usize, usize, usize)> {
+    let a: BTreeSet<Vec<u8>> = read_whitelist(path_a)?.into_iter().collect();
+    let b: BTreeSet<Vec<u8>> = read_whitelist(path_b)?.into_iter().collect();
+    info!("Read {} unique barcodes from {} and {} from {}", a.len(), path_a, b.len(), path_b);
+
+    let result: Vec<&Vec<u8>> = match operation {
+        SetOperation::Intersection => a.intersection(&b).collect(),
+        SetOperation::SymmetricDifference => a.symmetric_difference(&b).collect(),
+    };
+
+    let mut writer = create_output(output_path)?;
+    for barcode in result.iter() {
+        writer.write_all(barcode)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok((a.len(), b.len(), result.len()))
+}
+
+/// Positions of whitelist barcodes with an entropy (in bits) below this are considered low diversity.
+pub const LOW_DIVERSITY_ENTROPY: f64 = 1.0;
+
+/// Header of the TSV written by [`write_position_stats`].
+pub const POSITION_STATS_HEADER: &str = "position\tA_freq\tC_freq\tG_freq\tT_freq\tentropy";
+
+/// Nucleotide composition of one position across the barcodes of a whitelist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionStats {
+    /// 0-based position
+    pub position: usize,
+    /// Fraction of barcodes with A, C, G and T at the position
+    pub frequencies: [f64; 4],
+    /// Shannon entropy of the frequencies, in bits (at most 2)
+    pub entropy: f64,
+}
+
+/// Per-position nucleotide frequencies and Shannon entropy of the barcodes in a whitelist (which must all
+/// be of the same length). Positions with little diversity add little to the distance between barcodes.
+///
+/// # Example
+/// ```
+/// use barcodes::whitelist::position_stats;
+/// let stats = position_stats(&[b"AC".to_vec(), b"AG".to_vec(), b"AT".to_vec(), b"AA".to_vec()]).unwrap();
+/// assert_eq!(stats[0].frequencies, [1.0, 0.0, 0.0, 0.0]);
+/// assert_eq!(stats[0].entropy, 0.0);
+/// assert_eq!(stats[1].frequencies, [0.25, 0.25, 0.25, 0.25]);
+/// assert_eq!(stats[1].entropy, 2.0);
+/// ```
+pub fn position_stats(whitelist: &[Vec<u8>]) -> io::Result<Vec<PositionStats>> {
+    let length = barcode_length(whitelist)?;
+
+    Ok((0..length).map(|position| {
+        let mut counts = [0usize; 4];
+        for barcode in whitelist {
+            if let Some(i) = b"ACGT".iter().position(|&b| b == barcode[position]) {
+                counts[i] += 1;
+            }
+        }
+        let frequencies = counts.map(|n| n as f64 / whitelist.len() as f64);
+        // adding 0 turns the -0 entropy of a position with a single base into 0
+        let entropy = frequencies.iter().filter(|&&f| f > 0.0).map(|&f| -f * f.log2()).sum::<f64>() + 0.0;
+        PositionStats {position, frequencies, entropy}
+    }).collect())
+}
+
+/// Write the per-position statistics of a (optionally gzipped) whitelist as a TSV (see
+/// [`POSITION_STATS_HEADER`]) to a file, or to stdout if the filename is `-`, warning about low diversity
+/// positions.
+pub fn write_position_stats(whitelist_filename: &str, output_filename: &str) -> io::Result<Vec<PositionStats>> {
+    let stats = position_stats(&read_whitelist(whitelist_filename)?)?;
+
+    let mut writer = create_output(output_filename)?;
+    writeln!(writer, "{}", POSITION_STATS_HEADER)?;
+    for p in stats.iter() {
+        writeln!(writer, "{}\t{}\t{}\t{}\t{}\t{}", p.position, p.frequencies[0], p.frequencies[1], p.frequencies[2], p.frequencies[3], p.entropy)?;
+    }
+    writer.flush()?;
+
+    let low_diversity: Vec<usize> = stats.iter().filter(|p| p.entropy < LOW_DIVERSITY_ENTROPY).map(|p| p.position).collect();
+    if !low_diversity.is_empty() {
+        warn!("{} positions have an entropy below {} bit, and contribute little to telling barcodes apart: {}", low_diversity.len(), LOW_DIVERSITY_ENTROPY, low_diversity.iter().join(", "));
+    }
+
+    Ok(stats)
+}
+
+/// Header of the TSV written by [`write_distance_matrix`].
+pub const DISTANCE_MATRIX_HEADER: &str = "barcode1\tbarcode2\tdistance";
+
+/// Write each pair of distinct barcodes in a (optionally gzipped) whitelist that are within `max_distance`
+/// (Hamming distance) of each other as a TSV (see [`DISTANCE_MATRIX_HEADER`]), to a file (gzipped if the
+/// filename ends with `.gz`), or to stdout if the filename is `-`. Each pair is written once, with the
+/// lexicographically smaller barcode first, and pairs are sorted.
+///
+/// Returns the number of pairs written.
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use barcodes::whitelist::write_distance_matrix;
+/// let path = |name: &str| std::env::temp_dir().join(name).to_str().unwrap().to_string();
+/// fs::write(path("distance_matrix_whitelist.txt"), "ACGT\nACGA\nTCGA\nGGGG\n").unwrap();
+/// let n = write_distance_matrix(&path("distance_matrix_whitelist.txt"), 2, &path("distance_matrix.tsv")).unwrap();
+/// assert_eq!(n, 3);
+/// assert_eq!(fs::read_to_string(path("distance_matrix.tsv")).unwrap(), "barcode1\tbarcode2\tdistance\nACGA\tACGT\t1\nACGA\tTCGA\t1\nACGT\tTCGA\t2\n");
+/// ```
+pub fn write_distance_matrix(whitelist_filename: &str, max_distance: usize, output_filename: &str) -> io::Result<usize> {
+    let whitelist = read_whitelist(whitelist_filename)?;
+    barcode_length(&whitelist)?;
+    let whitelist: BTreeSet<Vec<u8>> = whitelist.into_iter().collect();
+    let trie: Trie = whitelist.iter().collect();
+
+    let mut writer = create_output(output_filename)?;
+    writeln!(writer, "{}", DISTANCE_MATRIX_HEADER)?;
+    let mut pairs = 0;
+    for barcode in whitelist.iter() {
+        let neighbors = trie.get_words_within_hamming_distance(barcode, max_distance).into_iter()
+            .filter(|(neighbor, _distance)| neighbor.as_bytes() > barcode.as_slice())
+            .sorted();
+        for (neighbor, distance) in neighbors {
+            writeln!(writer, "{}\t{}\t{}", String::from_utf8_lossy(barcode), neighbor, distance)?;
+            pairs += 1;
+        }
+    }
+    writer.flush()?;
+
+    Ok(pairs)
+}
+
+/// Lookup table from each sequence near a whitelist to the whitelisted barcodes near it (see
+/// [`precompute_neighborhood`]).
+pub type Neighborhoods = HashMap<Vec<u8>, Vec<Vec<u8>>>;
+
+/// Precompute the Hamming ball of each whitelisted barcode in `trie`: every sequence (of A, C, G and T) within
+/// `max_distance` of it that isn't itself in `whitelist`, mapped to the whitelisted barcodes within
+/// `max_distance` of it, in lexicographic order. Looking a barcode up in the table replaces a trie search, so
+/// correction is a hash lookup per read once it's built; however the table grows steeply with the distance
+/// and barcode length (e.g., ~1,100 sequences per 16bp barcode at distance 2).
+///
+/// # Example
+/// ```
+/// use std::collections::HashSet;
+/// use barcodes::trie::Trie;
+/// use barcodes::whitelist::precompute_neighborhood;
+/// let whitelist: HashSet<&[u8]> = HashSet::from([&b"ACGT"[..], b"ACGA", b"TTTT"]);
+/// let trie: Trie = whitelist.iter().collect();
+/// let neighborhoods = precompute_neighborhood(&trie, &whitelist, 1);
+/// // each barcode has 12 sequences at distance 1, but ACGT and ACGA are whitelisted, and ACGC and ACGG are
+/// // near both of them
+/// assert_eq!(neighborhoods.len(), 12 * 3 - 2 - 2);
+/// assert_eq!(neighborhoods[&b"ACGC".to_vec()], vec![b"ACGA".to_vec(), b"ACGT".to_vec()]);
+/// assert_eq!(neighborhoods[&b"TTTA".to_vec()], vec![b"TTTT".to_vec()]);
+/// assert!(!neighborhoods.contains_key(&b"ACGT".to_vec()));
+/// assert!(!neighborhoods.contains_key(&b"GGGG".to_vec()));
+/// ```
+pub fn precompute_neighborhood(trie: &Trie, whitelist: &HashSet<&[u8]>, max_distance: usize) -> Neighborhoods {
+    let mut neighborhoods: Neighborhoods = HashMap::new();
+    for barcode in trie.iter() {
+        let mut variant = barcode.clone();
+        add_variants(&mut variant, 0, max_distance, &mut |variant| {
+            if !whitelist.contains(variant) {
+                neighborhoods.entry(variant.to_vec()).or_default().push(barcode.clone());
+            }
+        });
+    }
+    neighborhoods
+}
+
+/// Call `f` on each variant of `sequence` with up to `substitutions` bases substituted at or after `start`
+/// (once each), leaving `sequence` as it was.
+fn add_variants(sequence: &mut [u8], start: usize, substitutions: usize, f: &mut impl FnMut(&[u8])) {
+    if substitutions == 0 {
+        return;
+    }
+    for i in start..sequence.len() {
+        let original = sequence[i];
+        for &base in b"ACGT".iter().filter(|&&b| b != original) {
+            sequence[i] = base;
+            f(sequence);
+            add_variants(sequence, i + 1, substitutions - 1, f);
+        }
+        sequence[i] = original;
+    }
+}
+
+/// Get the length shared by all barcodes in a whitelist.
+///
+/// Returns an error if the whitelist is empty, or if its barcodes are not all of the same length.
+///
+/// # Example
+/// ```
+/// use barcodes::whitelist::barcode_length;
+/// assert_eq!(barcode_length(&[b"ACGT".to_vec(), b"TTTT".to_vec()]).unwrap(), 4);
+/// assert!(barcode_length(&[b"ACGT".to_vec(), b"TTT".to_vec()]).is_err());
+/// ```
+pub fn barcode_length<B: AsRef<[u8]>>(whitelist: &[B]) -> io::Result<usize> {
+    let lengths = barcode_lengths(whitelist)?;
+
+    match lengths.len() {
+        1 => Ok(lengths[0]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Whitelist barcodes have inconsistent lengths ({})", lengths.iter().join(", "))))
+    }
+}
+
+/// Get the distinct lengths of the barcodes in a whitelist, shortest first (e.g., a combinatorial-index
+/// whitelist can have barcodes of two lengths).
+///
+/// Returns an error if the whitelist is empty.
+///
+/// # Example
+/// ```
+/// use barcodes::whitelist::barcode_lengths;
+/// assert_eq!(barcode_lengths(&[b"ACGTAC".to_vec(), b"ACGT".to_vec(), b"TTTT".to_vec()]).unwrap(), vec![4, 6]);
+/// assert!(barcode_lengths::<&[u8]>(&[]).is_err());
+/// ```
+pub fn barcode_lengths<B: AsRef<[u8]>>(whitelist: &[B]) -> io::Result<Vec<usize>> {
+    let lengths: Vec<usize> = whitelist.iter().map(|s| s.as_ref().len()).unique().sorted().collect();
+
+    match lengths.len() {
+        0 => Err(io::Error::new(io::ErrorKind::InvalidData, "Whitelist is empty")),
+        _ => Ok(lengths)
+    }
+}
+
+/// Bases represented by a degenerate IUPAC code, or None if the byte is not a degenerate code.
+fn iupac_bases(code: u8) -> Option<&'static [u8]> {
+    match code {
+        b'R' => Some(b"AG"),
+        b'Y' => Some(b"CT"),
+        b'S' => Some(b"CG"),
+        b'W' => Some(b"AT"),
+        b'K' => Some(b"GT"),
+        b'M' => Some(b"AC"),
+        b'B' => Some(b"CGT"),
+        b'D' => Some(b"AGT"),
+        b'H' => Some(b"ACT"),
+        b'V' => Some(b"ACG"),
+        b'N' => Some(b"ACGT"),
+        _ => None
+    }
+}
+
+/// Expand a barcode containing IUPAC ambiguity codes to all of the concrete sequences it represents.
+///
+/// Returns None if the barcode would expand to more than `max_expansions` sequences.
+///
+/// # Example
+/// ```
+/// use barcodes::whitelist::expand_iupac;
+/// let expanded = expand_iupac(b"ACRT", 10).unwrap();
+/// assert_eq!(expanded, vec![b"ACAT".to_vec(), b"ACGT".to_vec()]);
+/// assert_eq!(expand_iupac(b"NNNN", 10), None);
+/// ```
+pub fn expand_iupac(barcode: &[u8], max_expansions: usize) -> Option<Vec<Vec<u8>>> {
+    let options: Vec<Vec<u8>> = barcode.iter().map(|&b| match iupac_bases(b) {
+        Some(bases) => bases.to_vec(),
+        None => vec![b]
+    }).collect();
+
+    // checked multiplication, so that a huge number of expansions can't overflow
+    let expansion_count = options.iter().try_fold(1_usize, |acc, o| acc.checked_mul(o.len()))?;
+    if expansion_count > max_expansions {
+        return None;
+    }
+
+    let mut expanded: Vec<Vec<u8>> = vec![Vec::with_capacity(barcode.len())];
+    for bases in options {
+        expanded = expanded.iter().flat_map(|prefix| bases.iter().map(move |&b| {
+            let mut s = prefix.clone();
+            s.push(b);
+            s
+        })).collect();
+    }
+
+    Some(expanded)
+}
+
+/// Expand all degenerate barcodes in a whitelist to concrete sequences. Barcodes that aren't degenerate are
+/// kept as they are (so barcodes borrowed from a [`WhitelistFile`] stay borrowed).
+///
+/// Barcodes that would expand to more than `max_expansions` sequences are skipped with a warning.
+///
+/// # Example
+/// ```
+/// use std::borrow::Cow;
+/// use barcodes::whitelist::expand_whitelist;
+/// let expanded = expand_whitelist(vec![b"ACGT".as_slice(), b"ACRT", b"NNNN"], 10);
+/// assert!(matches!(expanded[0], Cow::Borrowed(b"ACGT")));
+/// assert_eq!(expanded[1..], [b"ACAT".to_vec(), b"ACGT".to_vec()]);
+/// ```
+pub fn expand_whitelist<'a, B: Into<Cow<'a, [u8]>>>(whitelist: Vec<B>, max_expansions: usize) -> Vec<Cow<'a, [u8]>> {
+    let mut expanded: Vec<Cow<[u8]>> = Vec::with_capacity(whitelist.len());
+
+    for barcode in whitelist {
+        let barcode = barcode.into();
+        if max_expansions > 0 && barcode.iter().all(|&b| iupac_bases(b).is_none()) {
+            expanded.push(barcode);
+            continue;
+        }
+        match expand_iupac(&barcode, max_expansions) {
+            Some(x) => expanded.extend(x.into_iter().map(Cow::Owned)),
+            None => warn!("Skipping whitelist barcode {}: IUPAC expansion exceeds {} sequences", String::from_utf8_lossy(&barcode), max_expansions)
+        }
+    }
+
+    expanded
+}